@@ -35,29 +35,447 @@ enum Commands {
     ListRules, // Nouvelle sous-commande
     // ... futures commandes
     CreateRule {
+        /// IP ou préfixe CIDR source (ex: 10.0.0.0/8, 2001:db8::/32), ou "*" pour toute source
         #[clap(long)]
         source_ip: String,
+        /// IP ou préfixe CIDR destination, ou "*" pour toute destination
         #[clap(long)]
         dest_ip: String,
+        /// Port ou plage de ports source (ex: 80, 49152-65535), ou "*"
         #[clap(long, default_value = "*")]
         source_port: String,
+        /// Port ou plage de ports destination (ex: 6000-6063), ou "*"
         #[clap(long, default_value = "*")]
         dest_port: String,
         #[clap(long)]
         action: String, // "allow" ou "deny"
+        /// TCP, UDP, ICMP, ICMPV6 ou ANY
         #[clap(long, default_value = "any")]
         protocol: String,
+        /// Priorité (plus petite = évaluée en premier, 100 par défaut)
+        #[clap(long)]
+        priority: Option<i32>,
+        /// Type ICMP (ex: 8 = echo request, 128 en ICMPV6) ; tout type si absent
+        #[clap(long)]
+        icmp_type: Option<i32>,
+        /// Code ICMP (exige --icmp-type) ; tout code si absent
+        #[clap(long)]
+        icmp_code: Option<i32>,
+        /// VLAN ID (1-4094, balise externe en QinQ) ; tout VLAN si absent
+        #[clap(long = "vlan")]
+        vlan_id: Option<i32>,
+        /// DENY : ne pas couper les connexions déjà suivies que la règle refuse
+        #[clap(long)]
+        keep_connections: bool,
     },
     DeleteRule { // Nouvelle sous-commande
         #[clap(long)]
         id: i32,
+        /// ALLOW : ne pas couper les connexions déjà suivies que la règle admettait
+        #[clap(long)]
+        keep_connections: bool,
+    },
+    /// Modifie une règle existante (même ID, compteurs conservés) ; seuls les champs donnés changent
+    UpdateRule {
+        #[clap(long)]
+        id: i32,
+        /// IP ou préfixe CIDR source, ou "*"
+        #[clap(long)]
+        source_ip: Option<String>,
+        /// IP ou préfixe CIDR destination, ou "*"
+        #[clap(long)]
+        dest_ip: Option<String>,
+        /// Port ou plage de ports source, ou "*"
+        #[clap(long)]
+        source_port: Option<String>,
+        /// Port ou plage de ports destination, ou "*"
+        #[clap(long)]
+        dest_port: Option<String>,
+        /// allow ou deny
+        #[clap(long)]
+        action: Option<String>,
+        /// TCP, UDP, ICMP, ICMPV6 ou ANY
+        #[clap(long)]
+        protocol: Option<String>,
+        #[clap(long)]
+        priority: Option<i32>,
+        #[clap(long)]
+        icmp_type: Option<i32>,
+        #[clap(long)]
+        icmp_code: Option<i32>,
+        /// Retire le type et le code ICMP (tout type)
+        #[clap(long, conflicts_with_all = ["icmp_type", "icmp_code"])]
+        any_icmp: bool,
+        #[clap(long = "vlan")]
+        vlan_id: Option<i32>,
+        /// Retire le VLAN (tout VLAN)
+        #[clap(long, conflicts_with = "vlan_id")]
+        any_vlan: bool,
+        /// Ne pas couper les connexions déjà suivies que la règle modifiée n'admet plus ou refuse
+        #[clap(long)]
+        keep_connections: bool,
+    },
+    /// Remplace tout le jeu de règles par celui d'un fichier, appliqué d'un bloc (base et noyau)
+    ApplyRuleset {
+        /// Une règle par ligne, champs `clé=valeur` séparés par des espaces, `#` pour un commentaire
+        /// (clés : id, source_ip, dest_ip, source_port, dest_port, action, protocol, priority, icmp_type, icmp_code, vlan)
+        #[clap(long)]
+        file: std::path::PathBuf,
+        /// Affiche les changements sans rien appliquer
+        #[clap(long)]
+        dry_run: bool,
+        /// Ne pas couper les connexions déjà suivies que le nouveau jeu n'admet plus ou refuse
+        #[clap(long)]
+        keep_connections: bool,
+    },
+    /// Modifie un timeout de suivi de connexion (appliqué sans redémarrage)
+    SetTimeout {
+        /// tcp, udp ou icmp (écho)
+        #[clap(long)]
+        protocol: String,
+        /// État TCP : syn_sent, syn_received, established, fin_wait1, fin_wait2, close_wait, closing, last_ack, time_wait
+        #[clap(long, default_value = "")]
+        state: String,
+        /// Port destination du flux, pour une surcharge (ex: 51820 pour WireGuard, 53 pour le DNS)
+        #[clap(long, default_value_t = 0)]
+        port: u32,
+        /// Timeout en secondes ; 0 retire une surcharge par port
+        #[clap(long)]
+        seconds: u32,
+    },
+    /// Modifie la politique par défaut d'une classe de trafic (appliquée sans redémarrage)
+    SetPolicy {
+        /// arp, ipv6, icmp, other_ip, unmatched (TCP/UDP sans règle ni suivi) ou fragment (fragment IPv4 / IPv6 orphelin)
+        #[clap(long = "class")]
+        traffic_class: String,
+        /// drop, pass ou log (passe en journalisant)
+        #[clap(long)]
+        policy: String,
+    },
+    /// Modifie la verbosité des événements paquet (appliquée sans redémarrage, "off" au démarrage du firewall)
+    SetLogLevel {
+        /// off, drops (verdicts DROP), sampled ou all
+        #[clap(long)]
+        level: String,
+        /// Avec "sampled" : un événement sur N
+        #[clap(long, default_value_t = 100)]
+        sample_rate: u32,
+    },
+    /// Affiche les compteurs de verdicts du datapath XDP
+    Stats {
+        /// Affiche en continu les débits (paquets/s) sur cet intervalle en secondes (Ctrl-C pour arrêter)
+        #[clap(long)]
+        interval: Option<u64>,
+    },
+    /// Affiche en continu les événements paquet (Ctrl-C pour arrêter)
+    Watch {
+        /// IP ou préfixe CIDR, en source ou en destination
+        #[clap(long, default_value = "")]
+        ip: String,
+        /// Port TCP/UDP, en source ou en destination
+        #[clap(long)]
+        port: Option<u32>,
+        /// drop ou pass
+        #[clap(long, default_value = "")]
+        verdict: String,
+        /// ID de la règle ayant décidé du paquet
+        #[clap(long)]
+        rule_id: Option<u32>,
+    },
+    /// Consulte et nettoie la table de suivi des connexions
+    Conn {
+        #[clap(subcommand)]
+        command: ConnCommands,
     },
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum ConnCommands {
+    /// Liste les entrées de suivi (5-tuple, état, âge)
+    List {
+        #[clap(flatten)]
+        filter: ConnFilterArgs,
+        /// Entrées à sauter
+        #[clap(long, default_value_t = 0)]
+        offset: u32,
+        /// Entrées affichées (au plus 1000)
+        #[clap(long, default_value_t = 100)]
+        limit: u32,
+    },
+    /// Retire l'entrée d'un flux, donné dans un sens ou dans l'autre
+    Kill {
+        #[clap(long)]
+        source_ip: String,
+        #[clap(long)]
+        dest_ip: String,
+        /// Écho ICMP : identifiant
+        #[clap(long)]
+        source_port: u32,
+        /// Écho ICMP : identifiant
+        #[clap(long)]
+        dest_port: u32,
+        /// tcp, udp, icmp ou icmpv6
+        #[clap(long)]
+        protocol: String,
+        /// VLAN du flux ; absent = non balisé
+        #[clap(long = "vlan")]
+        vlan_id: Option<u32>,
+    },
+    /// Retire toutes les entrées correspondant aux filtres
+    Flush {
+        #[clap(flatten)]
+        filter: ConnFilterArgs,
+        /// Confirme le vidage complet de la table quand aucun filtre n'est donné
+        #[clap(long)]
+        all: bool,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct ConnFilterArgs {
+    /// IP ou préfixe CIDR, en source ou en destination
+    #[clap(long, default_value = "")]
+    ip: String,
+    /// Port, en source ou en destination
+    #[clap(long)]
+    port: Option<u32>,
+    /// tcp, udp, icmp ou icmpv6
+    #[clap(long, default_value = "")]
+    protocol: String,
+    /// État TCP (syn_sent, established, time_wait...), new ou established
+    #[clap(long, default_value = "")]
+    state: String,
+    /// VLAN (0 = trafic non balisé)
+    #[clap(long = "vlan")]
+    vlan_id: Option<u32>,
+}
+
+impl ConnFilterArgs {
+    fn is_empty(&self) -> bool {
+        self.ip.is_empty() && self.port.is_none() && self.protocol.is_empty() && self.state.is_empty() && self.vlan_id.is_none()
+    }
+
+    fn into_proto(self) -> firewall::ConnectionFilter {
+        firewall::ConnectionFilter {
+            ip: self.ip,
+            port: self.port,
+            protocol: self.protocol,
+            state: self.state,
+            vlan_id: self.vlan_id,
+        }
+    }
+}
+
 async fn handle_get_status(client: &mut FirewallServiceClient<tonic::transport::Channel>) -> anyhow::Result<()> {
     let request = tonic::Request::new(Empty {});
     let response = client.get_status(request).await?.into_inner();
     println!("Firewall status: {}", response.status);
+    if !response.egress_tracking {
+        println!("Suivi des flux sortants inactif (TC egress non attaché) : les réponses aux connexions ouvertes par l'hôte exigent une règle ALLOW");
+    }
+    if !response.conntrack_timeouts.is_empty() {
+        println!("Timeouts de suivi de connexion :");
+        println!("{:<8} | {:<14} | {:<6} | {:<10}", "Proto", "État", "Port", "Timeout");
+        println!("{}", "-".repeat(46));
+        for t in response.conntrack_timeouts {
+            println!("{:<8} | {:<14} | {:<6} | {:<10}",
+                     t.protocol,
+                     if t.state.is_empty() { "*" } else { t.state.as_str() },
+                     if t.port == 0 { "*".to_string() } else { t.port.to_string() },
+                     format!("{}s", t.timeout_s));
+        }
+    }
+    if !response.default_policies.is_empty() {
+        println!("Politiques par défaut :");
+        println!("{:<10} | {:<8}", "Classe", "Politique");
+        println!("{}", "-".repeat(21));
+        for p in response.default_policies {
+            println!("{:<10} | {:<8}", p.traffic_class, p.policy);
+        }
+    }
+    if let Some(f) = response.fragments {
+        println!("Fragments IPv4 / IPv6 : {} premiers, {} suivis, {} orphelins, {} trop courts (rejetés), {} recouvrants (rejetés)",
+                 f.first, f.followed, f.orphan, f.tiny, f.overlapping);
+    }
+    if let Some(l) = response.log_level {
+        if l.level == "sampled" && l.sample_rate > 1 {
+            println!("Événements paquet : {} (1/{})", l.level, l.sample_rate);
+        } else {
+            println!("Événements paquet : {}", l.level);
+        }
+    }
+    Ok(())
+}
+
+async fn handle_set_timeout(
+    client: &mut FirewallServiceClient<tonic::transport::Channel>,
+    timeout: firewall::ConntrackTimeout,
+) -> anyhow::Result<()> {
+    let response = client.set_conntrack_timeout(tonic::Request::new(timeout)).await?.into_inner();
+    println!("Réponse du serveur: {}", response.message);
+    Ok(())
+}
+
+async fn handle_set_policy(
+    client: &mut FirewallServiceClient<tonic::transport::Channel>,
+    policy: firewall::DefaultPolicy,
+) -> anyhow::Result<()> {
+    let response = client.set_default_policy(tonic::Request::new(policy)).await?.into_inner();
+    println!("Réponse du serveur: {}", response.message);
+    Ok(())
+}
+
+async fn handle_set_log_level(
+    client: &mut FirewallServiceClient<tonic::transport::Channel>,
+    log_level: firewall::LogLevel,
+) -> anyhow::Result<()> {
+    let response = client.set_log_level(tonic::Request::new(log_level)).await?.into_inner();
+    println!("Réponse du serveur: {}", response.message);
+    Ok(())
+}
+
+async fn handle_get_stats(
+    client: &mut FirewallServiceClient<tonic::transport::Channel>,
+    interval: Option<u64>,
+) -> anyhow::Result<()> {
+    let mut previous = client.get_stats(tonic::Request::new(Empty {})).await?.into_inner();
+    let interval = match interval {
+        Some(interval) => interval,
+        None => {
+            println!("Compteurs du datapath (depuis le chargement du programme XDP) :");
+            println!("{:<24} | {:>14}", "Compteur", "Paquets");
+            println!("{}", "-".repeat(41));
+            for (name, value) in [
+                ("Passés", previous.passed),
+                ("Rejetés (règle)", previous.dropped_rule),
+                ("Rejetés (politique)", previous.dropped_policy),
+                ("Rejetés (suivi)", previous.dropped_conntrack),
+                ("Rejetés (fragment)", previous.dropped_fragment),
+                ("Rejetés (incohérent)", previous.dropped_invalid),
+                ("Avortés", previous.aborted),
+                ("Échecs insertion suivi", previous.conntrack_insert_failed),
+                ("Événements perdus", previous.events_lost),
+            ] {
+                println!("{:<24} | {:>14}", name, value);
+            }
+            return Ok(());
+        }
+    };
+    anyhow::ensure!(interval > 0, "L'intervalle doit être d'au moins 1 seconde");
+
+    println!("Débits du datapath (paquets/s, toutes les {}s) :", interval);
+    println!("{:>10} | {:>10} | {:>10} | {:>10} | {:>10} | {:>10} | {:>10} | {:>10}",
+             "Passés", "Règle", "Politique", "Suivi", "Fragment", "Incohérent", "Avortés", "Év. perdus");
+    println!("{}", "-".repeat(101));
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+        let current = client.get_stats(tonic::Request::new(Empty {})).await?.into_inner();
+        // Horloge du serveur : le débit ne dépend pas de la latence des appels
+        let elapsed_s = current.timestamp_ns.saturating_sub(previous.timestamp_ns) as f64 / 1e9;
+        let pps = |now: u64, before: u64| {
+            if elapsed_s > 0.0 { format!("{:.0}", now.saturating_sub(before) as f64 / elapsed_s) } else { "-".to_string() }
+        };
+        println!("{:>10} | {:>10} | {:>10} | {:>10} | {:>10} | {:>10} | {:>10} | {:>10}",
+                 pps(current.passed, previous.passed),
+                 pps(current.dropped_rule, previous.dropped_rule),
+                 pps(current.dropped_policy, previous.dropped_policy),
+                 pps(current.dropped_conntrack, previous.dropped_conntrack),
+                 pps(current.dropped_fragment, previous.dropped_fragment),
+                 pps(current.dropped_invalid, previous.dropped_invalid),
+                 pps(current.aborted, previous.aborted),
+                 pps(current.events_lost, previous.events_lost));
+        previous = current;
+    }
+}
+
+async fn handle_watch(
+    client: &mut FirewallServiceClient<tonic::transport::Channel>,
+    filter: firewall::WatchEventsRequest,
+) -> anyhow::Result<()> {
+    // Le noyau ne publie rien en verbosité "off" : le flux resterait muet
+    let status = client.get_status(tonic::Request::new(Empty {})).await?.into_inner();
+    if matches!(status.log_level, Some(l) if l.level == "off") {
+        eprintln!("Attention : verbosité des événements \"off\", aucun événement ne sera reçu (voir set-log-level).");
+    }
+
+    let mut events = client.watch_events(tonic::Request::new(filter)).await?.into_inner();
+    println!("{:<12} | {:<7} | {:<6} | {:<45} | {:<45} | {:<4} | {:<6} | {:<14}",
+             "Heure (UTC)", "Verdict", "Proto", "Source", "Destination", "VLAN", "Règle", "Raison");
+    println!("{}", "-".repeat(160));
+    while let Some(event) = events.message().await? {
+        if event.skipped > 0 {
+            println!("... {} événements sautés (client trop lent)", event.skipped);
+        }
+        let endpoint = |ip: &str, port: u32| {
+            if ip.contains(':') { format!("[{}]:{}", ip, port) } else { format!("{}:{}", ip, port) }
+        };
+        let ms = event.unix_time_ms % 86_400_000;
+        println!("{:<12} | {:<7} | {:<6} | {:<45} | {:<45} | {:<4} | {:<6} | {:<14}",
+                 format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000),
+                 event.verdict.to_uppercase(),
+                 event.protocol,
+                 endpoint(&event.source_ip, event.source_port),
+                 endpoint(&event.dest_ip, event.dest_port),
+                 event.vlan_id.map_or("*".to_string(), |v| v.to_string()),
+                 event.rule_id.map_or("-".to_string(), |r| r.to_string()),
+                 event.reason);
+    }
+    println!("Flux d'événements terminé par le serveur.");
+    Ok(())
+}
+
+async fn handle_conn_list(
+    client: &mut FirewallServiceClient<tonic::transport::Channel>,
+    request: firewall::ListConnectionsRequest,
+) -> anyhow::Result<()> {
+    let offset = request.offset;
+    let response = client.list_connections(tonic::Request::new(request)).await?.into_inner();
+
+    if response.connections.is_empty() {
+        println!("Aucune entrée de suivi ({} au total pour ces filtres).", response.total);
+        return Ok(());
+    }
+    println!("{:<6} | {:<45} | {:<45} | {:<4} | {:<12} | {:>8} | {:>8} | {:>8}",
+             "Proto", "Source", "Destination", "VLAN", "État", "Âge (s)", "Inactif", "Expire");
+    println!("{}", "-".repeat(158));
+    let endpoint = |ip: &str, port: u32| {
+        if ip.contains(':') { format!("[{}]:{}", ip, port) } else { format!("{}:{}", ip, port) }
+    };
+    for conn in &response.connections {
+        println!("{:<6} | {:<45} | {:<45} | {:<4} | {:<12} | {:>8} | {:>8} | {:>8}",
+                 conn.protocol,
+                 endpoint(&conn.source_ip, conn.source_port),
+                 endpoint(&conn.dest_ip, conn.dest_port),
+                 conn.vlan_id.map_or("-".to_string(), |v| v.to_string()),
+                 conn.state,
+                 conn.age_s,
+                 conn.idle_s,
+                 conn.expires_in_s);
+    }
+    println!("Entrées {} à {} sur {}.", offset + 1, offset + response.connections.len() as u32, response.total);
+    Ok(())
+}
+
+async fn handle_conn_kill(
+    client: &mut FirewallServiceClient<tonic::transport::Channel>,
+    tuple: firewall::ConnectionTuple,
+) -> anyhow::Result<()> {
+    let response = client.delete_connection(tonic::Request::new(tuple)).await?.into_inner();
+    println!("Réponse du serveur: {}", response.message);
+    Ok(())
+}
+
+async fn handle_conn_flush(
+    client: &mut FirewallServiceClient<tonic::transport::Channel>,
+    filter: ConnFilterArgs,
+    all: bool,
+) -> anyhow::Result<()> {
+    // Sans filtre, le serveur vide toute la table : exiger une confirmation explicite
+    if filter.is_empty() && !all {
+        anyhow::bail!("Aucun filtre donné : ajoutez --all pour vider toute la table de suivi.");
+    }
+    let response = client.flush_connections(tonic::Request::new(filter.into_proto())).await?.into_inner();
+    println!("Réponse du serveur: {}", response.message);
     Ok(())
 }
 
@@ -69,12 +487,19 @@ async fn handle_list_rules(client: &mut FirewallServiceClient<tonic::transport::
     if response.rules.is_empty() {
         println!("Aucune règle active trouvée.");
     } else {
-        println!("Règles actives du firewall :");
-        println!("{:<5} | {:<18} | {:<18} | {:<10} | {:<10} | {:<8} | {:<8} | {:<5}",
-                 "ID", "Source IP", "Dest IP", "Src Port", "Dest Port", "Action", "Proto", "Hits");
-        println!("{}", "-".repeat(100)); // Séparateur
+        println!("Règles actives du firewall (ordre d'évaluation) :");
+        println!("{:<5} | {:<5} | {:<18} | {:<18} | {:<11} | {:<11} | {:<8} | {:<8} | {:<9} | {:<4} | {:<10} | {:<12} | {:<19} | {:<7}",
+                 "Prio", "ID", "Source IP", "Dest IP", "Src Port", "Dest Port", "Action", "Proto", "Type/Code", "VLAN", "Hits", "Octets", "Dernier hit", "Actif");
+        println!("{}", "-".repeat(182)); // Séparateur
         for rule in response.rules {
-            println!("{:<5} | {:<18} | {:<18} | {:<10} | {:<10} | {:<8} | {:<8} | {:<5}",
+            // Type/code ICMP : "8/0", "3/*", vide pour les autres protocoles
+            let icmp = match (rule.icmp_type, rule.icmp_code) {
+                (Some(t), Some(c)) => format!("{}/{}", t, c),
+                (Some(t), None) => format!("{}/*", t),
+                _ => String::new(),
+            };
+            println!("{:<5} | {:<5} | {:<18} | {:<18} | {:<11} | {:<11} | {:<8} | {:<8} | {:<9} | {:<4} | {:<10} | {:<12} | {:<19} | {:<7}",
+                     rule.priority,
                      rule.id,
                      rule.source_ip,
                      rule.dest_ip,
@@ -82,7 +507,12 @@ async fn handle_list_rules(client: &mut FirewallServiceClient<tonic::transport::
                      rule.dest_port,
                      rule.action,
                      rule.protocol,
-                     rule.usage_count);
+                     icmp,
+                     rule.vlan_id.map_or_else(|| "*".to_string(), |v| v.to_string()),
+                     rule.usage_count,
+                     rule.byte_count,
+                     if rule.last_hit.is_empty() { "jamais" } else { rule.last_hit.as_str() },
+                     if rule.enforced { "oui" } else { "NON" });
         }
     }
     Ok(())
@@ -91,9 +521,11 @@ async fn handle_list_rules(client: &mut FirewallServiceClient<tonic::transport::
 async fn handle_create_rule(
      client: &mut FirewallServiceClient<tonic::transport::Channel>,
     rule_data: RuleData,
+    keep_connections: bool,
 ) -> anyhow::Result<()> {
     let request_payload = CreateRuleRequest {
         rule: Some(rule_data),
+        purge_connections: Some(!keep_connections),
     };
     let request = tonic::Request::new(request_payload);
 
@@ -108,10 +540,12 @@ async fn handle_create_rule(
 async fn handle_delete_rule(
     client: &mut FirewallServiceClient<tonic::transport::Channel>,
     rule_id: i32,
+    keep_connections: bool,
 ) -> anyhow::Result<()> {
     let rule_data_delete = RuleDataDelete { id: rule_id };
     let request_payload = DeleteRuleRequest {
         rule: Some(rule_data_delete),
+        purge_connections: Some(!keep_connections),
     };
     let request = tonic::Request::new(request_payload);
 
@@ -135,6 +569,99 @@ async fn handle_delete_rule(
     Ok(())
 }
 
+async fn handle_update_rule(
+    client: &mut FirewallServiceClient<tonic::transport::Channel>,
+    request: firewall::UpdateRuleRequest,
+) -> anyhow::Result<()> {
+    let response = client.update_rule(tonic::Request::new(request)).await?.into_inner();
+    println!("Réponse du serveur: ID={}, Message='{}'", response.id, response.message);
+    Ok(())
+}
+
+// Fichier de règles d'apply-ruleset ; champs absents : mêmes défauts que create-rule.
+// Une ligne avec id remplace cette règle, sans id elle reprend une règle identique ou en crée une.
+fn parse_ruleset(content: &str) -> anyhow::Result<Vec<firewall::RulesetEntry>> {
+    let mut entries = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| anyhow::anyhow!("Ligne {} : {}", index + 1, message);
+        let mut id = None;
+        let mut rule = RuleData {
+            source_port: "*".to_string(),
+            dest_port: "*".to_string(),
+            protocol: "any".to_string(),
+            ..Default::default()
+        };
+        for field in line.split_whitespace() {
+            let (key, value) = field.split_once('=')
+                .ok_or_else(|| error(format!("'{}' n'est pas de la forme clé=valeur", field)))?;
+            let number = |value: &str| value.parse::<i32>()
+                .map_err(|_| error(format!("{} : nombre attendu, '{}' reçu", key, value)));
+            match key {
+                "id" => id = Some(number(value)?),
+                "source_ip" => rule.source_ip = value.to_string(),
+                "dest_ip" => rule.dest_ip = value.to_string(),
+                "source_port" => rule.source_port = value.to_string(),
+                "dest_port" => rule.dest_port = value.to_string(),
+                "action" => rule.action = value.to_string(),
+                "protocol" => rule.protocol = value.to_string(),
+                "priority" => rule.priority = Some(number(value)?),
+                "icmp_type" => rule.icmp_type = Some(number(value)?),
+                "icmp_code" => rule.icmp_code = Some(number(value)?),
+                "vlan" => rule.vlan_id = Some(number(value)?),
+                _ => return Err(error(format!("clé inconnue '{}'", key))),
+            }
+        }
+        if rule.source_ip.is_empty() || rule.dest_ip.is_empty() || rule.action.is_empty() {
+            return Err(error("source_ip, dest_ip et action sont requis".to_string()));
+        }
+        entries.push(firewall::RulesetEntry { id, rule: Some(rule) });
+    }
+    Ok(entries)
+}
+
+// Règle dans la syntaxe du fichier d'apply-ruleset
+fn format_rule_data(rule: &RuleData) -> String {
+    let mut fields = vec![
+        format!("source_ip={}", rule.source_ip),
+        format!("dest_ip={}", rule.dest_ip),
+        format!("source_port={}", rule.source_port),
+        format!("dest_port={}", rule.dest_port),
+        format!("action={}", rule.action),
+        format!("protocol={}", rule.protocol),
+    ];
+    let optional = [("priority", rule.priority), ("icmp_type", rule.icmp_type), ("icmp_code", rule.icmp_code), ("vlan", rule.vlan_id)];
+    fields.extend(optional.iter().filter_map(|(key, value)| value.map(|v| format!("{}={}", key, v))));
+    fields.join(" ")
+}
+
+async fn handle_apply_ruleset(
+    client: &mut FirewallServiceClient<tonic::transport::Channel>,
+    request: firewall::ApplyRulesetRequest,
+) -> anyhow::Result<()> {
+    let response = client.apply_ruleset(tonic::Request::new(request)).await?.into_inner();
+
+    // Ligne illisible en base : pas de version précédente à afficher
+    let describe = |rule: &Option<RuleData>| rule.as_ref().map_or_else(|| "(illisible)".to_string(), format_rule_data);
+    for change in &response.created {
+        // ID 0 en simulation : la règle n'est pas encore insérée
+        let id = if change.id == 0 { "nouvelle".to_string() } else { format!("#{}", change.id) };
+        println!("+ {:<8} {}", id, describe(&change.after));
+    }
+    for change in &response.updated {
+        println!("~ #{:<7} {}", change.id, describe(&change.before));
+        println!("  {:<8} {}", "->", describe(&change.after));
+    }
+    for change in &response.deleted {
+        println!("- #{:<7} {}", change.id, describe(&change.before));
+    }
+    println!("Réponse du serveur: {}", response.message);
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> { // Utilisation de anyhow::Result
     let cli = Cli::parse();
@@ -160,6 +687,11 @@ async fn main() -> anyhow::Result<()> { // Utilisation de anyhow::Result
             dest_port,
             action,
             protocol,
+            priority,
+            icmp_type,
+            icmp_code,
+            vlan_id,
+            keep_connections,
         } => {                 // Bloc de code pour cette branche
             // Le compilateur va vous dire que RuleData n'est pas trouvé ici ensuite
             // car il n'est pas importé.
@@ -170,13 +702,157 @@ async fn main() -> anyhow::Result<()> { // Utilisation de anyhow::Result
                 dest_port,
                 action,
                 protocol,
+                priority,
+                icmp_type,
+                icmp_code,
+                vlan_id,
+            };
+            handle_create_rule(&mut client, rule_data, keep_connections).await?;
+        }
+        Commands::DeleteRule { id, keep_connections } => { // Gérer la nouvelle commande
+            handle_delete_rule(&mut client, id, keep_connections).await?;
+        }
+        Commands::UpdateRule {
+            id,
+            source_ip,
+            dest_ip,
+            source_port,
+            dest_port,
+            action,
+            protocol,
+            priority,
+            icmp_type,
+            icmp_code,
+            any_icmp,
+            vlan_id,
+            any_vlan,
+            keep_connections,
+        } => {
+            let changes = firewall::RuleUpdate {
+                source_ip,
+                dest_ip,
+                source_port,
+                dest_port,
+                action,
+                protocol,
+                priority,
+                icmp_type,
+                icmp_code,
+                vlan_id,
+                any_icmp,
+                any_vlan,
+            };
+            let request = firewall::UpdateRuleRequest { id, changes: Some(changes), purge_connections: Some(!keep_connections) };
+            handle_update_rule(&mut client, request).await?;
+        }
+        Commands::ApplyRuleset { file, dry_run, keep_connections } => {
+            let content = std::fs::read_to_string(&file)
+                .map_err(|e| anyhow::anyhow!("Lecture de {} impossible : {}", file.display(), e))?;
+            let request = firewall::ApplyRulesetRequest {
+                rules: parse_ruleset(&content)?,
+                dry_run,
+                purge_connections: Some(!keep_connections),
             };
-            handle_create_rule(&mut client, rule_data).await?;
+            handle_apply_ruleset(&mut client, request).await?;
+        }
+        Commands::SetTimeout { protocol, state, port, seconds } => {
+            let timeout = firewall::ConntrackTimeout { protocol, state, port, timeout_s: seconds };
+            handle_set_timeout(&mut client, timeout).await?;
+        }
+        Commands::SetPolicy { traffic_class, policy } => {
+            handle_set_policy(&mut client, firewall::DefaultPolicy { traffic_class, policy }).await?;
         }
-        Commands::DeleteRule { id } => { // Gérer la nouvelle commande
-            handle_delete_rule(&mut client, id).await?;
+        Commands::SetLogLevel { level, sample_rate } => {
+            handle_set_log_level(&mut client, firewall::LogLevel { level, sample_rate }).await?;
         }
+        Commands::Stats { interval } => {
+            handle_get_stats(&mut client, interval).await?;
+        }
+        Commands::Watch { ip, port, verdict, rule_id } => {
+            handle_watch(&mut client, firewall::WatchEventsRequest { ip, port, verdict, rule_id }).await?;
+        }
+        Commands::Conn { command } => match command {
+            ConnCommands::List { filter, offset, limit } => {
+                let request = firewall::ListConnectionsRequest { filter: Some(filter.into_proto()), offset, limit };
+                handle_conn_list(&mut client, request).await?;
+            }
+            ConnCommands::Kill { source_ip, dest_ip, source_port, dest_port, protocol, vlan_id } => {
+                let tuple = firewall::ConnectionTuple { source_ip, dest_ip, source_port, dest_port, protocol, vlan_id };
+                handle_conn_kill(&mut client, tuple).await?;
+            }
+            ConnCommands::Flush { filter, all } => {
+                handle_conn_flush(&mut client, filter, all).await?;
+            }
+        },
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ruleset_applies_defaults() {
+        let entries = parse_ruleset(
+            "# Jeu de règles\n\
+             \n\
+             id=4 source_ip=10.0.0.0/8 dest_ip=* dest_port=22 action=allow protocol=tcp # SSH interne\n\
+             source_ip=* dest_ip=* action=deny priority=200\n",
+        ).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, Some(4));
+        let ssh = entries[0].rule.as_ref().unwrap();
+        assert_eq!((ssh.source_port.as_str(), ssh.dest_port.as_str(), ssh.protocol.as_str()), ("*", "22", "tcp"));
+        assert_eq!(entries[1].id, None);
+        let default = entries[1].rule.as_ref().unwrap();
+        assert_eq!((default.protocol.as_str(), default.priority), ("any", Some(200)));
+    }
+
+    #[test]
+    fn parse_ruleset_reports_line_errors() {
+        for (content, line) in [
+            ("source_ip=* dest_ip=* action=deny\nsource_ip=* dest_ip=*", "Ligne 2"),
+            ("source_ip=* dest_ip=* action=deny vlan=abc", "Ligne 1"),
+            ("\nsource_ip=* dest_ip=* action=deny colour=red", "Ligne 2"),
+            ("source_ip=* dest_ip=* action deny", "Ligne 1"),
+        ] {
+            let error = parse_ruleset(content).unwrap_err().to_string();
+            assert!(error.starts_with(line), "{}", error);
+        }
+    }
+
+    #[test]
+    fn format_rule_data_round_trips() {
+        let rules = [
+            RuleData {
+                source_ip: "10.0.0.0/8".to_string(),
+                dest_ip: "2001:db8::/32".to_string(),
+                source_port: "*".to_string(),
+                dest_port: "1024-2048".to_string(),
+                action: "deny".to_string(),
+                protocol: "udp".to_string(),
+                priority: Some(10),
+                vlan_id: Some(12),
+                ..Default::default()
+            },
+            RuleData {
+                source_ip: "*".to_string(),
+                dest_ip: "*".to_string(),
+                source_port: "*".to_string(),
+                dest_port: "*".to_string(),
+                action: "allow".to_string(),
+                protocol: "icmp".to_string(),
+                icmp_type: Some(8),
+                icmp_code: Some(0),
+                ..Default::default()
+            },
+        ];
+        for rule in rules {
+            let entries = parse_ruleset(&format_rule_data(&rule)).unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].rule.as_ref(), Some(&rule));
+        }
+    }
+}
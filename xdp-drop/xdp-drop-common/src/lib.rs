@@ -1,29 +1,239 @@
 // Fichier : /root/FirewallIA/xdp-drop/xdp-drop-common/src/lib.rs
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 // Tu auras besoin de bytemuck pour dériver Pod.
 use bytemuck::{Pod, Zeroable};
 
-// --- Structure PacketLog ---
+// --- Structure PacketLog (événements du ring buffer EVENTS) ---
+// Publiée par le programme XDP selon la verbosité de LOG_CONFIG, avec une limite de débit par CPU.
+// Adresses en network byte order (IPv4 : premier mot seulement), ports en host byte order ;
+// pour ICMP / ICMPv6, src_port et dst_port portent le type et le code.
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable)] // Utilise les dérives de bytemuck
 pub struct PacketLog {
-    pub ipv4_address: u32,
+    pub timestamp_ns: u64, // bpf_ktime_get_ns (CLOCK_MONOTONIC)
+    pub src_ip: [u32; 4],
+    pub dst_ip: [u32; 4],
+    pub rule_id: u32, // 0 = aucune règle
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub vlan_id: u16,
+    pub protocol: u8,
+    pub family: u8, // EVENT_FAMILY_*
+    pub verdict: u8, // EVENT_VERDICT_*
+    pub reason: u8,  // EVENT_REASON_*
+    pub _pad: [u8; 2],
+}
+
+pub const EVENT_FAMILY_IPV4: u8 = 4;
+pub const EVENT_FAMILY_IPV6: u8 = 6;
+
+// Mêmes valeurs que XDP_DROP / XDP_PASS
+pub const EVENT_VERDICT_DROP: u8 = 1;
+pub const EVENT_VERDICT_PASS: u8 = 2;
+
+pub const EVENT_REASON_RULE: u8 = 1; // Règle BLOCKLIST (rule_id renseigné)
+pub const EVENT_REASON_CONNTRACK: u8 = 2; // Flux connu de la table de suivi, ou erreur ICMP qui le cite (drop : segment hors fenêtre)
+pub const EVENT_REASON_DEFAULT_POLICY: u8 = 3; // Politique par défaut d'une classe de trafic
+pub const EVENT_REASON_FRAGMENT: u8 = 4; // Fragment IPv4 / IPv6 trop court ou recouvrant
+pub const EVENT_REASON_INVALID: u8 = 5; // Paquet incohérent (ALLOW sans paquet d'ouverture, erreur ICMP sans flux...)
+
+// --- Verbosité des événements (map LOG_CONFIG, entrée unique) ---
+// Une classe de trafic en politique "log" publie ses événements quel que soit le niveau.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Default)]
+pub struct LogConfig {
+    pub level: u32, // LOG_LEVEL_*
+    pub sample_rate: u32, // LOG_LEVEL_SAMPLED : un événement sur sample_rate
+}
+
+pub const LOG_LEVEL_OFF: u32 = 0; // Aucun événement (défaut)
+pub const LOG_LEVEL_DROPS: u32 = 1; // Verdicts DROP seulement
+pub const LOG_LEVEL_SAMPLED: u32 = 2; // Échantillon de tous les verdicts
+pub const LOG_LEVEL_ALL: u32 = 3;
+
+// --- Préfixes sources (maps SRC_PREFIXES / SRC_PREFIXES_V6) ---
+// Les tries LPM sources associent chaque adresse au préfixe source le plus long
+// utilisé par une règle ; la valeur est l'identifiant de classe de ce préfixe.
+// Les règles des préfixes moins spécifiques sont recopiées dans chaque classe
+// par le daemon lors de la compilation du jeu de règles.
+
+// Nombre de bits de la clé de règle précédant l'adresse destination
+// (src_class + protocol + _pad). Longueur de préfixe LPM = RULE_KEY_PREFIX_BITS + longueur CIDR dest.
+pub const RULE_KEY_PREFIX_BITS: u32 = 64;
+
+// Nombre maximal de règles candidates par ensemble (bornée pour le vérifieur)
+pub const MAX_RULES_PER_KEY: usize = 32;
+
+// Ensembles de débordement chaînés derrière celui d'une clé (map RULE_SET_LINKS), au plus
+pub const MAX_RULE_SET_LINKS: u32 = 31;
+
+// Règles candidates au plus par clé : l'ensemble de tête et tous ses maillons
+pub const MAX_CANDIDATES_PER_KEY: usize = MAX_RULES_PER_KEY * (1 + MAX_RULE_SET_LINKS as usize);
+
+// Capacité de chaque map RULE_SET_LINKS (maillons de toutes les clés des deux familles)
+pub const RULE_SET_LINKS_MAX_ENTRIES: u32 = 1024;
+
+// Capacité de chaque trie SRC_PREFIXES / SRC_PREFIXES_V6 (préfixes source distincts)
+pub const SRC_PREFIXES_MAX_ENTRIES: u32 = 1024;
+
+// Capacité de chaque trie BLOCKLIST / BLOCKLIST_V6 (clés (classe, protocole, préfixe destination))
+pub const BLOCKLIST_MAX_ENTRIES: u32 = 4096;
+
+// --- Structure RuleKey (clé du trie LPM BLOCKLIST) ---
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Pod, Zeroable)]
+pub struct RuleKey {
+    pub src_class: u32,
+    pub protocol: u8, // Numéro de protocole IP, 0 = tout protocole
+    pub _pad: [u8; 3],
+    pub addr_dest: u32, // Doit rester en dernier : c'est la partie préfixe de la clé
+}
+
+// --- Structure RuleKeyV6 (clé du trie LPM BLOCKLIST_V6) ---
+// Les adresses sont stockées en network byte order, mot par mot.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Pod, Zeroable)]
+pub struct RuleKeyV6 {
+    pub src_class: u32,
+    pub protocol: u8,
+    pub _pad: [u8; 3],
+    pub addr_dest: [u32; 4],
+}
+
+// Règle candidate : plages de ports inclusives, en host byte order.
+// Pour une règle ICMP / ICMPv6, les plages source et destination portent le type et le code.
+// Une plage 0-65535 est un wildcard ; d'une règle "tout protocole", seules les entrées
+// wildcard s'appliquent à ICMP.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Pod, Zeroable)]
+pub struct RuleEntry {
+    pub rule_id: u32,
+    pub rank: u32, // Rang d'évaluation global (priorité) : le plus petit rang qui correspond l'emporte
     pub action: u32,
+    pub src_port_min: u16,
+    pub src_port_max: u16,
+    pub dst_port_min: u16,
+    pub dst_port_max: u16,
+    pub vlan_id: u16, // VLAN ID exigé, 0 = tout VLAN (et trames non balisées)
+    pub _pad: u16,
+}
+
+// --- Structure RuleSet (valeur des tries BLOCKLIST et de RULE_SET_LINKS) ---
+// Règles candidates d'une clé, triées par rang : la première qui correspond l'emporte.
+// Au-delà de MAX_RULES_PER_KEY, la suite est dans `links` maillons (chain, 0..links)
+// de RULE_SET_LINKS, toujours dans l'ordre des rangs.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Pod, Zeroable)]
+pub struct RuleSet {
+    pub count: u32,
+    pub links: u32, // Maillons à suivre ; 0 pour un maillon
+    pub chain: u32, // Identifiant de chaîne, propre à la clé
+    pub _pad: u32,
+    pub entries: [RuleEntry; MAX_RULES_PER_KEY],
 }
 
-// --- Structure IpPort ---
+// --- Structure RuleSetLink (clé de RULE_SET_LINKS) ---
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Pod, Zeroable)]
-pub struct IpPort {
-    pub addr: u32,
-    pub addr_dest: u32,
-    pub port: u16,
+pub struct RuleSetLink {
+    pub chain: u32,
+    pub link: u32,
+}
+
+// --- Structure RuleStats (valeur per-CPU de RULE_STATS, indexée par ID de règle) ---
+// Paquets et octets décidés par la règle ; le daemon fait la somme des CPUs.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Pod, Zeroable)]
+pub struct RuleStats {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+// --- Politique par défaut (map DEFAULT_POLICY, indexée par classe de trafic) ---
+// Verdict des paquets qu'aucune règle ni entrée de suivi n'a décidés. Pour IPv6 la
+// politique s'applique avant le filtrage : pass/log laissent les règles IPv6 s'appliquer.
+// Les trames ni IP ni ARP passent toujours.
+pub const TRAFFIC_CLASS_ARP: u32 = 0;
+pub const TRAFFIC_CLASS_IPV6: u32 = 1;
+pub const TRAFFIC_CLASS_ICMP: u32 = 2; // ICMP et ICMPv6 (attention au Neighbor Discovery)
+pub const TRAFFIC_CLASS_OTHER_IP: u32 = 3; // Protocoles IP autres que TCP/UDP/ICMP, sans règle ANY sans ports
+pub const TRAFFIC_CLASS_UNMATCHED: u32 = 4; // TCP/UDP sans règle ni suivi
+pub const TRAFFIC_CLASS_FRAGMENT: u32 = 5; // Fragments IPv4 / IPv6 orphelins (premier fragment inconnu ou expiré)
+pub const TRAFFIC_CLASS_COUNT: u32 = 6;
+
+pub const POLICY_UNSET: u32 = 0; // Map pas encore renseignée : politique intégrée
+pub const POLICY_DROP: u32 = 1;
+pub const POLICY_PASS: u32 = 2;
+pub const POLICY_LOG: u32 = 3; // Passe, en journalisant le paquet
+
+// Politique tant que le daemon n'en a pas configuré d'autre (comportement historique)
+pub const fn builtin_policy(class: u32) -> u32 {
+    if class == TRAFFIC_CLASS_UNMATCHED || class == TRAFFIC_CLASS_FRAGMENT { POLICY_DROP } else { POLICY_PASS }
+}
+
+// --- Fragments IPv4 (map FRAGMENTS) ---
+// Seul le premier fragment porte l'en-tête de transport : son verdict est mémorisé par
+// datagramme et repris par les fragments suivants.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Pod, Zeroable)]
+pub struct FragmentKey {
+    pub src_ip: u32,
+    pub dst_ip: u32,
+    pub id: u16, // Identification IP, network byte order
+    pub vlan_id: u16,
+    pub protocol: u8,
+    pub _pad: [u8; 3],
+}
+
+// --- Fragments IPv6 (map FRAGMENTS_V6, même valeur) ---
+// Le datagramme est identifié par ses adresses et l'identification de l'en-tête de fragment.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Pod, Zeroable)]
+pub struct FragmentKeyV6 {
+    pub src_ip: [u32; 4],
+    pub dst_ip: [u32; 4],
+    pub id: u32, // Identification, network byte order
+    pub vlan_id: u16,
     pub _pad: u16,
 }
 
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct FragmentValue {
+    pub first_seen_ns: u64,
+    pub action: u32,    // Verdict XDP du premier fragment
+    pub first_end: u16, // Octets de charge utile du premier fragment : un fragment suivant doit commencer au-delà
+    pub _pad: u16,
+}
+
+// Compteurs de fragments (map per-CPU FRAGMENT_STATS, indexée par FRAG_STAT_*)
+pub const FRAG_STAT_FIRST: u32 = 0; // Premiers fragments, verdict mémorisé
+pub const FRAG_STAT_FOLLOWED: u32 = 1; // Fragments suivants ayant repris le verdict du premier
+pub const FRAG_STAT_ORPHAN: u32 = 2; // Fragments suivants sans premier fragment connu
+pub const FRAG_STAT_TINY: u32 = 3; // Premiers fragments trop courts pour l'en-tête de transport
+pub const FRAG_STAT_OVERLAP: u32 = 4; // Fragments recouvrant le premier
+pub const FRAG_STAT_COUNT: u32 = 5;
+
+// Compteurs du datapath (map per-CPU DATAPATH_STATS, indexée par DATAPATH_STAT_*)
+// Chaque paquet vu par XDP compte une seule fois dans PASSED, un DROPPED_* ou ABORTED.
+pub const DATAPATH_STAT_PASSED: u32 = 0;
+pub const DATAPATH_STAT_DROPPED_RULE: u32 = 1; // Règle DENY
+pub const DATAPATH_STAT_DROPPED_POLICY: u32 = 2; // Politique par défaut d'une classe de trafic
+pub const DATAPATH_STAT_DROPPED_CONNTRACK: u32 = 3; // Segment TCP hors fenêtre d'un flux suivi
+pub const DATAPATH_STAT_DROPPED_FRAGMENT: u32 = 4; // Fragment trop court, recouvrant ou suivant un premier fragment rejeté
+pub const DATAPATH_STAT_DROPPED_INVALID: u32 = 5; // Paquet incohérent (ALLOW sans paquet d'ouverture, erreur ICMP sans flux...)
+pub const DATAPATH_STAT_ABORTED: u32 = 6; // Paquet tronqué (erreur d'analyse) ou échec d'accès à une map
+// Hors verdict
+pub const DATAPATH_STAT_CONNTRACK_INSERT_FAILED: u32 = 7; // Table de suivi pleine (en XDP, le paquet compte aussi dans ABORTED)
+pub const DATAPATH_STAT_EVENTS_LOST: u32 = 8; // Événements retenus mais perdus (budget du CPU épuisé ou ring buffer plein)
+pub const DATAPATH_STAT_COUNT: u32 = 9;
+
 // --- NOUVELLES STRUCTURES POUR LE SUIVI DE CONNEXION (STATEFUL) ---
+// Capacité de chaque table de suivi (CONN_TRACK_TABLE / CONN_TRACK_TABLE_V6)
+pub const CONN_TRACK_MAX_ENTRIES: u32 = 10240;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Pod, Zeroable)]
 pub struct ConnectionKey {
@@ -32,7 +242,20 @@ pub struct ConnectionKey {
     pub src_port: u16,
     pub dst_port: u16,
     pub protocol: u8,
-    pub _pad: [u8; 3], // Remplacer _pad1 et _pad2 par un seul padding explicite de 3 octets
+    pub _pad: u8,
+    pub vlan_id: u16, // VLAN du flux (0 = non balisé) : des tenants peuvent réutiliser les mêmes adresses
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Pod, Zeroable)]
+pub struct ConnectionKeyV6 {
+    pub src_ip: [u32; 4],
+    pub dst_ip: [u32; 4],
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+    pub _pad: u8,
+    pub vlan_id: u16,
 }
 
 
@@ -42,9 +265,15 @@ pub enum TcpState {
     SynSent = 1,
     SynReceived = 2,
     Established = 3,
-    FinWait1 = 4,
+    FinWait1 = 4,    // L'initiateur a fermé, FIN non acquitté
+    FinWait2 = 5,    // FIN de l'initiateur acquitté, l'autre extrémité n'a pas fermé
+    CloseWait = 6,   // L'autre extrémité a fermé en premier
+    Closing = 7,     // Les deux FIN envoyés, celui de l'autre extrémité pas encore acquitté
+    LastAck = 8,     // L'initiateur a fermé après l'autre extrémité, attente du dernier ACK
+    TimeWait = 9,    // Connexion fermée ; garde les retransmissions tardives
 }
 
+// États UDP, partagés par l'écho ICMP (requête = New, réponse = Established)
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum UdpState {
@@ -52,6 +281,53 @@ pub enum UdpState {
     Established = 2,
 }
 
+// Flags TCP tels que lus par le programme XDP (L4Info::tcp_flags)
+pub const TCP_FLAG_FIN: u8 = 0x01;
+pub const TCP_FLAG_SYN: u8 = 0x02;
+pub const TCP_FLAG_RST: u8 = 0x04;
+pub const TCP_FLAG_PSH: u8 = 0x08;
+pub const TCP_FLAG_ACK: u8 = 0x10;
+pub const TCP_FLAG_URG: u8 = 0x20;
+
+// Automate TCP (RFC 793) vu par le pare-feu, nommé du point de vue de l'initiateur :
+// FIN_WAIT* quand l'initiateur ferme en premier, CLOSE_WAIT/LAST_ACK quand c'est l'autre
+// extrémité. Un paquet qui ne fait pas avancer l'automate laisse l'état inchangé.
+#[inline(always)]
+pub fn tcp_next_state(state: u8, flags: u8, forward: bool) -> u8 {
+    const TCP_SYN_SENT: u8 = TcpState::SynSent as u8;
+    const TCP_SYN_RECEIVED: u8 = TcpState::SynReceived as u8;
+    const TCP_ESTABLISHED: u8 = TcpState::Established as u8;
+    const TCP_FIN_WAIT1: u8 = TcpState::FinWait1 as u8;
+    const TCP_FIN_WAIT2: u8 = TcpState::FinWait2 as u8;
+    const TCP_CLOSE_WAIT: u8 = TcpState::CloseWait as u8;
+    const TCP_CLOSING: u8 = TcpState::Closing as u8;
+    const TCP_LAST_ACK: u8 = TcpState::LastAck as u8;
+    const TCP_TIME_WAIT: u8 = TcpState::TimeWait as u8;
+
+    let syn = flags & TCP_FLAG_SYN != 0;
+    let ack = flags & TCP_FLAG_ACK != 0;
+    let fin = flags & TCP_FLAG_FIN != 0;
+
+    match (state, forward) {
+        // SYN-ACK, ou SYN seul de l'autre côté (ouverture simultanée)
+        (TCP_SYN_SENT, false) if syn => TCP_SYN_RECEIVED,
+        // ACK final de la poignée de main (des deux côtés en ouverture simultanée)
+        (TCP_SYN_RECEIVED, _) if ack && !syn && !fin => TCP_ESTABLISHED,
+        (TCP_SYN_RECEIVED | TCP_ESTABLISHED, true) if fin => TCP_FIN_WAIT1,
+        (TCP_SYN_RECEIVED | TCP_ESTABLISHED, false) if fin => TCP_CLOSE_WAIT,
+        // L'autre extrémité ferme aussi avant d'acquitter (fermeture simultanée ou FIN-ACK)
+        (TCP_FIN_WAIT1, false) if fin => TCP_CLOSING,
+        (TCP_FIN_WAIT1, false) if ack => TCP_FIN_WAIT2,
+        (TCP_FIN_WAIT2, false) if fin => TCP_TIME_WAIT,
+        (TCP_CLOSING, true) if ack => TCP_TIME_WAIT,
+        (TCP_CLOSE_WAIT, true) if fin => TCP_LAST_ACK,
+        (TCP_LAST_ACK, false) if ack => TCP_TIME_WAIT,
+        // Nouveau SYN sur un tuple en TIME_WAIT : nouvelle connexion
+        (TCP_TIME_WAIT, true) if syn && !ack => TCP_SYN_SENT,
+        _ => state,
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub union State {
@@ -66,14 +342,44 @@ pub enum Protocol {
     Udp
 }
 
+// Facteur d'échelle de fenêtre absent du SYN (pas de mise à l'échelle négociée)
+pub const TCP_WSCALE_UNSET: u8 = 0xff;
+
+// --- Fenêtre TCP d'un sens du flux (suivi de séquence à la nf_conntrack) ---
+// Numéros de séquence en host byte order.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct TcpWindow {
+    pub end: u32,     // Plus haut seq + longueur émis par ce sens
+    pub max_end: u32, // Borne droite acceptable : plus haut ack + fenêtre annoncés par l'autre sens
+    pub max_win: u32, // Plus grande fenêtre (mise à l'échelle) annoncée par ce sens
+    pub scale: u8,    // Facteur d'échelle (RFC 7323), TCP_WSCALE_UNSET tant qu'il n'est pas connu
+    pub seen: u8,     // 1 dès le premier segment de ce sens
+    pub _pad: [u8; 2],
+}
+
+impl TcpWindow {
+    pub const UNSEEN: TcpWindow = TcpWindow {
+        end: 0,
+        max_end: 0,
+        max_win: 0,
+        scale: TCP_WSCALE_UNSET,
+        seen: 0,
+        _pad: [0; 2],
+    };
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable)]
 pub struct ConnectionValue {
     pub last_seen_ns: u64,
+    pub first_seen_ns: u64, // Ouverture du flux (ou réouverture d'un tuple TCP en TIME_WAIT)
     pub state: u8,
     pub protocol: u8,
     pub _pad: [u8; 6],
+    pub tcp: [TcpWindow; 2], // TCP uniquement : [sens initiateur, sens retour]
 }
+
 // Bytemuck ne peut pas dériver Pod pour les enums avec des données ou les unions complexes
 // On doit l'implémenter manuellement si nécessaire ou simplifier la structure.
 // Pour l'instant, simplifions en ne dérivant Pod que sur les structures simples.
@@ -81,4 +387,102 @@ pub struct ConnectionValue {
 // Je l'ai simplifiée ci-dessus pour la rendre compatible.
 // Il faudra peut-être adapter ton code eBPF.
 // La structure que tu avais avec `ConnStateVariant` est aussi difficilement compatible Pod.
-// C'est un problème connu. On va le régler après la compilation.
\ No newline at end of file
+// C'est un problème connu. On va le régler après la compilation.
+
+// aya exige son propre trait Pod pour les clés/valeurs des maps côté userspace.
+#[cfg(feature = "user")]
+mod user {
+    use super::*;
+
+    unsafe impl aya::Pod for RuleKey {}
+    unsafe impl aya::Pod for RuleKeyV6 {}
+    unsafe impl aya::Pod for RuleSet {}
+    unsafe impl aya::Pod for RuleSetLink {}
+    unsafe impl aya::Pod for RuleStats {}
+    unsafe impl aya::Pod for ConnectionKey {}
+    unsafe impl aya::Pod for ConnectionKeyV6 {}
+    unsafe impl aya::Pod for ConnectionValue {}
+    unsafe impl aya::Pod for LogConfig {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYN: u8 = TCP_FLAG_SYN;
+    const SYN_ACK: u8 = TCP_FLAG_SYN | TCP_FLAG_ACK;
+    const ACK: u8 = TCP_FLAG_ACK;
+    const FIN_ACK: u8 = TCP_FLAG_FIN | TCP_FLAG_ACK;
+
+    // Rejoue une suite de segments (flags, sens initiateur -> répondeur) depuis `state`
+    fn replay(state: TcpState, segments: &[(u8, bool)]) -> u8 {
+        segments.iter().fold(state as u8, |state, &(flags, forward)| tcp_next_state(state, flags, forward))
+    }
+
+    #[test]
+    fn three_way_handshake() {
+        assert_eq!(tcp_next_state(TcpState::SynSent as u8, SYN_ACK, false), TcpState::SynReceived as u8);
+        assert_eq!(replay(TcpState::SynSent, &[(SYN_ACK, false), (ACK, true)]), TcpState::Established as u8);
+        // Retransmission du SYN : pas d'avancée
+        assert_eq!(tcp_next_state(TcpState::SynSent as u8, SYN, true), TcpState::SynSent as u8);
+        // Un ACK sans SYN-ACK préalable n'établit rien
+        assert_eq!(tcp_next_state(TcpState::SynSent as u8, ACK, false), TcpState::SynSent as u8);
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        // Les deux extrémités envoient un SYN seul, puis un SYN-ACK
+        let state = replay(TcpState::SynSent, &[(SYN, false)]);
+        assert_eq!(state, TcpState::SynReceived as u8);
+        assert_eq!(tcp_next_state(state, SYN_ACK, true), TcpState::SynReceived as u8);
+        assert_eq!(tcp_next_state(state, SYN_ACK, false), TcpState::SynReceived as u8);
+        // L'ACK final peut venir de l'un ou l'autre côté
+        assert_eq!(tcp_next_state(state, ACK, true), TcpState::Established as u8);
+        assert_eq!(tcp_next_state(state, ACK, false), TcpState::Established as u8);
+    }
+
+    #[test]
+    fn initiator_closes_first() {
+        let state = replay(TcpState::Established, &[(FIN_ACK, true)]);
+        assert_eq!(state, TcpState::FinWait1 as u8);
+        assert_eq!(replay(TcpState::FinWait1, &[(ACK, false)]), TcpState::FinWait2 as u8);
+        assert_eq!(replay(TcpState::FinWait1, &[(ACK, false), (FIN_ACK, false)]), TcpState::TimeWait as u8);
+        // FIN-ACK direct du répondeur : CLOSING puis dernier ACK de l'initiateur
+        assert_eq!(replay(TcpState::FinWait1, &[(FIN_ACK, false)]), TcpState::Closing as u8);
+        assert_eq!(replay(TcpState::FinWait1, &[(FIN_ACK, false), (ACK, true)]), TcpState::TimeWait as u8);
+        // Données de l'initiateur après son FIN : sans effet
+        assert_eq!(tcp_next_state(TcpState::FinWait2 as u8, ACK, true), TcpState::FinWait2 as u8);
+    }
+
+    #[test]
+    fn responder_closes_first() {
+        assert_eq!(replay(TcpState::Established, &[(FIN_ACK, false)]), TcpState::CloseWait as u8);
+        assert_eq!(replay(TcpState::CloseWait, &[(ACK, true)]), TcpState::CloseWait as u8);
+        assert_eq!(replay(TcpState::CloseWait, &[(FIN_ACK, true)]), TcpState::LastAck as u8);
+        assert_eq!(replay(TcpState::CloseWait, &[(FIN_ACK, true), (ACK, false)]), TcpState::TimeWait as u8);
+        // Le dernier ACK doit venir du répondeur
+        assert_eq!(replay(TcpState::LastAck, &[(ACK, true)]), TcpState::LastAck as u8);
+    }
+
+    #[test]
+    fn fin_during_handshake() {
+        assert_eq!(tcp_next_state(TcpState::SynReceived as u8, FIN_ACK, true), TcpState::FinWait1 as u8);
+        assert_eq!(tcp_next_state(TcpState::SynReceived as u8, FIN_ACK, false), TcpState::CloseWait as u8);
+        // Un FIN avant tout SYN-ACK ne ferme rien
+        assert_eq!(tcp_next_state(TcpState::SynSent as u8, FIN_ACK, true), TcpState::SynSent as u8);
+    }
+
+    #[test]
+    fn reuse_from_time_wait() {
+        // Nouveau SYN de l'initiateur : nouvelle connexion sur le même tuple
+        assert_eq!(tcp_next_state(TcpState::TimeWait as u8, SYN, true), TcpState::SynSent as u8);
+        assert_eq!(
+            replay(TcpState::TimeWait, &[(SYN, true), (SYN_ACK, false), (ACK, true)]),
+            TcpState::Established as u8
+        );
+        // Retransmissions tardives et SYN du répondeur : sans effet
+        for (flags, forward) in [(ACK, true), (FIN_ACK, false), (SYN, false), (SYN_ACK, true)] {
+            assert_eq!(tcp_next_state(TcpState::TimeWait as u8, flags, forward), TcpState::TimeWait as u8);
+        }
+    }
+}
@@ -5,32 +5,97 @@
     #![allow(nonstandard_style, dead_code, unused_imports)]
 
     use aya_ebpf::{
-        bindings::xdp_action,
-        macros::{map, xdp},
-        maps::HashMap,
-        programs::XdpContext,
-        helpers::bpf_ktime_get_ns,
+        bindings::{xdp_action, BPF_F_NO_PREALLOC, TC_ACT_OK},
+        macros::{classifier, map, xdp},
+        maps::{lpm_trie::Key, Array, HashMap, LpmTrie, LruHashMap, PerCpuArray, PerCpuHashMap, RingBuf},
+        programs::{TcContext, XdpContext},
+        helpers::{bpf_get_prandom_u32, bpf_ktime_get_ns},
+        EbpfContext,
     };
     use aya_log_ebpf::info;
 
     // Utiliser TcpHdr et UdpHdr de network_types
     use network_types::{
-        eth::{EthHdr, EtherType},
-        ip::{Ipv4Hdr, IpProto},
+        eth::EthHdr,
+        ip::{Ipv4Hdr, Ipv6Hdr, IpProto},
         tcp::TcpHdr,
         udp::UdpHdr,
     };
 
     // Vos structures partagées
-    use xdp_drop_common::{IpPort, ConnectionKey, ConnectionValue, TcpState, UdpState, ConnStateVariant};
+    use xdp_drop_common::{RuleKey, RuleKeyV6, RuleEntry, RuleSet, RuleSetLink, RuleStats, RULE_KEY_PREFIX_BITS, MAX_RULES_PER_KEY, MAX_RULE_SET_LINKS, BLOCKLIST_MAX_ENTRIES, RULE_SET_LINKS_MAX_ENTRIES, SRC_PREFIXES_MAX_ENTRIES, CONN_TRACK_MAX_ENTRIES, ConnectionKey, ConnectionKeyV6, ConnectionValue, TcpState, TcpWindow, UdpState, TCP_WSCALE_UNSET,
+        builtin_policy, POLICY_DROP, POLICY_LOG, POLICY_UNSET, TRAFFIC_CLASS_ARP, TRAFFIC_CLASS_COUNT, TRAFFIC_CLASS_FRAGMENT, TRAFFIC_CLASS_ICMP, TRAFFIC_CLASS_IPV6, TRAFFIC_CLASS_OTHER_IP, TRAFFIC_CLASS_UNMATCHED,
+        FragmentKey, FragmentKeyV6, FragmentValue, FRAG_STAT_COUNT, FRAG_STAT_FIRST, FRAG_STAT_FOLLOWED, FRAG_STAT_ORPHAN, FRAG_STAT_OVERLAP, FRAG_STAT_TINY,
+        PacketLog, EVENT_FAMILY_IPV4, EVENT_FAMILY_IPV6, EVENT_REASON_CONNTRACK, EVENT_REASON_DEFAULT_POLICY, EVENT_REASON_FRAGMENT, EVENT_REASON_INVALID, EVENT_REASON_RULE,
+        LogConfig, LOG_LEVEL_ALL, LOG_LEVEL_DROPS, LOG_LEVEL_SAMPLED,
+        DATAPATH_STAT_ABORTED, DATAPATH_STAT_CONNTRACK_INSERT_FAILED, DATAPATH_STAT_COUNT, DATAPATH_STAT_DROPPED_CONNTRACK, DATAPATH_STAT_DROPPED_FRAGMENT,
+        DATAPATH_STAT_DROPPED_INVALID, DATAPATH_STAT_DROPPED_POLICY, DATAPATH_STAT_DROPPED_RULE, DATAPATH_STAT_EVENTS_LOST, DATAPATH_STAT_PASSED,
+        tcp_next_state, TCP_FLAG_ACK, TCP_FLAG_FIN, TCP_FLAG_RST, TCP_FLAG_SYN};
 
-    // Définir les constantes de flags TCP manuellement
-    const TCP_FLAG_FIN: u8 = 0x01;
-    const TCP_FLAG_SYN: u8 = 0x02;
-    const TCP_FLAG_RST: u8 = 0x04;
-    const TCP_FLAG_PSH: u8 = 0x08;
-    const TCP_FLAG_ACK: u8 = 0x10;
-    const TCP_FLAG_URG: u8 = 0x20;
+    // EtherTypes (lus en u16 pour ne pas transmuter une valeur inconnue en EtherType)
+    const ETH_P_IPV4: u16 = 0x0800;
+    const ETH_P_ARP: u16 = 0x0806;
+    const ETH_P_IPV6: u16 = 0x86DD;
+    const ETH_P_8021Q: u16 = 0x8100;
+    const ETH_P_8021AD: u16 = 0x88A8;
+    // Offset du champ EtherType dans l'en-tête Ethernet
+    const ETH_TYPE_OFFSET: usize = 12;
+    // Balises VLAN parcourues (802.1Q, ou QinQ 802.1ad + 802.1Q)
+    const MAX_VLAN_TAGS: usize = 2;
+    const VLAN_VID_MASK: u16 = 0x0fff;
+
+    // Champ frag_off IPv4 (RFC 791) : drapeau More Fragments et offset en unités de 8 octets
+    const IP_MF: u16 = 0x2000;
+    const IP_OFFSET_MASK: u16 = 0x1fff;
+    // Durée de vie du verdict d'un datagramme fragmenté (ipfrag_time du noyau)
+    const FRAGMENT_TIMEOUT_NS: u64 = 30_000_000_000;
+
+    // Débit d'événements EVENTS par CPU (seau à jetons) : un flot de paquets rejetés ne doit
+    // pas saturer le ring buffer ni le daemon
+    const EVENT_RATE_PER_SEC: u64 = 1000;
+    const EVENT_BURST: u64 = 1000;
+    const EVENT_INTERVAL_NS: u64 = 1_000_000_000 / EVENT_RATE_PER_SEC;
+
+    // Numéros de protocole IP (lus en u8 pour ne pas transmuter une valeur inconnue en IpProto)
+    const IPPROTO_ICMP: u8 = 1;
+    const IPPROTO_TCP: u8 = 6;
+    const IPPROTO_UDP: u8 = 17;
+    const IPPROTO_ICMPV6: u8 = 58;
+
+    // Types ICMP (RFC 792) et ICMPv6 (RFC 4443) connus du suivi : l'écho est suivi par
+    // identifiant, les erreurs doivent citer un flux suivi
+    const ICMP_ECHO_REPLY: u8 = 0;
+    const ICMP_DEST_UNREACH: u8 = 3; // Dont "fragmentation nécessaire" (code 4)
+    const ICMP_ECHO_REQUEST: u8 = 8;
+    const ICMP_TIME_EXCEEDED: u8 = 11;
+    const ICMP_PARAMETER_PROB: u8 = 12;
+    const ICMPV6_DEST_UNREACH: u8 = 1;
+    const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+    const ICMPV6_TIME_EXCEEDED: u8 = 3;
+    const ICMPV6_PARAMETER_PROB: u8 = 4;
+    const ICMPV6_ECHO_REQUEST: u8 = 128;
+    const ICMPV6_ECHO_REPLY: u8 = 129;
+
+    // En-têtes d'extension IPv6 (RFC 8200) à traverser pour atteindre TCP/UDP
+    const IPV6_EXT_HOP_BY_HOP: u8 = 0;
+    const IPV6_EXT_ROUTING: u8 = 43;
+    const IPV6_EXT_FRAGMENT: u8 = 44;
+    const IPV6_EXT_AUTH: u8 = 51;
+    const IPV6_EXT_DEST_OPTS: u8 = 60;
+    // En-tête de fragment IPv6 : offset en octets (multiple de 8) et drapeau More Fragments
+    const IPV6_FRAG_OFFSET_MASK: u16 = 0xfff8;
+    const IPV6_FRAG_MF: u16 = 0x0001;
+    // Borne de la boucle de parcours (exigée par le vérifieur)
+    const MAX_IPV6_EXT_HEADERS: usize = 8;
+
+    // Options TCP (RFC 793, RFC 7323) : seul le facteur d'échelle de fenêtre nous intéresse
+    const TCP_OPT_EOL: u8 = 0;
+    const TCP_OPT_NOP: u8 = 1;
+    const TCP_OPT_WSCALE: u8 = 3;
+    const TCP_MAX_HDR_LEN: usize = 60;
+    const TCP_MAX_WSCALE: u8 = 14;
+    // Tolérance sur les ACK quand l'émetteur n'a pas encore annoncé de fenêtre (comme nf_conntrack)
+    const TCP_MAX_ACK_WINDOW: u32 = 66000;
 
 
     #[cfg(not(test))]
@@ -39,26 +104,250 @@
         loop {}
     }
 
+    // Adresse source -> classe du préfixe source le plus long (voir xdp-drop-common)
+    #[map]
+    static SRC_PREFIXES: LpmTrie<u32, u32> = LpmTrie::<u32, u32>::with_max_entries(SRC_PREFIXES_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+    #[map]
+    static SRC_PREFIXES_V6: LpmTrie<[u32; 4], u32> = LpmTrie::<[u32; 4], u32>::with_max_entries(SRC_PREFIXES_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+    // (classe source, protocole, préfixe destination) -> règles candidates (plages de ports)
+    #[map]
+    static BLOCKLIST: LpmTrie<RuleKey, RuleSet> = LpmTrie::<RuleKey, RuleSet>::with_max_entries(BLOCKLIST_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+    #[map]
+    static BLOCKLIST_V6: LpmTrie<RuleKeyV6, RuleSet> = LpmTrie::<RuleKeyV6, RuleSet>::with_max_entries(BLOCKLIST_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+    // (chaîne, maillon) -> suite des règles candidates d'une clé de BLOCKLIST / BLOCKLIST_V6
+    #[map]
+    static RULE_SET_LINKS: HashMap<RuleSetLink, RuleSet> = HashMap::<RuleSetLink, RuleSet>::with_max_entries(RULE_SET_LINKS_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+    // Second jeu de tries : le daemon y prépare un jeu de règles complet (ApplyRuleset) puis
+    // bascule RULES_GENERATION, sans que le programme ne voie jamais un jeu à moitié écrit
+    #[map]
+    static SRC_PREFIXES_B: LpmTrie<u32, u32> = LpmTrie::<u32, u32>::with_max_entries(SRC_PREFIXES_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+    #[map]
+    static SRC_PREFIXES_V6_B: LpmTrie<[u32; 4], u32> = LpmTrie::<[u32; 4], u32>::with_max_entries(SRC_PREFIXES_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+    #[map]
+    static BLOCKLIST_B: LpmTrie<RuleKey, RuleSet> = LpmTrie::<RuleKey, RuleSet>::with_max_entries(BLOCKLIST_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+    #[map]
+    static BLOCKLIST_V6_B: LpmTrie<RuleKeyV6, RuleSet> = LpmTrie::<RuleKeyV6, RuleSet>::with_max_entries(BLOCKLIST_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+    #[map]
+    static RULE_SET_LINKS_B: HashMap<RuleSetLink, RuleSet> = HashMap::<RuleSetLink, RuleSet>::with_max_entries(RULE_SET_LINKS_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+    // Génération du jeu de règles, écrite par le daemon : paire = SRC_PREFIXES / BLOCKLIST, impaire = jeu _B
+    #[map]
+    static RULES_GENERATION: Array<u32> = Array::<u32>::with_max_entries(1, 0);
+
+    // ID de règle -> paquets/octets décidés par la règle (per-CPU, sommé par le daemon)
     #[map]
-    static BLOCKLIST: HashMap<IpPort, u32> = HashMap::<IpPort, u32>::with_max_entries(1024, 0);
+    static RULE_STATS: PerCpuHashMap<u32, RuleStats> = PerCpuHashMap::<u32, RuleStats>::with_max_entries(4096, 0);
+
+    // Classe de trafic -> politique par défaut (POLICY_*), écrite par le daemon
+    #[map]
+    static DEFAULT_POLICY: Array<u32> = Array::<u32>::with_max_entries(TRAFFIC_CLASS_COUNT, 0);
+
+    // Datagramme IPv4 fragmenté -> verdict de son premier fragment (LRU : pas de nettoyage côté daemon)
+    #[map]
+    static FRAGMENTS: LruHashMap<FragmentKey, FragmentValue> =
+        LruHashMap::<FragmentKey, FragmentValue>::with_max_entries(4096, 0);
+
+    // Datagramme IPv6 fragmenté -> verdict de son premier fragment
+    #[map]
+    static FRAGMENTS_V6: LruHashMap<FragmentKeyV6, FragmentValue> =
+        LruHashMap::<FragmentKeyV6, FragmentValue>::with_max_entries(4096, 0);
+
+    // Compteurs de fragments (FRAG_STAT_*), sommés par le daemon
+    #[map]
+    static FRAGMENT_STATS: PerCpuArray<u64> = PerCpuArray::<u64>::with_max_entries(FRAG_STAT_COUNT, 0);
+
+    // Compteurs du datapath (DATAPATH_STAT_*), sommés par le daemon
+    #[map]
+    static DATAPATH_STATS: PerCpuArray<u64> = PerCpuArray::<u64>::with_max_entries(DATAPATH_STAT_COUNT, 0);
+
+    // Événements paquet (PacketLog) lus par le daemon
+    #[map]
+    static EVENTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+    // Seau à jetons des événements, par CPU
+    #[map]
+    static EVENT_BUDGET: PerCpuArray<EventBudget> = PerCpuArray::<EventBudget>::with_max_entries(1, 0);
+
+    // Verbosité des événements, écrite par le daemon (entrée absente ou nulle : aucun événement)
+    #[map]
+    static LOG_CONFIG: Array<LogConfig> = Array::<LogConfig>::with_max_entries(1, 0);
 
     #[map]
     static CONN_TRACK_TABLE: HashMap<ConnectionKey, ConnectionValue> =
-        HashMap::<ConnectionKey, ConnectionValue>::with_max_entries(10240, 0);
+        HashMap::<ConnectionKey, ConnectionValue>::with_max_entries(CONN_TRACK_MAX_ENTRIES, 0);
+
+    #[map]
+    static CONN_TRACK_TABLE_V6: HashMap<ConnectionKeyV6, ConnectionValue> =
+        HashMap::<ConnectionKeyV6, ConnectionValue>::with_max_entries(CONN_TRACK_MAX_ENTRIES, 0);
 
     const ACTION_DENY_FROM_MAP: u32 = 1;
     const ACTION_ALLOW_FROM_MAP: u32 = 2;
 
+    // Recherches BLOCKLIST : protocole exact et tout protocole (clé à 0). Chaque ensemble est
+    // trié par rang (priorité, puis règle la plus précise) ; la première règle qui correspond
+    // l'emporte, et entre les deux ensembles le plus petit rang. La source est couverte par
+    // SRC_PREFIXES.
+
+    /// Balise VLAN (802.1Q / 802.1ad) suivant l'adresse source Ethernet.
+    #[repr(C)]
+    struct VlanHdr {
+        tci: u16,        // PCP, DEI, VLAN ID ; network byte order
+        ether_type: u16, // EtherType encapsulé ; network byte order
+    }
+
+    /// En-tête d'extension IPv6 générique (Hop-by-Hop, Routing, Destination Options, AH).
+    #[repr(C)]
+    struct Ipv6ExtHdr {
+        next_hdr: u8,
+        hdr_ext_len: u8,
+    }
+
+    /// En-tête de fragmentation IPv6 (taille fixe de 8 octets).
+    #[repr(C)]
+    struct Ipv6FragHdr {
+        next_hdr: u8,
+        _reserved: u8,
+        frag_off: u16, // offset (13 bits) + flags, network byte order
+        identification: u32,
+    }
+
+    /// Fragment IPv6 : identification (network byte order) et position de sa charge utile.
+    #[derive(Clone, Copy)]
+    struct Ipv6Fragment {
+        id: u32,
+        offset: usize, // En octets ; 0 pour le premier fragment
+    }
+
+    /// En-tête ICMP / ICMPv6 (même format) ; `id` et `sequence` ne valent que pour l'écho.
+    #[repr(C)]
+    struct IcmpHdr {
+        type_: u8,
+        code: u8,
+        checksum: u16,
+        id: u16, // network byte order
+        sequence: u16,
+    }
+
+    #[repr(C)]
+    struct EventBudget {
+        tokens: u64,
+        last_refill_ns: u64,
+    }
+
+    /// Adresses du paquet décidé, pour les événements (IPv4 : premier mot).
+    struct EventAddrs {
+        family: u8,
+        src_ip: [u32; 4],
+        dst_ip: [u32; 4],
+    }
+
+    impl EventAddrs {
+        #[inline(always)]
+        fn v4(src_ip: u32, dst_ip: u32) -> Self {
+            Self { family: EVENT_FAMILY_IPV4, src_ip: [src_ip, 0, 0, 0], dst_ip: [dst_ip, 0, 0, 0] }
+        }
+
+        #[inline(always)]
+        fn v6(src_ip: [u32; 4], dst_ip: [u32; 4]) -> Self {
+            Self { family: EVENT_FAMILY_IPV6, src_ip, dst_ip }
+        }
+    }
+
+    /// Champs de couche 4 nécessaires au suivi de connexion et aux règles.
+    /// Les champs de séquence sont en host byte order et ne valent que pour TCP.
+    struct L4Info {
+        protocol: u8,
+        source_port_be: u16,
+        dest_port_be: u16,
+        tcp_flags: u8,
+        seq: u32,
+        ack_seq: u32,
+        window: u16,
+        wscale: u8,       // Option du SYN, TCP_WSCALE_UNSET si absente
+        payload_len: u32, // Données après l'en-tête TCP
+        icmp_type: u8,
+        icmp_code: u8,
+        vlan_id: u16, // VLAN de la trame, 0 si non balisée
+    }
+
+    impl L4Info {
+        /// Sans en-tête de transport lu (ports à 0).
+        #[inline(always)]
+        fn new(protocol: u8, vlan_id: u16) -> Self {
+            Self {
+                protocol,
+                source_port_be: 0,
+                dest_port_be: 0,
+                tcp_flags: 0,
+                seq: 0,
+                ack_seq: 0,
+                window: 0,
+                wscale: TCP_WSCALE_UNSET,
+                payload_len: 0,
+                icmp_type: 0,
+                icmp_code: 0,
+                vlan_id,
+            }
+        }
+    }
+
     #[xdp]
     pub fn xdp_firewall(ctx: XdpContext) -> u32 {
+        // Les rejets sont comptés là où ils sont décidés, avec leur raison
         match try_xdp_firewall(ctx) {
-            Ok(ret) => ret,
-            Err(_) => xdp_action::XDP_ABORTED,
+            Ok(ret) => {
+                if ret == xdp_action::XDP_PASS {
+                    datapath_stat(DATAPATH_STAT_PASSED);
+                }
+                ret
+            }
+            Err(_) => {
+                datapath_stat(DATAPATH_STAT_ABORTED);
+                xdp_action::XDP_ABORTED
+            }
         }
     }
 
+    /// Suivi des flux sortants (TC egress) : enregistre dans CONN_TRACK_TABLE les connexions
+    /// ouvertes par l'hôte pour que leurs réponses soient acceptées par `xdp_firewall`.
+    /// Ne filtre rien : le paquet sort toujours.
+    #[classifier]
+    pub fn tc_egress(ctx: TcContext) -> i32 {
+        let _ = try_tc_egress(&ctx);
+        TC_ACT_OK
+    }
+
+    /// Accès direct aux données du paquet, commun à XDP et TC.
+    trait PacketContext: EbpfContext {
+        fn data(&self) -> usize;
+        fn data_end(&self) -> usize;
+    }
+
+    impl PacketContext for XdpContext {
+        #[inline(always)]
+        fn data(&self) -> usize { XdpContext::data(self) }
+        #[inline(always)]
+        fn data_end(&self) -> usize { XdpContext::data_end(self) }
+    }
+
+    impl PacketContext for TcContext {
+        #[inline(always)]
+        fn data(&self) -> usize { TcContext::data(self) }
+        #[inline(always)]
+        fn data_end(&self) -> usize { TcContext::data_end(self) }
+    }
+
     #[inline(always)]
-    unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
+    unsafe fn ptr_at<C: PacketContext, T>(ctx: &C, offset: usize) -> Result<*const T, ()> {
         let start = ctx.data();
         let end = ctx.data_end();
         let len = core::mem::size_of::<T>();
@@ -66,160 +355,1261 @@
     }
 
     fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
-        let current_time_ns = unsafe { bpf_ktime_get_ns() };
+        let (ether_type, l3_offset, vlan_id) = parse_l2(&ctx)?;
+        match ether_type {
+            ETH_P_IPV4 => try_ipv4(&ctx, l3_offset, vlan_id),
+            ETH_P_IPV6 => {
+                let (action, log) = default_policy(TRAFFIC_CLASS_IPV6);
+                if action == xdp_action::XDP_DROP {
+                    datapath_stat(DATAPATH_STAT_DROPPED_POLICY);
+                    return Ok(action);
+                }
+                // Politique "log" explicite : hors événements, la trame n'a pas encore d'adresses lues
+                if log {
+                    info!(&ctx, "DEFAULT POLICY: log IPv6");
+                }
+                try_ipv6(&ctx, l3_offset, vlan_id)
+            }
+            ETH_P_ARP => {
+                let (action, log) = default_policy(TRAFFIC_CLASS_ARP);
+                if log {
+                    info!(&ctx, "DEFAULT POLICY: log ARP");
+                }
+                if action == xdp_action::XDP_DROP {
+                    datapath_stat(DATAPATH_STAT_DROPPED_POLICY);
+                }
+                Ok(action)
+            }
+            _ => Ok(xdp_action::XDP_PASS),
+        }
+    }
 
-        let eth_hdr: *const EthHdr = unsafe { ptr_at(&ctx, 0)? };
-        if unsafe { (*eth_hdr).ether_type } != EtherType::Ipv4 {
-            return Ok(xdp_action::XDP_PASS);
+    /// Parcourt jusqu'à MAX_VLAN_TAGS balises VLAN après l'en-tête Ethernet.
+    /// Retourne (EtherType encapsulé, offset de l'en-tête L3, VLAN ID). Le VLAN retenu est
+    /// celui de la première balise qui en porte un (la balise externe en QinQ), 0 sinon.
+    /// Si la carte retire les balises (rxvlan), XDP ne les voit pas : `ethtool -K <iface> rxvlan off`.
+    #[inline(always)]
+    fn parse_l2<C: PacketContext>(ctx: &C) -> Result<(u16, usize, u16), ()> {
+        let mut ether_type = u16::from_be(unsafe { *ptr_at::<C, u16>(ctx, ETH_TYPE_OFFSET)? });
+        let mut offset = EthHdr::LEN;
+        let mut vlan_id = 0;
+        for _ in 0..MAX_VLAN_TAGS {
+            if ether_type != ETH_P_8021Q && ether_type != ETH_P_8021AD {
+                break;
+            }
+            let vlan_hdr: *const VlanHdr = unsafe { ptr_at(ctx, offset)? };
+            if vlan_id == 0 {
+                vlan_id = u16::from_be(unsafe { (*vlan_hdr).tci }) & VLAN_VID_MASK;
+            }
+            ether_type = u16::from_be(unsafe { (*vlan_hdr).ether_type });
+            offset += core::mem::size_of::<VlanHdr>();
         }
+        Ok((ether_type, offset, vlan_id))
+    }
 
-        let ipv4_hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
-        let source_ip = unsafe { (*ipv4_hdr).src_addr };
-        let dest_ip = unsafe { (*ipv4_hdr).dst_addr };
-        let protocol = unsafe { (*ipv4_hdr).proto };
-        let transport_offset = EthHdr::LEN + (unsafe { (*ipv4_hdr).ihl() } as usize * 4);
+    /// Publie l'événement si la verbosité de LOG_CONFIG le retient.
+    #[inline(always)]
+    fn emit_event(addrs: &EventAddrs, l4: &L4Info, verdict: u32, rule_id: u32, reason: u8) {
+        // Tout rejet hors ARP / IPv6 / fragment suivant passe par ici : compté quelle que soit la verbosité
+        if verdict == xdp_action::XDP_DROP {
+            datapath_stat(match reason {
+                EVENT_REASON_RULE => DATAPATH_STAT_DROPPED_RULE,
+                EVENT_REASON_DEFAULT_POLICY => DATAPATH_STAT_DROPPED_POLICY,
+                EVENT_REASON_CONNTRACK => DATAPATH_STAT_DROPPED_CONNTRACK,
+                EVENT_REASON_FRAGMENT => DATAPATH_STAT_DROPPED_FRAGMENT,
+                _ => DATAPATH_STAT_DROPPED_INVALID,
+            });
+        }
+        let config = match LOG_CONFIG.get(0) {
+            Some(config) => config,
+            None => return,
+        };
+        let wanted = match config.level {
+            LOG_LEVEL_ALL => true,
+            LOG_LEVEL_DROPS => verdict == xdp_action::XDP_DROP,
+            LOG_LEVEL_SAMPLED => config.sample_rate <= 1 || unsafe { bpf_get_prandom_u32() } % config.sample_rate == 0,
+            _ => false,
+        };
+        if wanted {
+            publish_event(addrs, l4, verdict, rule_id, reason);
+        }
+    }
+
+    /// Politique par défaut appliquée : une classe en politique "log" publie toujours son événement.
+    #[inline(always)]
+    fn policy_event(addrs: &EventAddrs, l4: &L4Info, action: u32, log: bool) {
+        if log {
+            publish_event(addrs, l4, action, 0, EVENT_REASON_DEFAULT_POLICY);
+        } else {
+            emit_event(addrs, l4, action, 0, EVENT_REASON_DEFAULT_POLICY);
+        }
+    }
+
+    /// Publie un événement dans EVENTS si le budget du CPU le permet ; sinon il est perdu.
+    #[inline(always)]
+    fn publish_event(addrs: &EventAddrs, l4: &L4Info, verdict: u32, rule_id: u32, reason: u8) {
+        let now = unsafe { bpf_ktime_get_ns() };
+        if !event_budget(now) {
+            datapath_stat(DATAPATH_STAT_EVENTS_LOST);
+            return;
+        }
+        let (src_port, dst_port) = match l4.protocol {
+            IPPROTO_ICMP | IPPROTO_ICMPV6 => (l4.icmp_type as u16, l4.icmp_code as u16),
+            _ => (u16::from_be(l4.source_port_be), u16::from_be(l4.dest_port_be)),
+        };
+        let event = PacketLog {
+            timestamp_ns: now,
+            src_ip: addrs.src_ip,
+            dst_ip: addrs.dst_ip,
+            rule_id,
+            src_port,
+            dst_port,
+            vlan_id: l4.vlan_id,
+            protocol: l4.protocol,
+            family: addrs.family,
+            verdict: verdict as u8,
+            reason,
+            _pad: [0; 2],
+        };
+        // Ring buffer plein : le daemon ne suit pas, l'événement est perdu
+        if EVENTS.output(&event, 0).is_err() {
+            datapath_stat(DATAPATH_STAT_EVENTS_LOST);
+        }
+    }
+
+    /// Consomme un jeton du seau du CPU (EVENT_RATE_PER_SEC, rafale EVENT_BURST).
+    #[inline(always)]
+    fn event_budget(now: u64) -> bool {
+        let budget = match EVENT_BUDGET.get_ptr_mut(0) {
+            Some(budget) => unsafe { &mut *budget },
+            None => return false,
+        };
+        let refill = now.saturating_sub(budget.last_refill_ns) / EVENT_INTERVAL_NS;
+        if refill > 0 {
+            budget.tokens = if budget.tokens + refill > EVENT_BURST { EVENT_BURST } else { budget.tokens + refill };
+            budget.last_refill_ns = now;
+        }
+        if budget.tokens == 0 {
+            return false;
+        }
+        budget.tokens -= 1;
+        true
+    }
+
+    /// Verdict de la politique par défaut d'une classe de trafic ; le booléen
+    /// indique que le paquet doit être journalisé (POLICY_LOG).
+    #[inline(always)]
+    fn default_policy(class: u32) -> (u32, bool) {
+        let policy = match DEFAULT_POLICY.get(class) {
+            Some(&policy) if policy != POLICY_UNSET => policy,
+            _ => builtin_policy(class),
+        };
+        match policy {
+            POLICY_DROP => (xdp_action::XDP_DROP, false),
+            POLICY_LOG => (xdp_action::XDP_PASS, true),
+            _ => (xdp_action::XDP_PASS, false),
+        }
+    }
 
-        let (source_port_be, dest_port_be, tcp_flags_byte) = match protocol {
-            IpProto::Tcp => {
-                let tcp_hdr: *const TcpHdr = unsafe { ptr_at(&ctx, transport_offset)? };
+    /// Lit les ports (et les champs TCP) à `offset` ; `l4_len` est la longueur du segment
+    /// d'après l'en-tête IP. `None` pour un protocole non filtré.
+    /// Pour ICMP, les règles portent sur le type et le code.
+    #[inline(always)]
+    fn parse_l4<C: PacketContext>(ctx: &C, protocol: u8, offset: usize, l4_len: usize, vlan_id: u16) -> Result<Option<L4Info>, ()> {
+        let mut l4 = L4Info::new(protocol, vlan_id);
+        match protocol {
+            IPPROTO_TCP => {
+                let tcp_hdr: *const TcpHdr = unsafe { ptr_at(ctx, offset)? };
                 let mut flags: u8 = 0;
                 if unsafe { (*tcp_hdr).syn() } != 0 { flags |= TCP_FLAG_SYN; }
                 if unsafe { (*tcp_hdr).ack() } != 0 { flags |= TCP_FLAG_ACK; }
                 if unsafe { (*tcp_hdr).fin() } != 0 { flags |= TCP_FLAG_FIN; }
                 if unsafe { (*tcp_hdr).rst() } != 0 { flags |= TCP_FLAG_RST; }
-                (unsafe { (*tcp_hdr).source }, unsafe { (*tcp_hdr).dest }, flags)
+                let hdr_len = unsafe { (*tcp_hdr).doff() } as usize * 4;
+                l4.source_port_be = unsafe { (*tcp_hdr).source };
+                l4.dest_port_be = unsafe { (*tcp_hdr).dest };
+                l4.tcp_flags = flags;
+                l4.seq = u32::from_be(unsafe { (*tcp_hdr).seq });
+                l4.ack_seq = u32::from_be(unsafe { (*tcp_hdr).ack_seq });
+                l4.window = u16::from_be(unsafe { (*tcp_hdr).window });
+                l4.payload_len = l4_len.saturating_sub(hdr_len) as u32;
+                if flags & TCP_FLAG_SYN != 0 {
+                    l4.wscale = tcp_window_scale(ctx, offset, hdr_len)?;
+                }
+            }
+            IPPROTO_UDP => {
+                let udp_hdr: *const UdpHdr = unsafe { ptr_at(ctx, offset)? };
+                l4.source_port_be = unsafe { (*udp_hdr).source };
+                l4.dest_port_be = unsafe { (*udp_hdr).dest };
+            }
+            IPPROTO_ICMP | IPPROTO_ICMPV6 => {
+                let icmp_hdr: *const IcmpHdr = unsafe { ptr_at(ctx, offset)? };
+                l4.icmp_type = unsafe { (*icmp_hdr).type_ };
+                l4.icmp_code = unsafe { (*icmp_hdr).code };
+                // Écho : l'identifiant tient lieu de ports dans la clé de suivi
+                if is_icmp_echo(&l4) {
+                    l4.source_port_be = unsafe { (*icmp_hdr).id };
+                    l4.dest_port_be = l4.source_port_be;
+                }
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(l4))
+    }
+
+    /// Facteur d'échelle de fenêtre annoncé dans les options d'un SYN (RFC 7323).
+    #[inline(always)]
+    fn tcp_window_scale<C: PacketContext>(ctx: &C, tcp_offset: usize, hdr_len: usize) -> Result<u8, ()> {
+        let hdr_len = if hdr_len > TCP_MAX_HDR_LEN { TCP_MAX_HDR_LEN } else { hdr_len };
+        let mut opt = TcpHdr::LEN;
+        // Au plus une option par octet d'options
+        for _ in 0..(TCP_MAX_HDR_LEN - TcpHdr::LEN) {
+            if opt >= hdr_len {
+                break;
             }
-            IpProto::Udp => {
-                let udp_hdr: *const UdpHdr = unsafe { ptr_at(&ctx, transport_offset)? };
-                (unsafe { (*udp_hdr).source }, unsafe { (*udp_hdr).dest }, 0)
+            let kind: u8 = unsafe { *ptr_at::<u8>(ctx, tcp_offset + opt)? };
+            match kind {
+                TCP_OPT_EOL => break,
+                TCP_OPT_NOP => opt += 1,
+                _ => {
+                    let len = unsafe { *ptr_at::<u8>(ctx, tcp_offset + opt + 1)? } as usize;
+                    if kind == TCP_OPT_WSCALE && len == 3 {
+                        let shift = unsafe { *ptr_at::<u8>(ctx, tcp_offset + opt + 2)? };
+                        return Ok(if shift > TCP_MAX_WSCALE { TCP_MAX_WSCALE } else { shift });
+                    }
+                    if len < 2 {
+                        break;
+                    }
+                    opt += len;
+                }
             }
-            _ => return Ok(xdp_action::XDP_PASS),
+        }
+        Ok(TCP_WSCALE_UNSET)
+    }
+
+    /// IPv4 : un datagramme fragmenté est décidé sur son premier fragment, le seul qui porte
+    /// l'en-tête de transport ; les fragments suivants reprennent ce verdict (FRAGMENTS).
+    fn try_ipv4(ctx: &XdpContext, l3_offset: usize, vlan_id: u16) -> Result<u32, ()> {
+        let ipv4_hdr: *const Ipv4Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+        let frag_off = u16::from_be(unsafe { (*ipv4_hdr).frag_off });
+        if frag_off & (IP_MF | IP_OFFSET_MASK) == 0 {
+            return try_ipv4_transport(ctx, l3_offset, vlan_id);
+        }
+
+        let current_time_ns = unsafe { bpf_ktime_get_ns() };
+        let source_ip = unsafe { (*ipv4_hdr).src_addr };
+        let dest_ip = unsafe { (*ipv4_hdr).dst_addr };
+        let protocol = unsafe { (*ipv4_hdr).proto } as u8;
+        let ip_hdr_len = unsafe { (*ipv4_hdr).ihl() } as usize * 4;
+        let l4_len = (u16::from_be(unsafe { (*ipv4_hdr).tot_len }) as usize).saturating_sub(ip_hdr_len);
+        let frag_key = FragmentKey {
+            src_ip: source_ip,
+            dst_ip: dest_ip,
+            id: unsafe { (*ipv4_hdr).id },
+            vlan_id,
+            protocol,
+            _pad: [0; 3],
         };
+        let offset = (frag_off & IP_OFFSET_MASK) as usize * 8;
+        let addrs = EventAddrs::v4(source_ip, dest_ip);
+        let bare = L4Info::new(protocol, vlan_id);
+
+        if offset == 0 {
+            // L'en-tête de transport doit tenir en entier dans le premier fragment (RFC 1858)
+            if l4_len < transport_min_len(protocol) {
+                fragment_stat(FRAG_STAT_TINY);
+                emit_event(&addrs, &bare, xdp_action::XDP_DROP, 0, EVENT_REASON_FRAGMENT);
+                return Ok(xdp_action::XDP_DROP);
+            }
+            let action = try_ipv4_transport(ctx, l3_offset, vlan_id)?;
+            let first = FragmentValue {
+                first_seen_ns: current_time_ns,
+                action,
+                first_end: l4_len as u16,
+                _pad: 0,
+            };
+            // Table pleine : les fragments suivants seront traités en orphelins
+            let _ = FRAGMENTS.insert(&frag_key, &first, 0);
+            fragment_stat(FRAG_STAT_FIRST);
+            return Ok(action);
+        }
+
+        Ok(follow_fragment(&FRAGMENTS, &frag_key, offset, &addrs, &bare, current_time_ns))
+    }
+
+    /// Fragment suivant (IPv4 ou IPv6) : verdict du premier fragment du datagramme s'il est
+    /// connu et récent, sinon politique de la classe TRAFFIC_CLASS_FRAGMENT.
+    #[inline(always)]
+    fn follow_fragment<K>(
+        fragments: &LruHashMap<K, FragmentValue>,
+        frag_key: &K,
+        offset: usize,
+        addrs: &EventAddrs,
+        bare: &L4Info,
+        current_time_ns: u64,
+    ) -> u32 {
+        match unsafe { fragments.get(frag_key) }.copied() {
+            Some(first) if current_time_ns.saturating_sub(first.first_seen_ns) < FRAGMENT_TIMEOUT_NS => {
+                // Un fragment qui recouvre le premier pourrait réécrire l'en-tête de transport déjà filtré
+                if offset < first.first_end as usize {
+                    fragment_stat(FRAG_STAT_OVERLAP);
+                    emit_event(addrs, bare, xdp_action::XDP_DROP, 0, EVENT_REASON_FRAGMENT);
+                    return xdp_action::XDP_DROP;
+                }
+                fragment_stat(FRAG_STAT_FOLLOWED);
+                if first.action == xdp_action::XDP_DROP {
+                    datapath_stat(DATAPATH_STAT_DROPPED_FRAGMENT);
+                }
+                first.action
+            }
+            _ => {
+                fragment_stat(FRAG_STAT_ORPHAN);
+                let (action, log) = default_policy(TRAFFIC_CLASS_FRAGMENT);
+                policy_event(addrs, bare, action, log);
+                action
+            }
+        }
+    }
+
+    /// Taille minimale de l'en-tête de transport filtré (0 pour les autres protocoles).
+    #[inline(always)]
+    fn transport_min_len(protocol: u8) -> usize {
+        match protocol {
+            IPPROTO_TCP => TcpHdr::LEN,
+            IPPROTO_UDP => UdpHdr::LEN,
+            IPPROTO_ICMP | IPPROTO_ICMPV6 => core::mem::size_of::<IcmpHdr>(),
+            _ => 0,
+        }
+    }
+
+    #[inline(always)]
+    fn fragment_stat(stat: u32) {
+        if let Some(counter) = FRAGMENT_STATS.get_ptr_mut(stat) {
+            unsafe { *counter += 1 };
+        }
+    }
+
+    #[inline(always)]
+    fn datapath_stat(stat: u32) {
+        if let Some(counter) = DATAPATH_STATS.get_ptr_mut(stat) {
+            unsafe { *counter += 1 };
+        }
+    }
+
+    /// Datagramme IPv4 complet ou premier fragment.
+    fn try_ipv4_transport(ctx: &XdpContext, l3_offset: usize, vlan_id: u16) -> Result<u32, ()> {
+        let current_time_ns = unsafe { bpf_ktime_get_ns() };
+
+        let ipv4_hdr: *const Ipv4Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+        let source_ip = unsafe { (*ipv4_hdr).src_addr };
+        let dest_ip = unsafe { (*ipv4_hdr).dst_addr };
+        let protocol = unsafe { (*ipv4_hdr).proto } as u8;
+        let ip_hdr_len = unsafe { (*ipv4_hdr).ihl() } as usize * 4;
+        let transport_offset = l3_offset + ip_hdr_len;
+        let l4_len = (u16::from_be(unsafe { (*ipv4_hdr).tot_len }) as usize).saturating_sub(ip_hdr_len);
+        let addrs = EventAddrs::v4(source_ip, dest_ip);
+
+        let l4 = match parse_l4(ctx, protocol, transport_offset, l4_len, vlan_id)? {
+            Some(l4) => l4,
+            None => {
+                let l4 = L4Info::new(protocol, vlan_id);
+                let rule = blocklist_lookup_v4(source_ip, dest_ip, &l4);
+                return Ok(other_ip_verdict(ctx, &addrs, &l4, rule));
+            }
+        };
+        let (source_port_be, dest_port_be) = (l4.source_port_be, l4.dest_port_be);
+
+        if protocol == IPPROTO_ICMP {
+            return try_icmp_v4(ctx, &addrs, transport_offset, &l4, current_time_ns);
+        }
 
         let conn_key = ConnectionKey {
             src_ip: source_ip,
             src_port: source_port_be,
             dst_ip: dest_ip,
             dst_port: dest_port_be,
-            protocol: protocol as u8,
-            _pad1: 0, _pad2: 0,
+            protocol,
+            _pad: 0,
+            vlan_id: l4.vlan_id,
         };
         let reverse_conn_key = ConnectionKey {
             src_ip: dest_ip,
             src_port: dest_port_be,
             dst_ip: source_ip,
             dst_port: source_port_be,
-            protocol: protocol as u8,
-            _pad1: 0, _pad2: 0,
+            protocol,
+            _pad: 0,
+            vlan_id: l4.vlan_id,
         };
 
-        if let Some(conn_val_ptr) = unsafe { CONN_TRACK_TABLE.get_ptr_mut(&conn_key) } {
-            let mut current_state_val = unsafe { (*conn_val_ptr).clone() }; // current_state_val est défini ici
-            current_state_val.last_seen_ns = current_time_ns;
+        if let Some(action) = conntrack_lookup(&CONN_TRACK_TABLE, &conn_key, &reverse_conn_key, &l4, current_time_ns)? {
+            emit_event(&addrs, &l4, action, 0, EVENT_REASON_CONNTRACK);
+            return Ok(action);
+        }
 
-            match current_state_val.state {
-                ConnStateVariant::Tcp(ref mut tcp_s) => {
-                    if tcp_flags_byte & TCP_FLAG_RST != 0 {
-                        unsafe { CONN_TRACK_TABLE.remove(&conn_key).map_err(|_| ())? };
-                        info!(&ctx, "CTT: TCP RST (fwd), dropping & removing. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                        return Ok(xdp_action::XDP_DROP);
-                    }
-                    if tcp_flags_byte & TCP_FLAG_FIN != 0 && *tcp_s == TcpState::Established {
-                        *tcp_s = TcpState::FinWait1;
-                        info!(&ctx, "CTT: TCP FIN (fwd) on established. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                    }
-                    else if *tcp_s == TcpState::SynReceived && (tcp_flags_byte & TCP_FLAG_ACK != 0) && !(tcp_flags_byte & TCP_FLAG_SYN != 0) {
-                        *tcp_s = TcpState::Established;
-                        info!(&ctx, "CTT: TCP ACK for SYN-ACK (fwd). ESTABLISHED. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                    }
-                }
-                ConnStateVariant::Udp(ref mut udp_s) => {
-                    if *udp_s == UdpState::New { *udp_s = UdpState::Established; }
+        let action_from_blocklist = rule_hit(ctx, blocklist_lookup_v4(source_ip, dest_ip, &l4));
+        let rule_id = action_from_blocklist.map_or(0, |(_, rule_id)| rule_id);
+
+        match action_from_blocklist.map(|(action, _)| action) {
+            Some(ACTION_DENY_FROM_MAP) => {
+                emit_event(&addrs, &l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_RULE);
+                Ok(xdp_action::XDP_DROP)
+            }
+            Some(ACTION_ALLOW_FROM_MAP) => {
+                if conntrack_start(&CONN_TRACK_TABLE, &conn_key, &l4, current_time_ns)? {
+                    emit_event(&addrs, &l4, xdp_action::XDP_PASS, rule_id, EVENT_REASON_RULE);
+                    Ok(xdp_action::XDP_PASS)
+                } else {
+                    emit_event(&addrs, &l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_INVALID);
+                    Ok(xdp_action::XDP_DROP)
                 }
             }
-            // Utiliser current_state_val et passer par référence
-            unsafe { CONN_TRACK_TABLE.insert(&conn_key, &current_state_val, 0).map_err(|_| ())? };
-            return Ok(xdp_action::XDP_PASS);
+            None => {
+                let (action, log) = default_policy(TRAFFIC_CLASS_UNMATCHED);
+                policy_event(&addrs, &l4, action, log);
+                Ok(action)
+            }
+            _ => {
+                emit_event(&addrs, &l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_INVALID);
+                Ok(xdp_action::XDP_DROP)
+            }
+        }
+    }
 
-        } else if let Some(conn_val_ptr) = unsafe { CONN_TRACK_TABLE.get_ptr_mut(&reverse_conn_key) } {
-            let mut current_state_val = unsafe { (*conn_val_ptr).clone() }; // current_state_val est défini ici
-            current_state_val.last_seen_ns = current_time_ns;
+    fn try_ipv6(ctx: &XdpContext, l3_offset: usize, vlan_id: u16) -> Result<u32, ()> {
+        let (next_hdr, transport_offset, fragment) = ipv6_transport(ctx, l3_offset)?;
+        let fragment = match fragment {
+            Some(fragment) => fragment,
+            None => return try_ipv6_transport(ctx, l3_offset, vlan_id, next_hdr, transport_offset),
+        };
 
-            match current_state_val.state {
-                ConnStateVariant::Tcp(ref mut tcp_s) => {
-                    if tcp_flags_byte & TCP_FLAG_RST != 0 {
-                        unsafe { CONN_TRACK_TABLE.remove(&reverse_conn_key).map_err(|_| ())? };
-                        info!(&ctx, "CTT: TCP RST (rev), dropping & removing. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                        return Ok(xdp_action::XDP_DROP);
-                    }
-                    if *tcp_s == TcpState::SynSent && (tcp_flags_byte & (TCP_FLAG_SYN | TCP_FLAG_ACK) == (TCP_FLAG_SYN | TCP_FLAG_ACK)) {
-                        *tcp_s = TcpState::SynReceived;
-                        info!(&ctx, "CTT: TCP SYN-ACK for SYN (rev). SynReceived. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                    }
-                    else if tcp_flags_byte & TCP_FLAG_FIN != 0 && *tcp_s == TcpState::Established {
-                        info!(&ctx, "CTT: TCP FIN (rev) on established. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                    }
-                }
-                ConnStateVariant::Udp(ref mut udp_s) => {
-                    if *udp_s == UdpState::New {
-                        *udp_s = UdpState::Established;
-                        info!(&ctx, "CTT: UDP Reply. Established. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                    }
-                }
+        // Comme en IPv4 : le datagramme est décidé sur son premier fragment, le seul qui porte
+        // l'en-tête de transport, et les fragments suivants reprennent ce verdict (FRAGMENTS_V6).
+        // Ils ne sont pas laissés passer d'office : ils peuvent être routés au-delà de l'hôte.
+        let current_time_ns = unsafe { bpf_ktime_get_ns() };
+        let ipv6_hdr: *const Ipv6Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+        let source_ip: [u32; 4] = unsafe { (*ipv6_hdr).src_addr.in6_u.u6_addr32 };
+        let dest_ip: [u32; 4] = unsafe { (*ipv6_hdr).dst_addr.in6_u.u6_addr32 };
+        let l4_len = (u16::from_be(unsafe { (*ipv6_hdr).payload_len }) as usize)
+            .saturating_sub(transport_offset - l3_offset - Ipv6Hdr::LEN);
+        let frag_key = FragmentKeyV6 { src_ip: source_ip, dst_ip: dest_ip, id: fragment.id, vlan_id, _pad: 0 };
+        let addrs = EventAddrs::v6(source_ip, dest_ip);
+        let bare = L4Info::new(next_hdr, vlan_id);
+
+        if fragment.offset == 0 {
+            if l4_len < transport_min_len(next_hdr) {
+                fragment_stat(FRAG_STAT_TINY);
+                emit_event(&addrs, &bare, xdp_action::XDP_DROP, 0, EVENT_REASON_FRAGMENT);
+                return Ok(xdp_action::XDP_DROP);
+            }
+            let action = try_ipv6_transport(ctx, l3_offset, vlan_id, next_hdr, transport_offset)?;
+            let first = FragmentValue {
+                first_seen_ns: current_time_ns,
+                action,
+                first_end: l4_len as u16,
+                _pad: 0,
+            };
+            let _ = FRAGMENTS_V6.insert(&frag_key, &first, 0);
+            fragment_stat(FRAG_STAT_FIRST);
+            return Ok(action);
+        }
+
+        Ok(follow_fragment(&FRAGMENTS_V6, &frag_key, fragment.offset, &addrs, &bare, current_time_ns))
+    }
+
+    /// Datagramme IPv6 complet ou premier fragment ; `transport_offset` suit les en-têtes d'extension.
+    fn try_ipv6_transport(ctx: &XdpContext, l3_offset: usize, vlan_id: u16, next_hdr: u8, transport_offset: usize) -> Result<u32, ()> {
+        let current_time_ns = unsafe { bpf_ktime_get_ns() };
+
+        let ipv6_hdr: *const Ipv6Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+        let source_ip: [u32; 4] = unsafe { (*ipv6_hdr).src_addr.in6_u.u6_addr32 };
+        let dest_ip: [u32; 4] = unsafe { (*ipv6_hdr).dst_addr.in6_u.u6_addr32 };
+
+        // payload_len couvre les en-têtes d'extension traversés
+        let l4_len = (u16::from_be(unsafe { (*ipv6_hdr).payload_len }) as usize)
+            .saturating_sub(transport_offset - l3_offset - Ipv6Hdr::LEN);
+        let addrs = EventAddrs::v6(source_ip, dest_ip);
+        let l4 = match parse_l4(ctx, next_hdr, transport_offset, l4_len, vlan_id)? {
+            Some(l4) => l4,
+            None => {
+                let l4 = L4Info::new(next_hdr, vlan_id);
+                let rule = blocklist_lookup_v6(source_ip, dest_ip, &l4);
+                return Ok(other_ip_verdict(ctx, &addrs, &l4, rule));
             }
-            // Utiliser current_state_val et passer par référence
-            unsafe { CONN_TRACK_TABLE.insert(&reverse_conn_key, &current_state_val, 0).map_err(|_| ())? };
-            return Ok(xdp_action::XDP_PASS);
+        };
+        let protocol = l4.protocol;
+        let (source_port_be, dest_port_be) = (l4.source_port_be, l4.dest_port_be);
+
+        if protocol == IPPROTO_ICMPV6 {
+            return try_icmp_v6(ctx, &addrs, transport_offset, &l4, current_time_ns);
         }
 
-        let blocklist_key = IpPort {
-            addr: source_ip,
-            addr_dest: dest_ip,
-            port: dest_port_be,
+        let conn_key = ConnectionKeyV6 {
+            src_ip: source_ip,
+            src_port: source_port_be,
+            dst_ip: dest_ip,
+            dst_port: dest_port_be,
+            protocol,
+            _pad: 0,
+            vlan_id: l4.vlan_id,
+        };
+        let reverse_conn_key = ConnectionKeyV6 {
+            src_ip: dest_ip,
+            src_port: dest_port_be,
+            dst_ip: source_ip,
+            dst_port: source_port_be,
+            protocol,
             _pad: 0,
+            vlan_id: l4.vlan_id,
         };
 
-        let action_from_blocklist = unsafe { BLOCKLIST.get(&blocklist_key).copied() };
+        if let Some(action) = conntrack_lookup(&CONN_TRACK_TABLE_V6, &conn_key, &reverse_conn_key, &l4, current_time_ns)? {
+            emit_event(&addrs, &l4, action, 0, EVENT_REASON_CONNTRACK);
+            return Ok(action);
+        }
+
+        let action_from_blocklist = rule_hit(ctx, blocklist_lookup_v6(source_ip, dest_ip, &l4));
+        let rule_id = action_from_blocklist.map_or(0, |(_, rule_id)| rule_id);
 
-        match action_from_blocklist {
+        match action_from_blocklist.map(|(action, _)| action) {
             Some(ACTION_DENY_FROM_MAP) => {
-                info!(&ctx, "BLOCKLIST: DENY. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                return Ok(xdp_action::XDP_DROP);
+                emit_event(&addrs, &l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_RULE);
+                Ok(xdp_action::XDP_DROP)
             }
             Some(ACTION_ALLOW_FROM_MAP) => {
-                let new_conn_state_opt: Option<ConnStateVariant> = match protocol {
-                    IpProto::Tcp if (tcp_flags_byte & TCP_FLAG_SYN != 0) && !(tcp_flags_byte & TCP_FLAG_ACK != 0) => {
-                        info!(&ctx, "BLOCKLIST: ALLOW new TCP SYN. Creating CTT entry. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                        Some(ConnStateVariant::Tcp(TcpState::SynSent))
-                    }
-                    IpProto::Udp => {
-                        info!(&ctx, "BLOCKLIST: ALLOW new UDP. Creating CTT entry. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                        Some(ConnStateVariant::Udp(UdpState::New))
-                    }
-                    _ => {
-                        info!(&ctx, "BLOCKLIST: ALLOW rule, but not valid init packet (e.g. TCP not SYN). Dropping. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                        None
-                    }
-                };
-
-                if let Some(new_state) = new_conn_state_opt {
-                    let new_conn_val = ConnectionValue {
-                        state: new_state,
-                        last_seen_ns: current_time_ns,
-                    };
-                    unsafe { CONN_TRACK_TABLE.insert(&conn_key, &new_conn_val, 0).map_err(|_| ())? };
-                    return Ok(xdp_action::XDP_PASS);
+                if conntrack_start(&CONN_TRACK_TABLE_V6, &conn_key, &l4, current_time_ns)? {
+                    emit_event(&addrs, &l4, xdp_action::XDP_PASS, rule_id, EVENT_REASON_RULE);
+                    Ok(xdp_action::XDP_PASS)
                 } else {
-                    return Ok(xdp_action::XDP_DROP);
+                    emit_event(&addrs, &l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_INVALID);
+                    Ok(xdp_action::XDP_DROP)
                 }
             }
             None => {
-                info!(&ctx, "DEFAULT DROP (no CTT, no BLOCKLIST allow): {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
-                return Ok(xdp_action::XDP_DROP);
+                let (action, log) = default_policy(TRAFFIC_CLASS_UNMATCHED);
+                policy_event(&addrs, &l4, action, log);
+                Ok(action)
             }
             _ => {
-                info!(&ctx, "BLOCKLIST: Unknown action value. Dropping. {:i}:{} -> {:i}:{}", u32::from_be(source_ip), u16::from_be(source_port_be), u32::from_be(dest_ip), u16::from_be(dest_port_be));
+                emit_event(&addrs, &l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_INVALID);
+                Ok(xdp_action::XDP_DROP)
+            }
+        }
+    }
+
+    /// ICMP : l'écho est suivi par identifiant, une erreur n'est acceptée que si elle cite un
+    /// flux suivi ; les règles (type/code) puis la politique de la classe ICMP décident du reste.
+    fn try_icmp_v4(ctx: &XdpContext, addrs: &EventAddrs, icmp_offset: usize, l4: &L4Info, current_time_ns: u64) -> Result<u32, ()> {
+        let (source_ip, dest_ip) = (addrs.src_ip[0], addrs.dst_ip[0]);
+        let error = is_icmp_error(l4);
+        if error && !icmp_error_quotes_flow_v4(ctx, dest_ip, icmp_offset + core::mem::size_of::<IcmpHdr>(), l4.vlan_id)? {
+            emit_event(addrs, l4, xdp_action::XDP_DROP, 0, EVENT_REASON_INVALID);
+            return Ok(xdp_action::XDP_DROP);
+        }
+
+        let conn_key = ConnectionKey {
+            src_ip: source_ip,
+            src_port: l4.source_port_be,
+            dst_ip: dest_ip,
+            dst_port: l4.dest_port_be,
+            protocol: IPPROTO_ICMP,
+            _pad: 0,
+            vlan_id: l4.vlan_id,
+        };
+        if is_icmp_echo(l4) {
+            let reverse_conn_key = ConnectionKey {
+                src_ip: dest_ip,
+                src_port: l4.dest_port_be,
+                dst_ip: source_ip,
+                dst_port: l4.source_port_be,
+                protocol: IPPROTO_ICMP,
+                _pad: 0,
+                vlan_id: l4.vlan_id,
+            };
+            if let Some(action) = conntrack_lookup(&CONN_TRACK_TABLE, &conn_key, &reverse_conn_key, l4, current_time_ns)? {
+                emit_event(addrs, l4, action, 0, EVENT_REASON_CONNTRACK);
+                return Ok(action);
+            }
+        }
+
+        Ok(match rule_hit(ctx, blocklist_lookup_v4(source_ip, dest_ip, l4)) {
+            Some((ACTION_DENY_FROM_MAP, rule_id)) => {
+                emit_event(addrs, l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_RULE);
+                xdp_action::XDP_DROP
+            }
+            Some((ACTION_ALLOW_FROM_MAP, rule_id)) => {
+                conntrack_start(&CONN_TRACK_TABLE, &conn_key, l4, current_time_ns)?;
+                emit_event(addrs, l4, xdp_action::XDP_PASS, rule_id, EVENT_REASON_RULE);
+                xdp_action::XDP_PASS
+            }
+            Some((_, rule_id)) => {
+                emit_event(addrs, l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_INVALID);
+                xdp_action::XDP_DROP
+            }
+            // Erreur liée à un flux suivi
+            None if error => {
+                emit_event(addrs, l4, xdp_action::XDP_PASS, 0, EVENT_REASON_CONNTRACK);
+                xdp_action::XDP_PASS
+            }
+            None => {
+                let (action, log) = default_policy(TRAFFIC_CLASS_ICMP);
+                policy_event(addrs, l4, action, log);
+                action
+            }
+        })
+    }
+
+    fn try_icmp_v6(ctx: &XdpContext, addrs: &EventAddrs, icmp_offset: usize, l4: &L4Info, current_time_ns: u64) -> Result<u32, ()> {
+        let (source_ip, dest_ip) = (addrs.src_ip, addrs.dst_ip);
+        let error = is_icmp_error(l4);
+        if error && !icmp_error_quotes_flow_v6(ctx, dest_ip, icmp_offset + core::mem::size_of::<IcmpHdr>(), l4.vlan_id)? {
+            emit_event(addrs, l4, xdp_action::XDP_DROP, 0, EVENT_REASON_INVALID);
+            return Ok(xdp_action::XDP_DROP);
+        }
+
+        let conn_key = ConnectionKeyV6 {
+            src_ip: source_ip,
+            src_port: l4.source_port_be,
+            dst_ip: dest_ip,
+            dst_port: l4.dest_port_be,
+            protocol: IPPROTO_ICMPV6,
+            _pad: 0,
+            vlan_id: l4.vlan_id,
+        };
+        if is_icmp_echo(l4) {
+            let reverse_conn_key = ConnectionKeyV6 {
+                src_ip: dest_ip,
+                src_port: l4.dest_port_be,
+                dst_ip: source_ip,
+                dst_port: l4.source_port_be,
+                protocol: IPPROTO_ICMPV6,
+                _pad: 0,
+                vlan_id: l4.vlan_id,
+            };
+            if let Some(action) = conntrack_lookup(&CONN_TRACK_TABLE_V6, &conn_key, &reverse_conn_key, l4, current_time_ns)? {
+                emit_event(addrs, l4, action, 0, EVENT_REASON_CONNTRACK);
+                return Ok(action);
+            }
+        }
+
+        Ok(match rule_hit(ctx, blocklist_lookup_v6(source_ip, dest_ip, l4)) {
+            Some((ACTION_DENY_FROM_MAP, rule_id)) => {
+                emit_event(addrs, l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_RULE);
+                xdp_action::XDP_DROP
+            }
+            Some((ACTION_ALLOW_FROM_MAP, rule_id)) => {
+                conntrack_start(&CONN_TRACK_TABLE_V6, &conn_key, l4, current_time_ns)?;
+                emit_event(addrs, l4, xdp_action::XDP_PASS, rule_id, EVENT_REASON_RULE);
+                xdp_action::XDP_PASS
+            }
+            Some((_, rule_id)) => {
+                emit_event(addrs, l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_INVALID);
+                xdp_action::XDP_DROP
+            }
+            // Erreur liée à un flux suivi
+            None if error => {
+                emit_event(addrs, l4, xdp_action::XDP_PASS, 0, EVENT_REASON_CONNTRACK);
+                xdp_action::XDP_PASS
+            }
+            None => {
+                let (action, log) = default_policy(TRAFFIC_CLASS_ICMP);
+                policy_event(addrs, l4, action, log);
+                action
+            }
+        })
+    }
+
+    #[inline(always)]
+    fn is_icmp_echo(l4: &L4Info) -> bool {
+        match l4.protocol {
+            IPPROTO_ICMP => l4.icmp_type == ICMP_ECHO_REQUEST || l4.icmp_type == ICMP_ECHO_REPLY,
+            IPPROTO_ICMPV6 => l4.icmp_type == ICMPV6_ECHO_REQUEST || l4.icmp_type == ICMPV6_ECHO_REPLY,
+            _ => false,
+        }
+    }
+
+    #[inline(always)]
+    fn is_icmp_echo_request(l4: &L4Info) -> bool {
+        match l4.protocol {
+            IPPROTO_ICMP => l4.icmp_type == ICMP_ECHO_REQUEST,
+            IPPROTO_ICMPV6 => l4.icmp_type == ICMPV6_ECHO_REQUEST,
+            _ => false,
+        }
+    }
+
+    #[inline(always)]
+    fn is_icmp_error(l4: &L4Info) -> bool {
+        match l4.protocol {
+            IPPROTO_ICMP => matches!(l4.icmp_type, ICMP_DEST_UNREACH | ICMP_TIME_EXCEEDED | ICMP_PARAMETER_PROB),
+            IPPROTO_ICMPV6 => matches!(l4.icmp_type, ICMPV6_DEST_UNREACH | ICMPV6_PACKET_TOO_BIG | ICMPV6_TIME_EXCEEDED | ICMPV6_PARAMETER_PROB),
+            _ => false,
+        }
+    }
+
+    /// Ports (network byte order) cités par une erreur ICMP : TCP/UDP, ou identifiant d'un écho.
+    #[inline(always)]
+    fn quoted_ports(ctx: &XdpContext, protocol: u8, offset: usize) -> Result<Option<(u16, u16)>, ()> {
+        match protocol {
+            IPPROTO_TCP | IPPROTO_UDP => {
+                // TCP et UDP commencent tous deux par (port source, port destination)
+                let ports: *const [u16; 2] = unsafe { ptr_at(ctx, offset)? };
+                Ok(Some(unsafe { ((*ports)[0], (*ports)[1]) }))
+            }
+            IPPROTO_ICMP | IPPROTO_ICMPV6 => {
+                let icmp_hdr: *const IcmpHdr = unsafe { ptr_at(ctx, offset)? };
+                let id = unsafe { (*icmp_hdr).id };
+                Ok(Some((id, id)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Vrai si le paquet cité par l'erreur a été émis par `local_ip` (destinataire de l'erreur)
+    /// et appartient à un flux de la table de suivi, dans un sens ou dans l'autre.
+    #[inline(always)]
+    fn icmp_error_quotes_flow_v4(ctx: &XdpContext, local_ip: u32, inner_offset: usize, vlan_id: u16) -> Result<bool, ()> {
+        let inner_hdr: *const Ipv4Hdr = unsafe { ptr_at(ctx, inner_offset)? };
+        let inner_src = unsafe { (*inner_hdr).src_addr };
+        if inner_src != local_ip {
+            return Ok(false);
+        }
+        let inner_dst = unsafe { (*inner_hdr).dst_addr };
+        let protocol = unsafe { (*inner_hdr).proto } as u8;
+        let inner_l4_offset = inner_offset + unsafe { (*inner_hdr).ihl() } as usize * 4;
+        let (src_port, dst_port) = match quoted_ports(ctx, protocol, inner_l4_offset)? {
+            Some(ports) => ports,
+            None => return Ok(false),
+        };
+        let key = ConnectionKey { src_ip: inner_src, src_port, dst_ip: inner_dst, dst_port, protocol, _pad: 0, vlan_id };
+        let reverse_key = ConnectionKey { src_ip: inner_dst, src_port: dst_port, dst_ip: inner_src, dst_port: src_port, protocol, _pad: 0, vlan_id };
+        Ok(CONN_TRACK_TABLE.get_ptr(&key).is_some() || CONN_TRACK_TABLE.get_ptr(&reverse_key).is_some())
+    }
+
+    /// Variante IPv6 ; les en-têtes d'extension du paquet cité ne sont pas parcourus.
+    #[inline(always)]
+    fn icmp_error_quotes_flow_v6(ctx: &XdpContext, local_ip: [u32; 4], inner_offset: usize, vlan_id: u16) -> Result<bool, ()> {
+        let inner_hdr: *const Ipv6Hdr = unsafe { ptr_at(ctx, inner_offset)? };
+        let inner_src: [u32; 4] = unsafe { (*inner_hdr).src_addr.in6_u.u6_addr32 };
+        if inner_src != local_ip {
+            return Ok(false);
+        }
+        let inner_dst: [u32; 4] = unsafe { (*inner_hdr).dst_addr.in6_u.u6_addr32 };
+        let protocol = unsafe { (*inner_hdr).next_hdr } as u8;
+        let (src_port, dst_port) = match quoted_ports(ctx, protocol, inner_offset + Ipv6Hdr::LEN)? {
+            Some(ports) => ports,
+            None => return Ok(false),
+        };
+        let key = ConnectionKeyV6 { src_ip: inner_src, src_port, dst_ip: inner_dst, dst_port, protocol, _pad: 0, vlan_id };
+        let reverse_key = ConnectionKeyV6 { src_ip: inner_dst, src_port: dst_port, dst_ip: inner_src, dst_port: src_port, protocol, _pad: 0, vlan_id };
+        Ok(CONN_TRACK_TABLE_V6.get_ptr(&key).is_some() || CONN_TRACK_TABLE_V6.get_ptr(&reverse_key).is_some())
+    }
+
+    /// Parcours borné des en-têtes d'extension IPv6 jusqu'à l'en-tête de transport.
+    /// Retourne (protocole, offset, fragment). Pour un fragment non initial, l'offset est celui
+    /// de sa charge utile, qui ne contient pas d'en-tête de transport.
+    #[inline(always)]
+    fn ipv6_transport<C: PacketContext>(ctx: &C, l3_offset: usize) -> Result<(u8, usize, Option<Ipv6Fragment>), ()> {
+        let ipv6_hdr: *const Ipv6Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+        let mut next_hdr = unsafe { (*ipv6_hdr).next_hdr } as u8;
+        let mut transport_offset = l3_offset + Ipv6Hdr::LEN;
+        let mut fragment = None;
+
+        for _ in 0..MAX_IPV6_EXT_HEADERS {
+            match next_hdr {
+                IPV6_EXT_HOP_BY_HOP | IPV6_EXT_ROUTING | IPV6_EXT_DEST_OPTS => {
+                    let ext_hdr: *const Ipv6ExtHdr = unsafe { ptr_at(ctx, transport_offset)? };
+                    next_hdr = unsafe { (*ext_hdr).next_hdr };
+                    transport_offset += (unsafe { (*ext_hdr).hdr_ext_len } as usize + 1) * 8;
+                }
+                IPV6_EXT_AUTH => {
+                    // La longueur de AH est exprimée en mots de 4 octets, moins 2
+                    let ext_hdr: *const Ipv6ExtHdr = unsafe { ptr_at(ctx, transport_offset)? };
+                    next_hdr = unsafe { (*ext_hdr).next_hdr };
+                    transport_offset += (unsafe { (*ext_hdr).hdr_ext_len } as usize + 2) * 4;
+                }
+                IPV6_EXT_FRAGMENT => {
+                    let frag_hdr: *const Ipv6FragHdr = unsafe { ptr_at(ctx, transport_offset)? };
+                    let frag_off = u16::from_be(unsafe { (*frag_hdr).frag_off });
+                    next_hdr = unsafe { (*frag_hdr).next_hdr };
+                    transport_offset += core::mem::size_of::<Ipv6FragHdr>();
+                    let offset = (frag_off & IPV6_FRAG_OFFSET_MASK) as usize;
+                    // Fragment atomique (offset 0, sans M) : datagramme complet (RFC 6946)
+                    if offset != 0 || frag_off & IPV6_FRAG_MF != 0 {
+                        fragment = Some(Ipv6Fragment { id: unsafe { (*frag_hdr).identification }, offset });
+                    }
+                    if offset != 0 {
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+        Ok((next_hdr, transport_offset, fragment))
+    }
+
+    /// Première règle de l'ensemble dont les plages couvrent le paquet : ports, ou type et
+    /// code pour ICMP. `any_protocol` : ensemble des règles "tout protocole", dont les plages
+    /// sont des ports.
+    #[inline(always)]
+    fn match_rule_set(set: &RuleSet, l4: &L4Info, any_protocol: bool) -> Option<RuleEntry> {
+        let has_ports = l4.protocol == IPPROTO_TCP || l4.protocol == IPPROTO_UDP;
+        let (src_port, dst_port) = if has_ports {
+            (u16::from_be(l4.source_port_be), u16::from_be(l4.dest_port_be))
+        } else {
+            (l4.icmp_type as u16, l4.icmp_code as u16)
+        };
+
+        for i in 0..MAX_RULES_PER_KEY {
+            if i as u32 >= set.count {
+                break;
+            }
+            let entry = set.entries.get(i)?;
+            let wildcard = entry.src_port_min == 0 && entry.src_port_max == u16::MAX
+                && entry.dst_port_min == 0 && entry.dst_port_max == u16::MAX;
+            if !has_ports && any_protocol && !wildcard {
+                continue;
+            }
+            if entry.vlan_id != 0 && entry.vlan_id != l4.vlan_id {
+                continue;
+            }
+            if src_port >= entry.src_port_min && src_port <= entry.src_port_max
+                && dst_port >= entry.dst_port_min && dst_port <= entry.dst_port_max
+            {
+                return Some(*entry);
+            }
+        }
+        None
+    }
+
+    /// Première règle de la chaîne qui couvre le paquet : l'ensemble de tête, puis ses maillons.
+    /// Les rangs croissent le long de la chaîne, la première trouvée est donc la meilleure.
+    #[inline(always)]
+    fn match_rule_chain(set: &RuleSet, links: &HashMap<RuleSetLink, RuleSet>, l4: &L4Info, any_protocol: bool) -> Option<RuleEntry> {
+        if let Some(entry) = match_rule_set(set, l4, any_protocol) {
+            return Some(entry);
+        }
+        for link in 0..MAX_RULE_SET_LINKS {
+            if link >= set.links {
+                break;
+            }
+            // Maillon absent (mise à jour en cours) : la suite de la chaîne est ignorée
+            let next = unsafe { links.get(&RuleSetLink { chain: set.chain, link }) }?;
+            if let Some(entry) = match_rule_set(next, l4, any_protocol) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+
+    /// Protocole dont l'en-tête de transport n'est pas lu (GRE, ESP, SCTP...) : seules les règles
+    /// "tout protocole" sans ports s'appliquent, sans suivi de connexion ; sinon politique OTHER_IP.
+    #[inline(always)]
+    fn other_ip_verdict(ctx: &XdpContext, addrs: &EventAddrs, l4: &L4Info, rule: Option<RuleEntry>) -> u32 {
+        match rule_hit(ctx, rule) {
+            Some((ACTION_DENY_FROM_MAP, rule_id)) => {
+                emit_event(addrs, l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_RULE);
+                xdp_action::XDP_DROP
+            }
+            Some((ACTION_ALLOW_FROM_MAP, rule_id)) => {
+                emit_event(addrs, l4, xdp_action::XDP_PASS, rule_id, EVENT_REASON_RULE);
+                xdp_action::XDP_PASS
+            }
+            Some((_, rule_id)) => {
+                emit_event(addrs, l4, xdp_action::XDP_DROP, rule_id, EVENT_REASON_INVALID);
+                xdp_action::XDP_DROP
+            }
+            None => {
+                let (action, log) = default_policy(TRAFFIC_CLASS_OTHER_IP);
+                policy_event(addrs, l4, action, log);
+                action
+            }
+        }
+    }
+
+    /// Compte le paquet pour la règle retenue et renvoie (action, ID de règle).
+    #[inline(always)]
+    fn rule_hit(ctx: &XdpContext, rule: Option<RuleEntry>) -> Option<(u32, u32)> {
+        let entry = rule?;
+        let bytes = (ctx.data_end() - ctx.data()) as u64;
+        match RULE_STATS.get_ptr_mut(&entry.rule_id) {
+            Some(stats) => unsafe {
+                (*stats).packets += 1;
+                (*stats).bytes += bytes;
+            },
+            None => {
+                let _ = RULE_STATS.insert(&entry.rule_id, &RuleStats { packets: 1, bytes }, 0);
+            }
+        }
+        Some((entry.action, entry.rule_id))
+    }
+
+    #[inline(always)]
+    fn first_by_rank(best: Option<RuleEntry>, entry: RuleEntry) -> Option<RuleEntry> {
+        match best {
+            Some(b) if b.rank <= entry.rank => Some(b),
+            _ => Some(entry),
+        }
+    }
+
+    /// Jeu de tries lu pour ce paquet (génération impaire : jeu _B). La génération n'est lue
+    /// qu'une fois par recherche : préfixe source et règles viennent toujours du même jeu.
+    #[inline(always)]
+    fn rules_bank_b() -> bool {
+        matches!(RULES_GENERATION.get(0), Some(generation) if *generation & 1 == 1)
+    }
+
+    /// Recherche de la règle applicable : la première par rang parmi les ensembles
+    /// protocole exact et tout protocole. Le trie retient le préfixe destination le
+    /// plus long ; son ensemble inclut les règles des préfixes moins spécifiques.
+    #[inline(always)]
+    fn blocklist_lookup_v4(source_ip: u32, dest_ip: u32, l4: &L4Info) -> Option<RuleEntry> {
+        if rules_bank_b() {
+            blocklist_lookup_v4_in(&SRC_PREFIXES_B, &BLOCKLIST_B, &RULE_SET_LINKS_B, source_ip, dest_ip, l4)
+        } else {
+            blocklist_lookup_v4_in(&SRC_PREFIXES, &BLOCKLIST, &RULE_SET_LINKS, source_ip, dest_ip, l4)
+        }
+    }
+
+    #[inline(always)]
+    fn blocklist_lookup_v4_in(
+        src_prefixes: &LpmTrie<u32, u32>,
+        blocklist: &LpmTrie<RuleKey, RuleSet>,
+        links: &HashMap<RuleSetLink, RuleSet>,
+        source_ip: u32,
+        dest_ip: u32,
+        l4: &L4Info,
+    ) -> Option<RuleEntry> {
+        // Sans préfixe source connu, aucune règle ne peut correspondre
+        let src_class = *src_prefixes.get(&Key::new(32, source_ip))?;
+        let mut best = None;
+        for protocol in [l4.protocol, 0] {
+            let blocklist_key = RuleKey {
+                src_class,
+                protocol,
+                _pad: [0; 3],
+                addr_dest: dest_ip,
+            };
+            if let Some(entry) = blocklist.get(&Key::new(RULE_KEY_PREFIX_BITS + 32, blocklist_key)).and_then(|set| match_rule_chain(set, links, l4, protocol == 0)) {
+                best = first_by_rank(best, entry);
+            }
+        }
+        best
+    }
+
+    #[inline(always)]
+    fn blocklist_lookup_v6(source_ip: [u32; 4], dest_ip: [u32; 4], l4: &L4Info) -> Option<RuleEntry> {
+        if rules_bank_b() {
+            blocklist_lookup_v6_in(&SRC_PREFIXES_V6_B, &BLOCKLIST_V6_B, &RULE_SET_LINKS_B, source_ip, dest_ip, l4)
+        } else {
+            blocklist_lookup_v6_in(&SRC_PREFIXES_V6, &BLOCKLIST_V6, &RULE_SET_LINKS, source_ip, dest_ip, l4)
+        }
+    }
+
+    #[inline(always)]
+    fn blocklist_lookup_v6_in(
+        src_prefixes: &LpmTrie<[u32; 4], u32>,
+        blocklist: &LpmTrie<RuleKeyV6, RuleSet>,
+        links: &HashMap<RuleSetLink, RuleSet>,
+        source_ip: [u32; 4],
+        dest_ip: [u32; 4],
+        l4: &L4Info,
+    ) -> Option<RuleEntry> {
+        let src_class = *src_prefixes.get(&Key::new(128, source_ip))?;
+        let mut best = None;
+        for protocol in [l4.protocol, 0] {
+            let blocklist_key = RuleKeyV6 {
+                src_class,
+                protocol,
+                _pad: [0; 3],
+                addr_dest: dest_ip,
+            };
+            if let Some(entry) = blocklist.get(&Key::new(RULE_KEY_PREFIX_BITS + 128, blocklist_key)).and_then(|set| match_rule_chain(set, links, l4, protocol == 0)) {
+                best = first_by_rank(best, entry);
+            }
+        }
+        best
+    }
+
+    /// Cherche le flux dans la table de suivi (sens aller puis retour) et met à jour son état.
+    /// Retourne `Some(action)` si le flux est connu, `None` sinon.
+    #[inline(always)]
+    fn conntrack_lookup<K>(
+        table: &HashMap<K, ConnectionValue>,
+        conn_key: &K,
+        reverse_conn_key: &K,
+        l4: &L4Info,
+        current_time_ns: u64,
+    ) -> Result<Option<u32>, ()> {
+        if let Some(conn_val_ptr) = table.get_ptr_mut(conn_key) {
+            let conn_val = unsafe { &mut *conn_val_ptr };
+            return conntrack_update(table, conn_key, conn_val, l4, true, current_time_ns).map(Some);
+        }
+        if let Some(conn_val_ptr) = table.get_ptr_mut(reverse_conn_key) {
+            let conn_val = unsafe { &mut *conn_val_ptr };
+            return conntrack_update(table, reverse_conn_key, conn_val, l4, false, current_time_ns).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Met à jour l'entrée d'un flux connu. `forward` : paquet émis par l'initiateur du flux.
+    #[inline(always)]
+    fn conntrack_update<K>(
+        table: &HashMap<K, ConnectionValue>,
+        key: &K,
+        conn_val: &mut ConnectionValue,
+        l4: &L4Info,
+        forward: bool,
+        current_time_ns: u64,
+    ) -> Result<u32, ()> {
+        if l4.protocol == IPPROTO_TCP {
+            let new_syn = l4.tcp_flags & (TCP_FLAG_SYN | TCP_FLAG_ACK) == TCP_FLAG_SYN;
+            if forward && new_syn && conn_val.state == TCP_TIME_WAIT {
+                // Réutilisation du tuple : nouvelle connexion, nouvelles séquences
+                conn_val.tcp = [tcp_window_from_syn(l4), TcpWindow::UNSEEN];
+                conn_val.first_seen_ns = current_time_ns;
+            } else if !tcp_window_check(conn_val, l4, forward) {
+                // Segment (ou RST) hors fenêtre : injection ou RST aveugle, l'entrée n'est pas touchée
                 return Ok(xdp_action::XDP_DROP);
             }
+            conn_val.last_seen_ns = current_time_ns;
+
+            // Le RST doit atteindre l'autre extrémité ; la connexion est terminée pour nous
+            if l4.tcp_flags & TCP_FLAG_RST != 0 {
+                table.remove(key).map_err(|_| ())?;
+                return Ok(xdp_action::XDP_PASS);
+            }
+            conn_val.state = tcp_next_state(conn_val.state, l4.tcp_flags, forward);
+        } else {
+            conn_val.last_seen_ns = current_time_ns;
+            if conn_val.state == UdpState::New as u8 {
+                conn_val.state = UdpState::Established as u8;
+            }
         }
-    }
\ No newline at end of file
+        Ok(xdp_action::XDP_PASS)
+    }
+
+    #[inline(always)]
+    fn seq_before(a: u32, b: u32) -> bool {
+        (a.wrapping_sub(b) as i32) < 0
+    }
+
+    #[inline(always)]
+    fn seq_after(a: u32, b: u32) -> bool {
+        seq_before(b, a)
+    }
+
+    /// Fenêtre du sens initiateur d'après son SYN.
+    #[inline(always)]
+    fn tcp_window_from_syn(l4: &L4Info) -> TcpWindow {
+        let end = l4.seq.wrapping_add(1).wrapping_add(l4.payload_len);
+        TcpWindow {
+            end,
+            max_end: end,
+            max_win: if l4.window == 0 { 1 } else { l4.window as u32 },
+            scale: l4.wscale,
+            seen: 1,
+            _pad: [0; 2],
+        }
+    }
+
+    /// Vérifie qu'un segment est dans la fenêtre du flux puis met à jour le suivi de séquence
+    /// (algorithme de nf_conntrack, d'après Guido van Rooij). `false` : segment hors fenêtre.
+    #[inline(always)]
+    fn tcp_window_check(conn_val: &mut ConnectionValue, l4: &L4Info, forward: bool) -> bool {
+        let syn = l4.tcp_flags & TCP_FLAG_SYN != 0;
+        let ack_flag = l4.tcp_flags & TCP_FLAG_ACK != 0;
+        let fin = l4.tcp_flags & TCP_FLAG_FIN != 0;
+        let [initiator, responder] = &mut conn_val.tcp;
+        let (sender, receiver) = if forward { (initiator, responder) } else { (responder, initiator) };
+
+        let seq = l4.seq;
+        let ack = l4.ack_seq;
+        let end = seq.wrapping_add(l4.payload_len).wrapping_add(syn as u32).wrapping_add(fin as u32);
+
+        if sender.seen == 0 {
+            // Premier segment de l'autre extrémité : il doit acquitter exactement le SYN
+            // (SYN-ACK, ou RST de refus) ; un SYN seul est une ouverture simultanée.
+            if ack_flag && ack != receiver.end {
+                return false;
+            }
+            if !syn {
+                return ack_flag;
+            }
+            *sender = TcpWindow {
+                end,
+                max_end: end,
+                max_win: if l4.window == 0 { 1 } else { l4.window as u32 },
+                scale: l4.wscale,
+                seen: 1,
+                _pad: [0; 2],
+            };
+            // La mise à l'échelle ne s'applique que si les deux SYN l'ont annoncée
+            if sender.scale == TCP_WSCALE_UNSET || receiver.scale == TCP_WSCALE_UNSET {
+                sender.scale = 0;
+                receiver.scale = 0;
+            }
+        } else if receiver.seen != 0 {
+            let max_ack_window = if sender.max_win == 0 { TCP_MAX_ACK_WINDOW } else { sender.max_win };
+            let in_window = !seq_after(seq, sender.max_end)                              // pas au-delà de la fenêtre du récepteur
+                && !seq_before(end, sender.end.wrapping_sub(receiver.max_win))           // pas trop ancien
+                && (!ack_flag
+                    || (!seq_after(ack, receiver.end)                                    // n'acquitte que des données émises
+                        && !seq_before(ack, receiver.end.wrapping_sub(max_ack_window)))); // ni un ACK trop ancien
+            if !in_window {
+                return false;
+            }
+        }
+
+        // La fenêtre d'un SYN n'est jamais mise à l'échelle
+        let shift = if syn || sender.scale == TCP_WSCALE_UNSET { 0 } else { sender.scale };
+        let win = (l4.window as u32) << shift;
+        if sender.max_win < win {
+            sender.max_win = win;
+        }
+        if seq_after(end, sender.end) {
+            sender.end = end;
+        }
+        if ack_flag {
+            let right_edge = ack.wrapping_add(if win == 0 { 1 } else { win });
+            if seq_after(right_edge, receiver.max_end) {
+                receiver.max_end = right_edge;
+            }
+        }
+        true
+    }
+
+    const TCP_TIME_WAIT: u8 = TcpState::TimeWait as u8;
+
+    /// Crée l'entrée de suivi pour un nouveau flux autorisé par une règle ALLOW.
+    /// Retourne `false` si le paquet ne peut pas ouvrir de flux (ex: TCP sans SYN).
+    #[inline(always)]
+    fn conntrack_start<K>(
+        table: &HashMap<K, ConnectionValue>,
+        conn_key: &K,
+        l4: &L4Info,
+        current_time_ns: u64,
+    ) -> Result<bool, ()> {
+        let state = match l4.protocol {
+            IPPROTO_TCP if (l4.tcp_flags & TCP_FLAG_SYN != 0) && (l4.tcp_flags & TCP_FLAG_ACK == 0) => TcpState::SynSent as u8,
+            IPPROTO_UDP => UdpState::New as u8,
+            // Écho ICMP : suivi comme un flux UDP, clé sur l'identifiant
+            IPPROTO_ICMP | IPPROTO_ICMPV6 if is_icmp_echo_request(l4) => UdpState::New as u8,
+            _ => return Ok(false),
+        };
+
+        let initiator = if l4.protocol == IPPROTO_TCP { tcp_window_from_syn(l4) } else { TcpWindow::UNSEEN };
+        let new_conn_val = ConnectionValue {
+            last_seen_ns: current_time_ns,
+            first_seen_ns: current_time_ns,
+            state,
+            protocol: l4.protocol,
+            _pad: [0; 6],
+            tcp: [initiator, TcpWindow::UNSEEN],
+        };
+        table.insert(conn_key, &new_conn_val, 0).map_err(|_| datapath_stat(DATAPATH_STAT_CONNTRACK_INSERT_FAILED))?;
+        Ok(true)
+    }
+
+    fn try_tc_egress(ctx: &TcContext) -> Result<(), ()> {
+        let current_time_ns = unsafe { bpf_ktime_get_ns() };
+        let (ether_type, l3_offset, mut vlan_id) = parse_l2(ctx)?;
+        // Avec l'accélération matérielle, la balise VLAN est portée par le skb et non par la trame
+        if vlan_id == 0 && unsafe { (*ctx.skb.skb).vlan_present } != 0 {
+            vlan_id = unsafe { (*ctx.skb.skb).vlan_tci } as u16 & VLAN_VID_MASK;
+        }
+        // Longueur prise sur le skb : avec GSO/TSO l'en-tête IP ne décrit pas tout le super-paquet
+        let l4_len = |transport_offset: usize| (ctx.len() as usize).saturating_sub(transport_offset);
+
+        match ether_type {
+            ETH_P_IPV4 => {
+                let ipv4_hdr: *const Ipv4Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+                let source_ip = unsafe { (*ipv4_hdr).src_addr };
+                let dest_ip = unsafe { (*ipv4_hdr).dst_addr };
+                let protocol = unsafe { (*ipv4_hdr).proto } as u8;
+                let transport_offset = l3_offset + unsafe { (*ipv4_hdr).ihl() } as usize * 4;
+                // Fragment non initial : pas d'en-tête de transport
+                if u16::from_be(unsafe { (*ipv4_hdr).frag_off }) & IP_OFFSET_MASK != 0 {
+                    return Ok(());
+                }
+                if protocol != IPPROTO_TCP && protocol != IPPROTO_UDP && protocol != IPPROTO_ICMP {
+                    return Ok(());
+                }
+                let l4 = match parse_l4(ctx, protocol, transport_offset, l4_len(transport_offset), vlan_id)? {
+                    Some(l4) => l4,
+                    None => return Ok(()),
+                };
+                let conn_key = ConnectionKey {
+                    src_ip: source_ip,
+                    src_port: l4.source_port_be,
+                    dst_ip: dest_ip,
+                    dst_port: l4.dest_port_be,
+                    protocol,
+                    _pad: 0,
+                    vlan_id: l4.vlan_id,
+                };
+                let reverse_conn_key = ConnectionKey {
+                    src_ip: dest_ip,
+                    src_port: l4.dest_port_be,
+                    dst_ip: source_ip,
+                    dst_port: l4.source_port_be,
+                    protocol,
+                    _pad: 0,
+                    vlan_id: l4.vlan_id,
+                };
+                egress_track(&CONN_TRACK_TABLE, &conn_key, &reverse_conn_key, &l4, current_time_ns)
+            }
+            ETH_P_IPV6 => {
+                let ipv6_hdr: *const Ipv6Hdr = unsafe { ptr_at(ctx, l3_offset)? };
+                let source_ip: [u32; 4] = unsafe { (*ipv6_hdr).src_addr.in6_u.u6_addr32 };
+                let dest_ip: [u32; 4] = unsafe { (*ipv6_hdr).dst_addr.in6_u.u6_addr32 };
+                let (protocol, transport_offset, fragment) = ipv6_transport(ctx, l3_offset)?;
+                if matches!(fragment, Some(fragment) if fragment.offset != 0) {
+                    return Ok(());
+                }
+                if protocol != IPPROTO_TCP && protocol != IPPROTO_UDP && protocol != IPPROTO_ICMPV6 {
+                    return Ok(());
+                }
+                let l4 = match parse_l4(ctx, protocol, transport_offset, l4_len(transport_offset), vlan_id)? {
+                    Some(l4) => l4,
+                    None => return Ok(()),
+                };
+                let conn_key = ConnectionKeyV6 {
+                    src_ip: source_ip,
+                    src_port: l4.source_port_be,
+                    dst_ip: dest_ip,
+                    dst_port: l4.dest_port_be,
+                    protocol,
+                    _pad: 0,
+                    vlan_id: l4.vlan_id,
+                };
+                let reverse_conn_key = ConnectionKeyV6 {
+                    src_ip: dest_ip,
+                    src_port: l4.dest_port_be,
+                    dst_ip: source_ip,
+                    dst_port: l4.source_port_be,
+                    protocol,
+                    _pad: 0,
+                    vlan_id: l4.vlan_id,
+                };
+                egress_track(&CONN_TRACK_TABLE_V6, &conn_key, &reverse_conn_key, &l4, current_time_ns)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Met à jour le flux connu (sortant ou réponse d'un flux entrant), sinon ouvre
+    /// l'entrée d'un flux initié par l'hôte (SYN, premier datagramme UDP ou écho ICMP).
+    #[inline(always)]
+    fn egress_track<K>(
+        table: &HashMap<K, ConnectionValue>,
+        conn_key: &K,
+        reverse_conn_key: &K,
+        l4: &L4Info,
+        current_time_ns: u64,
+    ) -> Result<(), ()> {
+        // Le verdict (segment hors fenêtre) est ignoré : on ne filtre pas le trafic de l'hôte
+        if conntrack_lookup(table, conn_key, reverse_conn_key, l4, current_time_ns)?.is_none() {
+            conntrack_start(table, conn_key, l4, current_time_ns)?;
+        }
+        Ok(())
+    }
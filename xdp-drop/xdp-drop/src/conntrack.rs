@@ -0,0 +1,601 @@
+// Expiration des entrées de suivi de connexion (CONN_TRACK_TABLE / CONN_TRACK_TABLE_V6).
+//
+// `last_seen_ns` est écrit par le programme XDP avec bpf_ktime_get_ns(), c'est-à-dire
+// l'horloge CLOCK_MONOTONIC du noyau : le daemon lit la même horloge pour calculer l'âge
+// de chaque entrée et retire celles qui ont dépassé le timeout de leur état.
+// Les timeouts (par état TCP, UDP, écho ICMP, et surcharges par port destination) sont
+// modifiables au runtime via gRPC et persistés dans la table conntrack_timeouts. Les entrées
+// peuvent aussi être listées et retirées à la demande (équivalent de conntrack -L/-D/-F).
+
+use aya::maps::{HashMap as AyaHashMap, MapData, MapError};
+use log::warn;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use xdp_drop_common::{ConnectionKey, ConnectionKeyV6, ConnectionValue, TcpState, UdpState};
+
+use crate::rules::{ipv6_from_be_words, ipv6_to_be_words, Blocklists, IpPrefix, Rule, ACTION_ALLOW, ACTION_DENY};
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMPV6: u8 = 58;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+const NS_PER_S: u64 = 1_000_000_000;
+
+// Flux d'une entrée de suivi, dans le sens de son initiateur
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Flow {
+    pub source: IpAddr,
+    pub dest: IpAddr,
+    pub source_port: u16, // Écho ICMP : identifiant (source et destination)
+    pub dest_port: u16,
+    pub protocol: u8,
+    pub vlan_id: u16, // 0 = non balisé
+}
+
+impl Flow {
+    pub fn reversed(&self) -> Self {
+        Self {
+            source: self.dest,
+            dest: self.source,
+            source_port: self.dest_port,
+            dest_port: self.source_port,
+            ..*self
+        }
+    }
+}
+
+// Clé de table de suivi : le port destination est celui du flux initial (le service)
+pub trait FlowKey: aya::Pod {
+    fn dst_port(&self) -> u16;
+    fn flow(&self) -> Flow;
+    // None si le flux n'est pas de la famille de la table
+    fn from_flow(flow: &Flow) -> Option<Self>;
+}
+
+impl FlowKey for ConnectionKey {
+    fn dst_port(&self) -> u16 {
+        u16::from_be(self.dst_port)
+    }
+
+    fn flow(&self) -> Flow {
+        Flow {
+            source: IpAddr::V4(Ipv4Addr::from(u32::from_be(self.src_ip))),
+            dest: IpAddr::V4(Ipv4Addr::from(u32::from_be(self.dst_ip))),
+            source_port: u16::from_be(self.src_port),
+            dest_port: u16::from_be(self.dst_port),
+            protocol: self.protocol,
+            vlan_id: self.vlan_id,
+        }
+    }
+
+    fn from_flow(flow: &Flow) -> Option<Self> {
+        match (flow.source, flow.dest) {
+            (IpAddr::V4(source), IpAddr::V4(dest)) => Some(ConnectionKey {
+                src_ip: u32::from(source).to_be(),
+                dst_ip: u32::from(dest).to_be(),
+                src_port: flow.source_port.to_be(),
+                dst_port: flow.dest_port.to_be(),
+                protocol: flow.protocol,
+                _pad: 0,
+                vlan_id: flow.vlan_id,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl FlowKey for ConnectionKeyV6 {
+    fn dst_port(&self) -> u16 {
+        u16::from_be(self.dst_port)
+    }
+
+    fn flow(&self) -> Flow {
+        Flow {
+            source: IpAddr::V6(ipv6_from_be_words(self.src_ip)),
+            dest: IpAddr::V6(ipv6_from_be_words(self.dst_ip)),
+            source_port: u16::from_be(self.src_port),
+            dest_port: u16::from_be(self.dst_port),
+            protocol: self.protocol,
+            vlan_id: self.vlan_id,
+        }
+    }
+
+    fn from_flow(flow: &Flow) -> Option<Self> {
+        match (flow.source, flow.dest) {
+            (IpAddr::V6(source), IpAddr::V6(dest)) => Some(ConnectionKeyV6 {
+                src_ip: ipv6_to_be_words(&source),
+                dst_ip: ipv6_to_be_words(&dest),
+                src_port: flow.source_port.to_be(),
+                dst_port: flow.dest_port.to_be(),
+                protocol: flow.protocol,
+                _pad: 0,
+                vlan_id: flow.vlan_id,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Réglage de timeout adressable au runtime (gRPC, table conntrack_timeouts)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TimeoutTarget {
+    Tcp(u8), // État TcpState
+    Udp,
+    Icmp, // Écho ICMP / ICMPv6 (clé : identifiant de l'écho)
+    // Surcharge par port destination : s'applique aux flux UDP et aux flux TCP établis
+    Port { protocol: u8, port: u16 },
+}
+
+const TCP_STATE_NAMES: [(TcpState, &str); 9] = [
+    (TcpState::SynSent, "syn_sent"),
+    (TcpState::SynReceived, "syn_received"),
+    (TcpState::Established, "established"),
+    (TcpState::FinWait1, "fin_wait1"),
+    (TcpState::FinWait2, "fin_wait2"),
+    (TcpState::CloseWait, "close_wait"),
+    (TcpState::Closing, "closing"),
+    (TcpState::LastAck, "last_ack"),
+    (TcpState::TimeWait, "time_wait"),
+];
+
+impl TimeoutTarget {
+    // protocole "tcp" / "udp" ; état TCP (vide pour UDP et les ports) ; port 0 = pas de surcharge
+    pub fn parse(protocol: &str, state: &str, port: u32) -> Result<Self, String> {
+        let protocol = match protocol.trim().to_lowercase().as_str() {
+            "tcp" => IPPROTO_TCP,
+            "udp" => IPPROTO_UDP,
+            "icmp" => IPPROTO_ICMP,
+            other => return Err(format!("Protocole de suivi inconnu : '{}' (tcp, udp ou icmp)", other)),
+        };
+        let state = state.trim().to_lowercase();
+        if protocol == IPPROTO_ICMP {
+            return if port == 0 && (state.is_empty() || state == "*") {
+                Ok(TimeoutTarget::Icmp)
+            } else {
+                Err("L'écho ICMP n'a ni état ni port configurable".to_string())
+            };
+        }
+        if port != 0 {
+            let port = u16::try_from(port).map_err(|_| format!("Port invalide : {}", port))?;
+            if !state.is_empty() && state != "*" {
+                return Err("Une surcharge par port ne porte pas d'état".to_string());
+            }
+            return Ok(TimeoutTarget::Port { protocol, port });
+        }
+        match protocol {
+            IPPROTO_TCP => TCP_STATE_NAMES.iter()
+                .find(|(_, name)| *name == state)
+                .map(|(s, _)| TimeoutTarget::Tcp(*s as u8))
+                .ok_or_else(|| format!(
+                    "État TCP inconnu : '{}' ({})",
+                    state, TCP_STATE_NAMES.map(|(_, n)| n).join(", ")
+                )),
+            _ if state.is_empty() || state == "*" => Ok(TimeoutTarget::Udp),
+            _ => Err("UDP n'a pas d'état configurable".to_string()),
+        }
+    }
+
+    // (protocole, état, port) tels qu'exposés par gRPC et stockés en base
+    pub fn names(&self) -> (&'static str, &'static str, u16) {
+        match self {
+            TimeoutTarget::Tcp(state) => {
+                let name = TCP_STATE_NAMES.iter().find(|(s, _)| *s as u8 == *state).map_or("?", |(_, n)| n);
+                ("tcp", name, 0)
+            }
+            TimeoutTarget::Udp => ("udp", "", 0),
+            TimeoutTarget::Icmp => ("icmp", "", 0),
+            TimeoutTarget::Port { protocol, port } => {
+                (if *protocol == IPPROTO_TCP { "tcp" } else { "udp" }, "", *port)
+            }
+        }
+    }
+}
+
+// "tcp", "udp", "icmp" (écho ICMP) ou "icmpv6" (écho ICMPv6)
+pub fn parse_protocol(name: &str) -> Result<u8, String> {
+    match name.trim().to_lowercase().as_str() {
+        "tcp" => Ok(IPPROTO_TCP),
+        "udp" => Ok(IPPROTO_UDP),
+        "icmp" => Ok(IPPROTO_ICMP),
+        "icmpv6" => Ok(IPPROTO_ICMPV6),
+        other => Err(format!("Protocole de suivi inconnu : '{}' (tcp, udp, icmp ou icmpv6)", other)),
+    }
+}
+
+// État TCP (voir TCP_STATE_NAMES), "new" ou "established" pour UDP et l'écho ICMP
+pub fn state_name(value: &ConnectionValue) -> &'static str {
+    match value.protocol {
+        IPPROTO_TCP => TCP_STATE_NAMES.iter().find(|(s, _)| *s as u8 == value.state).map_or("?", |(_, n)| n),
+        _ if value.state == UdpState::Established as u8 => "established",
+        _ => "new",
+    }
+}
+
+pub fn parse_state(name: &str) -> Result<&'static str, String> {
+    let name = name.trim().to_lowercase();
+    TCP_STATE_NAMES.iter().map(|(_, n)| *n)
+        .chain(["new"])
+        .find(|n| *n == name)
+        .ok_or_else(|| format!(
+            "État de suivi inconnu : '{}' ({}, new)",
+            name, TCP_STATE_NAMES.map(|(_, n)| n).join(", ")
+        ))
+}
+
+// Sélection d'entrées de suivi ; un critère absent laisse tout passer
+#[derive(Debug, Default)]
+pub struct ConnFilter {
+    pub prefix: Option<IpPrefix>, // Source ou destination
+    pub port: Option<u16>,        // Port source ou destination
+    pub protocol: Option<u8>,
+    pub state: Option<&'static str>,
+    pub vlan_id: Option<u16>,
+}
+
+impl ConnFilter {
+    pub fn matches(&self, flow: &Flow, value: &ConnectionValue) -> bool {
+        if let Some(prefix) = &self.prefix {
+            if !prefix.contains_addr(flow.source) && !prefix.contains_addr(flow.dest) {
+                return false;
+            }
+        }
+        if let Some(port) = self.port {
+            if flow.source_port != port && flow.dest_port != port {
+                return false;
+            }
+        }
+        (self.protocol.is_none() || self.protocol == Some(flow.protocol))
+            && (self.state.is_none() || self.state == Some(state_name(value)))
+            && (self.vlan_id.is_none() || self.vlan_id == Some(flow.vlan_id))
+    }
+}
+
+// Entrée de suivi telle que listée
+#[derive(Debug, Clone)]
+pub struct ConnEntry {
+    pub flow: Flow,
+    pub state: &'static str,
+    pub age_ns: u64,        // Depuis l'ouverture du flux
+    pub idle_ns: u64,       // Depuis le dernier paquet
+    pub expires_in_ns: u64, // Avant retrait par la tâche de nettoyage
+}
+
+// Entrées correspondant au filtre ; le parcours s'arrête à la première erreur de lecture
+pub fn list<K: FlowKey>(
+    table: &AyaHashMap<MapData, K, ConnectionValue>,
+    filter: &ConnFilter,
+    timeouts: &CttTimeouts,
+    now_ns: u64,
+) -> Vec<ConnEntry> {
+    let mut entries = Vec::new();
+    for item in table.iter() {
+        match item {
+            Ok((key, value)) => {
+                let flow = key.flow();
+                if !filter.matches(&flow, &value) {
+                    continue;
+                }
+                let idle_ns = now_ns.saturating_sub(value.last_seen_ns);
+                entries.push(ConnEntry {
+                    flow,
+                    state: state_name(&value),
+                    age_ns: now_ns.saturating_sub(value.first_seen_ns),
+                    idle_ns,
+                    expires_in_ns: timeouts.for_entry(&value, key.dst_port()).saturating_sub(idle_ns),
+                });
+            }
+            Err(e) => {
+                warn!("🔎 Parcours de la table de suivi interrompu : {}", e);
+                break;
+            }
+        }
+    }
+    entries
+}
+
+// Retire l'entrée du flux, qu'il soit donné dans le sens de l'initiateur ou dans le sens retour.
+// Renvoie le flux retiré (sens de l'initiateur), None si aucune entrée ne correspond.
+pub fn delete<K: FlowKey>(table: &mut AyaHashMap<MapData, K, ConnectionValue>, flow: &Flow) -> Result<Option<Flow>, MapError> {
+    for candidate in [*flow, flow.reversed()] {
+        let Some(key) = K::from_flow(&candidate) else { return Ok(None) };
+        if table.get(&key, 0).is_ok() {
+            table.remove(&key)?;
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+// Retire les entrées retenues par `predicate` ; renvoie le nombre d'entrées supprimées.
+// Comme pour `expire`, les clés sont collectées avant suppression.
+pub fn remove_matching<K: FlowKey>(
+    table: &mut AyaHashMap<MapData, K, ConnectionValue>,
+    predicate: impl Fn(&Flow, &ConnectionValue) -> bool,
+) -> usize {
+    let mut matching = Vec::new();
+    for item in table.iter() {
+        match item {
+            Ok((key, value)) => {
+                if predicate(&key.flow(), &value) {
+                    matching.push(key);
+                }
+            }
+            Err(e) => {
+                warn!("🔎 Parcours de la table de suivi interrompu : {}", e);
+                break;
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for key in matching {
+        // Déjà retirée entre-temps (RST, nettoyage) : rien à faire
+        if table.get(&key, 0).is_err() {
+            continue;
+        }
+        match table.remove(&key) {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("🔎 Erreur lors de la suppression d'une entrée CTT : {}", e),
+        }
+    }
+    removed
+}
+
+// Types ICMP (requête, réponse) d'un écho suivi ; sans objet pour TCP/UDP
+fn echo_types(protocol: u8) -> (u8, u8) {
+    match protocol {
+        IPPROTO_ICMPV6 => (ICMPV6_ECHO_REQUEST, ICMPV6_ECHO_REPLY),
+        _ => (ICMP_ECHO_REQUEST, ICMP_ECHO_REPLY),
+    }
+}
+
+// Purge après le retrait d'une règle ALLOW : le paquet d'ouverture du flux correspondait à la
+// règle et le jeu restant (`blocklists`, règle déjà retirée) ne l'admet plus.
+pub fn revoked_by_removal(blocklists: &Blocklists, removed: &Rule, flow: &Flow) -> bool {
+    let (request, _) = echo_types(flow.protocol);
+    removed.action == ACTION_ALLOW
+        && removed.matches_flow(flow, request)
+        && !matches!(blocklists.decide(flow, request), Some(rule) if rule.action == ACTION_ALLOW)
+}
+
+// Purge après l'ajout d'une règle DENY : la règle décide désormais les paquets entrants du flux.
+// Le sens de l'ouverture n'étant pas mémorisé, les deux sens sont examinés : flux ouvert depuis
+// l'extérieur, ou par l'hôte (les réponses entrent alors dans le sens retour).
+pub fn cut_by_deny(blocklists: &Blocklists, deny: &Rule, flow: &Flow) -> bool {
+    let (request, reply) = echo_types(flow.protocol);
+    let decided_by_deny = |flow: &Flow, icmp_type: u8| {
+        matches!(blocklists.decide(flow, icmp_type), Some(rule) if rule.id == deny.id)
+    };
+    deny.action == ACTION_DENY && (decided_by_deny(flow, request) || decided_by_deny(&flow.reversed(), reply))
+}
+
+// Durées d'inactivité maximales (en nanosecondes), modifiables au runtime
+#[derive(Debug, Clone)]
+pub struct CttTimeouts {
+    tcp_ns: BTreeMap<u8, u64>, // Par état TcpState
+    udp_ns: u64,
+    icmp_ns: u64,
+    port_overrides: BTreeMap<(u8, u16), u64>,
+}
+
+impl Default for CttTimeouts {
+    fn default() -> Self {
+        Self {
+            tcp_ns: BTreeMap::from([
+                (TcpState::SynSent as u8, 60 * NS_PER_S), // 1 minute (ouverture)
+                (TcpState::SynReceived as u8, 60 * NS_PER_S),
+                (TcpState::Established as u8, 300 * NS_PER_S), // 5 minutes
+                (TcpState::FinWait1 as u8, 60 * NS_PER_S), // 1 minute (fermeture en cours)
+                (TcpState::FinWait2 as u8, 60 * NS_PER_S),
+                (TcpState::CloseWait as u8, 60 * NS_PER_S),
+                (TcpState::Closing as u8, 30 * NS_PER_S), // 30 secondes (dernier ACK attendu)
+                (TcpState::LastAck as u8, 30 * NS_PER_S),
+                (TcpState::TimeWait as u8, 30 * NS_PER_S), // Connexion fermée : libérée vite
+            ]),
+            udp_ns: 30 * NS_PER_S, // 30 secondes
+            icmp_ns: 30 * NS_PER_S,
+            port_overrides: BTreeMap::new(),
+        }
+    }
+}
+
+impl CttTimeouts {
+    // Timeout applicable à une entrée ; un état TCP inconnu prend le plus court
+    pub fn for_entry(&self, value: &ConnectionValue, dst_port: u16) -> u64 {
+        let port_override = self.port_overrides.get(&(value.protocol, dst_port)).copied();
+        match value.protocol {
+            IPPROTO_TCP if value.state == TcpState::Established as u8 => {
+                port_override.unwrap_or(self.tcp_ns[&(TcpState::Established as u8)])
+            }
+            IPPROTO_TCP => self.tcp_ns.get(&value.state).copied()
+                .unwrap_or_else(|| self.tcp_ns.values().copied().min().unwrap_or(0)),
+            IPPROTO_ICMP | IPPROTO_ICMPV6 => self.icmp_ns,
+            _ => port_override.unwrap_or(self.udp_ns),
+        }
+    }
+
+    fn is_expired(&self, value: &ConnectionValue, dst_port: u16, now_ns: u64) -> bool {
+        now_ns.saturating_sub(value.last_seen_ns) > self.for_entry(value, dst_port)
+    }
+
+    // `None` retire une surcharge par port ; les timeouts globaux ne peuvent pas être retirés
+    pub fn set(&mut self, target: TimeoutTarget, timeout_ns: Option<u64>) -> Result<(), String> {
+        match (target, timeout_ns) {
+            (TimeoutTarget::Tcp(state), Some(ns)) => { self.tcp_ns.insert(state, ns); }
+            (TimeoutTarget::Udp, Some(ns)) => self.udp_ns = ns,
+            (TimeoutTarget::Icmp, Some(ns)) => self.icmp_ns = ns,
+            (TimeoutTarget::Port { protocol, port }, Some(ns)) => { self.port_overrides.insert((protocol, port), ns); }
+            (TimeoutTarget::Port { protocol, port }, None) => { self.port_overrides.remove(&(protocol, port)); }
+            (_, None) => return Err("Un timeout global doit être strictement positif".to_string()),
+        }
+        Ok(())
+    }
+
+    // Tous les réglages en vigueur, globaux puis surcharges par port
+    pub fn entries(&self) -> Vec<(TimeoutTarget, u64)> {
+        self.tcp_ns.iter().map(|(state, ns)| (TimeoutTarget::Tcp(*state), *ns))
+            .chain([(TimeoutTarget::Udp, self.udp_ns), (TimeoutTarget::Icmp, self.icmp_ns)])
+            .chain(self.port_overrides.iter().map(|(&(protocol, port), ns)| (TimeoutTarget::Port { protocol, port }, *ns)))
+            .collect()
+    }
+}
+
+// Horloge de bpf_ktime_get_ns() vue depuis l'espace utilisateur
+pub fn kernel_monotonic_ns() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `ts` est un timespec valide et CLOCK_MONOTONIC existe toujours sous Linux.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+// Retire les entrées expirées d'une table ; renvoie (entrées inspectées, entrées supprimées).
+// Les clés sont collectées avant suppression pour ne pas perturber le parcours de la map,
+// et chaque entrée est relue juste avant d'être retirée : un paquet a pu la rafraîchir.
+pub fn expire<K: FlowKey>(
+    table: &mut AyaHashMap<MapData, K, ConnectionValue>,
+    timeouts: &CttTimeouts,
+    now_ns: u64,
+) -> (usize, usize) {
+    let mut inspected = 0;
+    let mut expired = Vec::new();
+    for item in table.iter() {
+        match item {
+            Ok((key, value)) => {
+                inspected += 1;
+                if timeouts.is_expired(&value, key.dst_port(), now_ns) {
+                    expired.push(key);
+                }
+            }
+            Err(e) => {
+                warn!("🧹 Parcours de la table de suivi interrompu : {}", e);
+                break;
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for key in expired {
+        let still_expired = matches!(table.get(&key, 0), Ok(value) if timeouts.is_expired(&value, key.dst_port(), now_ns));
+        if still_expired {
+            match table.remove(&key) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("🧹 Erreur lors de la suppression d'une entrée CTT : {}", e),
+            }
+        }
+    }
+    (inspected, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xdp_drop_common::TcpWindow;
+
+    fn entry(protocol: u8, state: u8) -> ConnectionValue {
+        ConnectionValue {
+            last_seen_ns: 0,
+            first_seen_ns: 0,
+            state,
+            protocol,
+            _pad: [0; 6],
+            tcp: [TcpWindow::UNSEEN; 2],
+        }
+    }
+
+    #[test]
+    fn timeout_target_parse() {
+        assert_eq!(TimeoutTarget::parse("TCP", " Established ", 0), Ok(TimeoutTarget::Tcp(TcpState::Established as u8)));
+        assert_eq!(TimeoutTarget::parse("tcp", "time_wait", 0), Ok(TimeoutTarget::Tcp(TcpState::TimeWait as u8)));
+        assert_eq!(TimeoutTarget::parse("udp", "", 0), Ok(TimeoutTarget::Udp));
+        assert_eq!(TimeoutTarget::parse("udp", "*", 0), Ok(TimeoutTarget::Udp));
+        assert_eq!(TimeoutTarget::parse("icmp", "", 0), Ok(TimeoutTarget::Icmp));
+        assert_eq!(TimeoutTarget::parse("tcp", "", 443), Ok(TimeoutTarget::Port { protocol: IPPROTO_TCP, port: 443 }));
+        assert_eq!(TimeoutTarget::parse("udp", "*", 53), Ok(TimeoutTarget::Port { protocol: IPPROTO_UDP, port: 53 }));
+    }
+
+    #[test]
+    fn timeout_target_rejects_invalid_combinations() {
+        for (protocol, state, port) in [
+            ("sctp", "", 0),
+            ("icmpv6", "", 0),
+            ("tcp", "", 0),
+            ("tcp", "listen", 0),
+            ("udp", "established", 0),
+            ("icmp", "", 7),
+            ("icmp", "established", 0),
+            ("tcp", "established", 443),
+            ("udp", "", 65536),
+        ] {
+            assert!(TimeoutTarget::parse(protocol, state, port).is_err(), "{} {} {} accepté", protocol, state, port);
+        }
+    }
+
+    #[test]
+    fn timeout_target_names_round_trip() {
+        for target in CttTimeouts::default().entries().into_iter().map(|(target, _)| target)
+            .chain([TimeoutTarget::Port { protocol: IPPROTO_TCP, port: 22 }, TimeoutTarget::Port { protocol: IPPROTO_UDP, port: 53 }])
+        {
+            let (protocol, state, port) = target.names();
+            assert_eq!(TimeoutTarget::parse(protocol, state, port as u32), Ok(target));
+        }
+    }
+
+    #[test]
+    fn for_entry_applies_port_overrides() {
+        let mut timeouts = CttTimeouts::default();
+        timeouts.set(TimeoutTarget::Port { protocol: IPPROTO_TCP, port: 22 }, Some(3600 * NS_PER_S)).unwrap();
+        timeouts.set(TimeoutTarget::Port { protocol: IPPROTO_UDP, port: 53 }, Some(5 * NS_PER_S)).unwrap();
+
+        let established = entry(IPPROTO_TCP, TcpState::Established as u8);
+        assert_eq!(timeouts.for_entry(&established, 22), 3600 * NS_PER_S);
+        assert_eq!(timeouts.for_entry(&established, 80), 300 * NS_PER_S);
+        // Les états d'ouverture et de fermeture ignorent la surcharge
+        assert_eq!(timeouts.for_entry(&entry(IPPROTO_TCP, TcpState::SynSent as u8), 22), 60 * NS_PER_S);
+        assert_eq!(timeouts.for_entry(&entry(IPPROTO_TCP, TcpState::TimeWait as u8), 22), 30 * NS_PER_S);
+        // Surcharge propre au protocole
+        assert_eq!(timeouts.for_entry(&entry(IPPROTO_UDP, UdpState::Established as u8), 53), 5 * NS_PER_S);
+        assert_eq!(timeouts.for_entry(&entry(IPPROTO_UDP, UdpState::New as u8), 22), 30 * NS_PER_S);
+        assert_eq!(timeouts.for_entry(&established, 53), 300 * NS_PER_S);
+        // L'écho ICMP n'a pas de port
+        assert_eq!(timeouts.for_entry(&entry(IPPROTO_ICMP, UdpState::New as u8), 53), 30 * NS_PER_S);
+        assert_eq!(timeouts.for_entry(&entry(IPPROTO_ICMPV6, UdpState::New as u8), 53), 30 * NS_PER_S);
+        // État TCP inconnu : le plus court
+        assert_eq!(timeouts.for_entry(&entry(IPPROTO_TCP, 0), 80), 30 * NS_PER_S);
+    }
+
+    #[test]
+    fn zero_timeout_removes_port_override_only() {
+        let mut timeouts = CttTimeouts::default();
+        let target = TimeoutTarget::Port { protocol: IPPROTO_UDP, port: 53 };
+        timeouts.set(target, Some(5 * NS_PER_S)).unwrap();
+        timeouts.set(target, None).unwrap();
+        assert_eq!(timeouts.for_entry(&entry(IPPROTO_UDP, UdpState::Established as u8), 53), 30 * NS_PER_S);
+        assert!(!timeouts.entries().iter().any(|(t, _)| *t == target));
+
+        assert!(timeouts.set(TimeoutTarget::Udp, None).is_err());
+        assert!(timeouts.set(TimeoutTarget::Tcp(TcpState::Established as u8), None).is_err());
+        timeouts.set(TimeoutTarget::Udp, Some(10 * NS_PER_S)).unwrap();
+        assert_eq!(timeouts.for_entry(&entry(IPPROTO_UDP, UdpState::New as u8), 53), 10 * NS_PER_S);
+    }
+
+    #[test]
+    fn state_names_round_trip() {
+        for (state, name) in TCP_STATE_NAMES {
+            assert_eq!(state_name(&entry(IPPROTO_TCP, state as u8)), name);
+            assert_eq!(parse_state(name), Ok(name));
+        }
+        assert_eq!(state_name(&entry(IPPROTO_TCP, 0)), "?");
+        assert_eq!(state_name(&entry(IPPROTO_UDP, UdpState::New as u8)), "new");
+        assert_eq!(state_name(&entry(IPPROTO_UDP, UdpState::Established as u8)), "established");
+        assert_eq!(state_name(&entry(IPPROTO_ICMP, UdpState::Established as u8)), "established");
+        assert_eq!(parse_state(" TIME_WAIT "), Ok("time_wait"));
+        assert_eq!(parse_state("new"), Ok("new"));
+        assert!(parse_state("listen").is_err());
+        assert!(parse_state("").is_err());
+    }
+}
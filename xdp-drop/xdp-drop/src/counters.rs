@@ -0,0 +1,92 @@
+// Compteurs de hits par règle.
+//
+// Le programme XDP incrémente RULE_STATS (map per-CPU, clé = ID de règle) pour chaque paquet
+// décidé par une règle. Les compteurs noyau sont cumulatifs : le daemon garde les totaux déjà
+// reportés en base et n'écrit que la différence dans `usage_count` / `byte_count` / `last_hit`.
+//
+// Les compteurs globaux (fragments, datapath) sont des PerCpuArray<u64> indexées par constante :
+// ils ne sont pas persistés, leurs totaux repartent de zéro au chargement du programme.
+
+use aya::maps::{MapData, MapError, PerCpuArray, PerCpuHashMap};
+use std::collections::HashMap;
+use xdp_drop_common::{RuleStats, DATAPATH_STAT_COUNT, FRAG_STAT_COUNT};
+
+pub struct RuleCounters {
+    map: PerCpuHashMap<MapData, u32, RuleStats>,
+    // Totaux déjà reportés en base, par ID de règle
+    flushed: HashMap<u32, RuleStats>,
+}
+
+impl RuleCounters {
+    pub fn new(map: PerCpuHashMap<MapData, u32, RuleStats>) -> Self {
+        Self { map, flushed: HashMap::new() }
+    }
+
+    // Compteurs cumulés depuis le chargement du programme (somme des CPUs)
+    pub fn totals(&self) -> Result<HashMap<u32, RuleStats>, MapError> {
+        let mut totals = HashMap::new();
+        for item in self.map.iter() {
+            let (rule_id, per_cpu) = item?;
+            let total = per_cpu.iter().fold(RuleStats::default(), |acc, v| RuleStats {
+                packets: acc.packets + v.packets,
+                bytes: acc.bytes + v.bytes,
+            });
+            totals.insert(rule_id, total);
+        }
+        Ok(totals)
+    }
+
+    // Hits pas encore reportés en base, par ID de règle (règles touchées uniquement)
+    pub fn pending(&self) -> Result<HashMap<u32, RuleStats>, MapError> {
+        Ok(self.totals()?
+            .into_iter()
+            .filter_map(|(rule_id, total)| {
+                let flushed = self.flushed.get(&rule_id).copied().unwrap_or_default();
+                let delta = RuleStats {
+                    packets: total.packets.saturating_sub(flushed.packets),
+                    bytes: total.bytes.saturating_sub(flushed.bytes),
+                };
+                (delta.packets > 0).then_some((rule_id, delta))
+            })
+            .collect())
+    }
+
+    // À appeler une fois `delta` écrit en base
+    pub fn mark_flushed(&mut self, rule_id: u32, delta: RuleStats) {
+        let flushed = self.flushed.entry(rule_id).or_default();
+        flushed.packets += delta.packets;
+        flushed.bytes += delta.bytes;
+    }
+
+    // Règle supprimée : ses compteurs ne seront plus reportés
+    pub fn forget(&mut self, rule_id: u32) {
+        // Absente de la map si la règle n'a jamais été touchée
+        let _ = self.map.remove(&rule_id);
+        self.flushed.remove(&rule_id);
+    }
+}
+
+// Compteurs de fragments IPv4 / IPv6 (FRAGMENT_STATS, indexée par FRAG_STAT_*)
+pub type FragmentCounters = ArrayCounters<{ FRAG_STAT_COUNT as usize }>;
+// Compteurs du datapath (DATAPATH_STATS, indexée par DATAPATH_STAT_*)
+pub type DatapathCounters = ArrayCounters<{ DATAPATH_STAT_COUNT as usize }>;
+
+// N compteurs d'une PerCpuArray<u64>
+pub struct ArrayCounters<const N: usize> {
+    map: PerCpuArray<MapData, u64>,
+}
+
+impl<const N: usize> ArrayCounters<N> {
+    pub fn new(map: PerCpuArray<MapData, u64>) -> Self {
+        Self { map }
+    }
+
+    // Totaux depuis le chargement du programme (somme des CPUs), indexés par constante
+    pub fn totals(&self) -> Result<[u64; N], MapError> {
+        let mut totals = [0; N];
+        for (stat, total) in totals.iter_mut().enumerate() {
+            *total = self.map.get(&(stat as u32), 0)?.iter().sum();
+        }
+        Ok(totals)
+    }
+}
@@ -0,0 +1,292 @@
+// Événements paquet (ring buffer EVENTS).
+//
+// Le programme XDP publie un PacketLog à chaque décision (règle appliquée, politique par
+// défaut, flux suivi, fragment rejeté) retenue par la verbosité de la map LOG_CONFIG, avec une
+// limite de débit par CPU. La verbosité se règle au runtime via gRPC (non persistée : aucun
+// événement au démarrage). La tâche `run_event_consumer` les décode en `PacketEvent` et les
+// diffuse sur un canal broadcast : chaque partie du daemon intéressée s'abonne via
+// `EventBus::subscribe` (journal, clients WatchEvents avec leur `EventFilter`).
+
+use anyhow::Context;
+use aya::maps::{Array, MapData, MapError, RingBuf};
+use log::{info, warn};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::broadcast;
+use crate::rules::{ipv6_from_be_words, IpPrefix, PROTO_ICMP, PROTO_ICMPV6, PROTO_TCP, PROTO_UDP};
+use xdp_drop_common::{
+    PacketLog, EVENT_FAMILY_IPV4, EVENT_FAMILY_IPV6, EVENT_REASON_CONNTRACK, EVENT_REASON_DEFAULT_POLICY,
+    EVENT_REASON_FRAGMENT, EVENT_REASON_INVALID, EVENT_REASON_RULE, EVENT_VERDICT_DROP, EVENT_VERDICT_PASS,
+    LogConfig, LOG_LEVEL_ALL, LOG_LEVEL_DROPS, LOG_LEVEL_OFF, LOG_LEVEL_SAMPLED,
+};
+
+// Événements en attente par abonné : au-delà, un abonné trop lent perd les plus anciens
+const EVENT_CHANNEL_CAPACITY: usize = 4096;
+
+const LOG_LEVEL_NAMES: [(u32, &str); 4] = [
+    (LOG_LEVEL_OFF, "off"),
+    (LOG_LEVEL_DROPS, "drops"),
+    (LOG_LEVEL_SAMPLED, "sampled"),
+    (LOG_LEVEL_ALL, "all"),
+];
+
+pub fn parse_level(name: &str) -> Result<u32, String> {
+    let name = name.trim().to_lowercase();
+    LOG_LEVEL_NAMES.iter()
+        .find(|(_, n)| *n == name)
+        .map(|(level, _)| *level)
+        .ok_or_else(|| format!("Verbosité inconnue : '{}' (off, drops, sampled ou all)", name))
+}
+
+pub fn level_name(level: u32) -> &'static str {
+    LOG_LEVEL_NAMES.iter().find(|(l, _)| *l == level).map_or("?", |(_, n)| n)
+}
+
+// Verbosité en vigueur, tenue à jour avec la map noyau
+pub struct EventVerbosity {
+    map: Array<MapData, LogConfig>,
+    config: LogConfig,
+}
+
+impl EventVerbosity {
+    // Remet la map à "off" : un daemon relancé ne reprend pas la verbosité précédente
+    pub fn new(map: Array<MapData, LogConfig>) -> Result<Self, MapError> {
+        let mut verbosity = Self { map, config: LogConfig::default() };
+        verbosity.set(LOG_LEVEL_OFF, 0)?;
+        Ok(verbosity)
+    }
+
+    // sample_rate n'a de sens que pour LOG_LEVEL_SAMPLED (0 ou 1 : tous les événements)
+    pub fn set(&mut self, level: u32, sample_rate: u32) -> Result<(), MapError> {
+        let sample_rate = if level == LOG_LEVEL_SAMPLED { sample_rate } else { 0 };
+        let config = LogConfig { level, sample_rate };
+        self.map.set(0, config, 0)?;
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn level(&self) -> u32 {
+        self.config.level
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.config.sample_rate
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Rule,
+    Conntrack,
+    DefaultPolicy,
+    Fragment,
+    Invalid,
+}
+
+#[derive(Debug, Clone)]
+pub struct PacketEvent {
+    pub timestamp_ns: u64, // Horloge monotone noyau (voir conntrack::kernel_monotonic_ns)
+    pub source: IpAddr,
+    pub dest: IpAddr,
+    pub protocol: u8,
+    pub source_port: u16, // ICMP / ICMPv6 : type
+    pub dest_port: u16,   // ICMP / ICMPv6 : code
+    pub vlan_id: Option<u16>,
+    pub verdict: Verdict,
+    pub rule_id: Option<u32>,
+    pub reason: Reason,
+}
+
+impl PacketEvent {
+    // None pour un événement illisible (programme XDP d'une autre version)
+    pub fn decode(raw: &PacketLog) -> Option<Self> {
+        let (source, dest) = match raw.family {
+            EVENT_FAMILY_IPV4 => (
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(raw.src_ip[0]))),
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(raw.dst_ip[0]))),
+            ),
+            EVENT_FAMILY_IPV6 => (IpAddr::V6(ipv6_from_be_words(raw.src_ip)), IpAddr::V6(ipv6_from_be_words(raw.dst_ip))),
+            _ => return None,
+        };
+        let verdict = match raw.verdict {
+            EVENT_VERDICT_PASS => Verdict::Pass,
+            EVENT_VERDICT_DROP => Verdict::Drop,
+            _ => return None,
+        };
+        let reason = match raw.reason {
+            EVENT_REASON_RULE => Reason::Rule,
+            EVENT_REASON_CONNTRACK => Reason::Conntrack,
+            EVENT_REASON_DEFAULT_POLICY => Reason::DefaultPolicy,
+            EVENT_REASON_FRAGMENT => Reason::Fragment,
+            EVENT_REASON_INVALID => Reason::Invalid,
+            _ => return None,
+        };
+        Some(Self {
+            timestamp_ns: raw.timestamp_ns,
+            source,
+            dest,
+            protocol: raw.protocol,
+            source_port: raw.src_port,
+            dest_port: raw.dst_port,
+            vlan_id: (raw.vlan_id != 0).then_some(raw.vlan_id),
+            verdict,
+            rule_id: (raw.rule_id != 0).then_some(raw.rule_id),
+            reason,
+        })
+    }
+}
+
+impl Verdict {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "pass" => Ok(Verdict::Pass),
+            "drop" => Ok(Verdict::Drop),
+            other => Err(format!("Verdict inconnu : '{}' (drop ou pass)", other)),
+        }
+    }
+}
+
+// "TCP", "UDP", "ICMP", "ICMPV6", sinon le numéro de protocole
+pub fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        PROTO_TCP => "TCP".to_string(),
+        PROTO_UDP => "UDP".to_string(),
+        PROTO_ICMP => "ICMP".to_string(),
+        PROTO_ICMPV6 => "ICMPV6".to_string(),
+        other => other.to_string(),
+    }
+}
+
+// Filtre d'un abonné ; un critère absent laisse tout passer
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    pub prefix: Option<IpPrefix>, // Source ou destination
+    pub port: Option<u16>,        // Port TCP/UDP source ou destination (jamais ICMP)
+    pub verdict: Option<Verdict>,
+    pub rule_id: Option<u32>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &PacketEvent) -> bool {
+        if let Some(prefix) = &self.prefix {
+            if !prefix.contains_addr(event.source) && !prefix.contains_addr(event.dest) {
+                return false;
+            }
+        }
+        if let Some(port) = self.port {
+            let has_ports = event.protocol == PROTO_TCP || event.protocol == PROTO_UDP;
+            if !has_ports || (event.source_port != port && event.dest_port != port) {
+                return false;
+            }
+        }
+        (self.verdict.is_none() || self.verdict == Some(event.verdict))
+            && (self.rule_id.is_none() || event.rule_id == self.rule_id)
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Verdict::Pass => "pass",
+            Verdict::Drop => "drop",
+        })
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reason::Rule => "rule",
+            Reason::Conntrack => "conntrack",
+            Reason::DefaultPolicy => "default_policy",
+            Reason::Fragment => "fragment",
+            Reason::Invalid => "invalid",
+        })
+    }
+}
+
+impl fmt::Display for PacketEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let endpoint = |addr: &IpAddr, port: u16| match addr {
+            IpAddr::V4(a) => format!("{}:{}", a, port),
+            IpAddr::V6(a) => format!("[{}]:{}", a, port),
+        };
+        write!(f, "{} ({}) proto {} {} -> {}", self.verdict, self.reason, self.protocol,
+            endpoint(&self.source, self.source_port), endpoint(&self.dest, self.dest_port))?;
+        if let Some(vlan_id) = self.vlan_id {
+            write!(f, " vlan {}", vlan_id)?;
+        }
+        if let Some(rule_id) = self.rule_id {
+            write!(f, " rule #{}", rule_id)?;
+        }
+        Ok(())
+    }
+}
+
+// Canal de diffusion des événements décodés
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<PacketEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PacketEvent> {
+        self.tx.subscribe()
+    }
+
+    fn publish(&self, event: PacketEvent) {
+        // Aucun abonné : l'événement est simplement ignoré
+        let _ = self.tx.send(event);
+    }
+}
+
+// Lit le ring buffer EVENTS dès qu'il a des données et publie les événements décodés
+pub async fn run_event_consumer(ring: RingBuf<MapData>, bus: EventBus) -> anyhow::Result<()> {
+    let mut ring = AsyncFd::new(ring).context("EVENTS ring buffer registration error")?;
+    loop {
+        let mut guard = ring.readable_mut().await.context("EVENTS ring buffer poll error")?;
+        let events = guard.get_inner_mut();
+        while let Some(item) = events.next() {
+            if item.len() < std::mem::size_of::<PacketLog>() {
+                warn!("📭 Événement EVENTS tronqué ({} octets), ignoré", item.len());
+                continue;
+            }
+            // SAFETY : taille vérifiée, PacketLog est Pod ; le ring buffer n'aligne que sur 8 octets
+            let raw: PacketLog = unsafe { std::ptr::read_unaligned(item.as_ptr() as *const PacketLog) };
+            match PacketEvent::decode(&raw) {
+                Some(event) => bus.publish(event),
+                None => warn!("📭 Événement EVENTS illisible (famille {}, verdict {}, raison {})", raw.family, raw.verdict, raw.reason),
+            }
+        }
+        guard.clear_ready();
+    }
+}
+
+// Abonné journal : chaque événement reçu est écrit dans logs/firewall.log
+pub async fn run_event_log_task(mut events: broadcast::Receiver<PacketEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => info!("📦 {}", event),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => warn!("📦 {} événements non journalisés (retard)", skipped),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
@@ -2,22 +2,42 @@ use anyhow::Context;
 use aya::{
     Bpf,
     include_bytes_aligned,
-    maps::{HashMap as AyaHashMap, MapData}, // Renommer pour éviter conflit avec std::collections::HashMap
-    programs::{Xdp, XdpFlags},
+    maps::{Array, HashMap as AyaHashMap, LpmTrie, MapData, PerCpuArray, PerCpuHashMap, RingBuf}, // Renommer pour éviter conflit avec std::collections::HashMap
+    programs::{tc, SchedClassifier, TcAttachType, Xdp, XdpFlags},
 };
 use aya_log::EbpfLogger;
 use clap::{Parser, CommandFactory};
 use flexi_logger::{Duplicate, FileSpec, Logger};
 use log::{info, warn, error}; // error
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Duration; // Pour le cleanup
+use std::time::{Duration, SystemTime, UNIX_EPOCH}; // Pour le cleanup
 use tokio::signal;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
 use tokio::time::interval; // Pour le cleanup
 use tonic::{transport::Server, Request, Response, Status};
 
 // Importer les nouvelles structures
-use xdp_drop_common::{IpPort, ConnectionKey, ConnectionValue, TcpState, UdpState, ConnStateVariant};
-
+use xdp_drop_common::{ConnectionKey, ConnectionKeyV6, ConnectionValue, RuleStats,
+    FRAG_STAT_FIRST, FRAG_STAT_FOLLOWED, FRAG_STAT_ORPHAN, FRAG_STAT_OVERLAP, FRAG_STAT_TINY,
+    DATAPATH_STAT_ABORTED, DATAPATH_STAT_CONNTRACK_INSERT_FAILED, DATAPATH_STAT_DROPPED_CONNTRACK, DATAPATH_STAT_DROPPED_FRAGMENT,
+    DATAPATH_STAT_DROPPED_INVALID, DATAPATH_STAT_DROPPED_POLICY, DATAPATH_STAT_DROPPED_RULE, DATAPATH_STAT_EVENTS_LOST, DATAPATH_STAT_PASSED};
+
+
+mod conntrack;
+mod counters;
+mod events;
+mod metrics;
+mod policy;
+mod rules;
+use crate::conntrack::{kernel_monotonic_ns, ConnEntry, ConnFilter, CttTimeouts, Flow, TimeoutTarget};
+use crate::counters::{DatapathCounters, FragmentCounters, RuleCounters};
+use crate::events::{run_event_consumer, run_event_log_task, EventBus, EventFilter, EventVerbosity, PacketEvent, Verdict};
+use crate::metrics::{run_metrics_server, GrpcMetrics, GrpcMetricsLayer, MetricsSources};
+use crate::policy::DefaultPolicies;
+use crate::rules::{Blocklists, IpPrefix, PortRange, Rule, RuleBank, ACTION_ALLOW, ACTION_DENY, DEFAULT_PRIORITY};
 
 // ... (reste de vos imports et modules firewall, google)
 pub mod firewall {
@@ -30,7 +50,7 @@ pub mod google {
 }
 
 use crate::firewall::firewall_service_server::{FirewallService, FirewallServiceServer};
-use crate::firewall::{FirewallStatus, RuleInfo, RuleListResponse, CreateRuleRequest, CreateRuleResponse, RuleData, DeleteRuleRequest, DeleteRuleResponse, RuleDataDelete};
+use crate::firewall::{FirewallStatus, RuleInfo, RuleListResponse, CreateRuleRequest, CreateRuleResponse, RuleData, DeleteRuleRequest, DeleteRuleResponse, RuleDataDelete, RuleUpdate, UpdateRuleRequest, UpdateRuleResponse, ApplyRulesetRequest, ApplyRulesetResponse, RulesetChange, ConntrackTimeout, SetConntrackTimeoutResponse, DefaultPolicy, SetDefaultPolicyResponse, FragmentStats, LogLevel, SetLogLevelResponse, DatapathStats, WatchEventsRequest, EventInfo, ConnectionFilter, ListConnectionsRequest, ListConnectionsResponse, ConnectionInfo, ConnectionTuple, DeleteConnectionResponse, FlushConnectionsResponse};
 use crate::google::protobuf::Empty;
 
 
@@ -38,6 +58,9 @@ use crate::google::protobuf::Empty;
 struct Opt {
     #[clap(short = 'i', long = "int")]
     iface: String,
+    /// Adresse d'écoute de l'endpoint Prometheus /metrics (ex: 127.0.0.1:9100) ; désactivé si absent
+    #[clap(long = "metrics-addr")]
+    metrics_addr: Option<SocketAddr>,
 }
 
 fn validate_args(opt: &Opt) {
@@ -49,13 +72,35 @@ fn validate_args(opt: &Opt) {
     }
 }
 
+const DB_CONFIG: &str = "host=localhost user=postgres password=postgres dbname=firewall";
+
 //  RUST_LOG=info cargo run -- -i enp0s1
 pub struct MyFirewallService {
     db_client: Arc<tokio_postgres::Client>,
-    // On a besoin d'un accès à la map BLOCKLIST pour Create/Delete Rule
-    // Et potentiellement à CONN_TRACK_TABLE si on veut effacer des états lors de la suppression de règles
-    bpf_blocklist_map: Arc<tokio::sync::Mutex<AyaHashMap<MapData, IpPort, u32>>>,
-    // bpf_ctt_map: Arc<tokio::sync::Mutex<AyaHashMap<MapData, ConnectionKey, ConnectionValue>>>, // Si besoin
+    // Connexion réservée aux transactions d'ApplyRuleset (`transaction()` demande un accès exclusif)
+    ruleset_db: Arc<tokio::sync::Mutex<tokio_postgres::Client>>,
+    // On a besoin d'un accès aux tries BLOCKLIST pour Create/Delete Rule
+    // Et à CONN_TRACK_TABLE pour couper les flux qu'une règle retirée ou un refus ajouté n'admet plus
+    bpf_blocklist_map: Arc<tokio::sync::Mutex<Blocklists>>,
+    // Compteurs de hits noyau pas encore reportés en base (affichage en direct)
+    rule_counters: Arc<tokio::sync::Mutex<RuleCounters>>,
+    // Timeouts de suivi partagés avec la tâche de nettoyage CTT
+    ctt_timeouts: Arc<tokio::sync::Mutex<CttTimeouts>>,
+    // Politiques par défaut par classe de trafic (map DEFAULT_POLICY)
+    default_policies: Arc<tokio::sync::Mutex<DefaultPolicies>>,
+    // Compteurs de fragments IPv4 / IPv6 (map FRAGMENT_STATS)
+    fragment_counters: Arc<tokio::sync::Mutex<FragmentCounters>>,
+    // Compteurs de verdicts du programme XDP (map DATAPATH_STATS)
+    datapath_counters: Arc<tokio::sync::Mutex<DatapathCounters>>,
+    // Verbosité des événements paquet (map LOG_CONFIG)
+    event_verbosity: Arc<tokio::sync::Mutex<EventVerbosity>>,
+    // Événements paquet décodés, relayés aux clients WatchEvents
+    event_bus: EventBus,
+    // Tables de suivi (CONN_TRACK_TABLE, CONN_TRACK_TABLE_V6), partagées avec la tâche de nettoyage
+    ctt_map: Arc<tokio::sync::Mutex<AyaHashMap<MapData, ConnectionKey, ConnectionValue>>>,
+    ctt_v6_map: Arc<tokio::sync::Mutex<AyaHashMap<MapData, ConnectionKeyV6, ConnectionValue>>>,
+    // Suivi des flux sortants (tc_egress) attaché au démarrage
+    egress_tracking: bool,
 }
 
 // Fonction pour récupérer et formater les règles (existante, inchangée)
@@ -64,7 +109,8 @@ async fn fetch_and_format_rules_from_db(
 ) -> Result<Vec<RuleInfo>, anyhow::Error> {
     let rows = db_client
         .query(
-            "SELECT id, source_ip, dest_ip, source_port, dest_port, action, protocol, usage_count FROM rules",
+            "SELECT id, source_ip, dest_ip, source_port, source_port_end, dest_port, dest_port_end, action, protocol, priority, \
+             icmp_type, icmp_code, vlan_id, usage_count, byte_count, to_char(last_hit, 'YYYY-MM-DD HH24:MI:SS') AS last_hit FROM rules",
             &[],
         )
         .await
@@ -76,32 +122,469 @@ async fn fetch_and_format_rules_from_db(
         let source_ip_str: String = row.get("source_ip");
         let dest_ip_str: String = row.get("dest_ip");
         let source_port_opt: Option<i32> = row.get("source_port");
+        let source_port_end_opt: Option<i32> = row.get("source_port_end");
         let dest_port_opt: Option<i32> = row.get("dest_port");
+        let dest_port_end_opt: Option<i32> = row.get("dest_port_end");
         let action_str: String = row.get("action");
         let protocol_opt: Option<String> = row.get("protocol");
-        let usage_count_val: i32 = row.get("usage_count");
+        let priority: i32 = row.get("priority");
+        let icmp_type: Option<i32> = row.get("icmp_type");
+        let icmp_code: Option<i32> = row.get("icmp_code");
+        let vlan_id: Option<i32> = row.get("vlan_id");
+        let usage_count_val: i64 = row.get("usage_count");
+        let byte_count_val: i64 = row.get("byte_count");
+        let last_hit_opt: Option<String> = row.get("last_hit");
 
         rule_infos.push(RuleInfo {
             id,
             source_ip: source_ip_str,
             dest_ip: dest_ip_str,
-            source_port: source_port_opt.map_or("*".to_string(), |p| p.to_string()),
-            dest_port: dest_port_opt.map_or("*".to_string(), |p| p.to_string()),
+            source_port: port_column_display(source_port_opt, source_port_end_opt),
+            dest_port: port_column_display(dest_port_opt, dest_port_end_opt),
             action: action_str,
             protocol: protocol_opt.unwrap_or_else(|| "any".to_string()),
             usage_count: usage_count_val,
+            enforced: false,
+            priority,
+            byte_count: byte_count_val as u64,
+            last_hit: last_hit_opt.unwrap_or_default(),
+            icmp_type,
+            icmp_code,
+            vlan_id,
         });
     }
     Ok(rule_infos)
 }
 
+// Affichage brut des colonnes port / fin de plage ("*" si NULL)
+fn port_column_display(start: Option<i32>, end: Option<i32>) -> String {
+    match (start, end) {
+        (None, _) => "*".to_string(),
+        (Some(p), None) => p.to_string(),
+        (Some(a), Some(b)) => format!("{}-{}", a, b),
+    }
+}
+
+// Règle noyau à partir d'une ligne de la table rules
+fn rule_from_row(row: &tokio_postgres::Row) -> Result<Rule, String> {
+    let source_port = PortRange::from_db(row.get("source_port"), row.get("source_port_end"), "source")?; // NULL pour wildcard
+    let dest_port = PortRange::from_db(row.get("dest_port"), row.get("dest_port_end"), "destination")?;
+    let protocol: Option<String> = row.get("protocol"); // NULL = tout protocole
+    let mut rule = Rule::parse(
+        row.get("id"),
+        row.get("source_ip"),
+        row.get("dest_ip"),
+        source_port,
+        dest_port,
+        protocol.as_deref().unwrap_or("any"),
+        row.get("action"),
+    )?;
+    rule.priority = row.get("priority");
+    rule.set_icmp(row.get("icmp_type"), row.get("icmp_code"))?;
+    rule.set_vlan(row.get("vlan_id"))?;
+    Ok(rule)
+}
+
+// Règle validée à partir des champs gRPC (CreateRule, UpdateRule après fusion)
+fn rule_from_data(id: i32, data: &RuleData) -> Result<Rule, String> {
+    // Validations (simples)
+    if data.source_ip.is_empty() || data.dest_ip.is_empty() {
+        return Err("IPs source/dest requises (\"*\" pour toute adresse).".to_string());
+    }
+    let action_str = data.action.to_lowercase();
+    if action_str != "allow" && action_str != "deny" {
+        return Err("Action doit être 'allow' ou 'deny'.".to_string());
+    }
+    let priority = data.priority.unwrap_or(DEFAULT_PRIORITY);
+    if priority < 0 {
+        return Err("La priorité doit être positive ou nulle.".to_string());
+    }
+    // "*" / "any" => wildcard, "80" ou "49152-65535" ; un port illisible est refusé plutôt qu'élargi
+    let source_port = PortRange::parse(&data.source_port)?;
+    let dest_port = PortRange::parse(&data.dest_port)?;
+
+    let mut rule = Rule::parse(id, &data.source_ip, &data.dest_ip, source_port, dest_port, &data.protocol, &action_str)?;
+    rule.priority = priority;
+    rule.set_icmp(data.icmp_type, data.icmp_code)?;
+    rule.set_vlan(data.vlan_id)?;
+    Ok(rule)
+}
+
+// Champs gRPC d'une règle validée (inverse de `rule_from_data`)
+fn rule_to_data(rule: &Rule) -> RuleData {
+    RuleData {
+        source_ip: rule.source.to_string(),
+        dest_ip: rule.dest.to_string(),
+        source_port: rule.source_port.to_string(),
+        dest_port: rule.dest_port.to_string(),
+        action: rule.action_name().to_string(),
+        protocol: rule.protocol_name().to_string(),
+        priority: Some(rule.priority),
+        icmp_type: rule.icmp_type.map(i32::from),
+        icmp_code: rule.icmp_code.map(i32::from),
+        vlan_id: rule.vlan_id.map(i32::from),
+    }
+}
+
+// Champs de la règle existante, remplacés par ceux fournis dans la mise à jour
+fn merge_rule_update(current: &Rule, changes: RuleUpdate) -> RuleData {
+    let current = rule_to_data(current);
+    let (icmp_type, icmp_code) = match changes.any_icmp {
+        true => (None, None),
+        false => (changes.icmp_type.or(current.icmp_type), changes.icmp_code.or(current.icmp_code)),
+    };
+    RuleData {
+        source_ip: changes.source_ip.unwrap_or(current.source_ip),
+        dest_ip: changes.dest_ip.unwrap_or(current.dest_ip),
+        source_port: changes.source_port.unwrap_or(current.source_port),
+        dest_port: changes.dest_port.unwrap_or(current.dest_port),
+        action: changes.action.unwrap_or(current.action),
+        protocol: changes.protocol.unwrap_or(current.protocol),
+        priority: changes.priority.or(current.priority),
+        icmp_type,
+        icmp_code,
+        vlan_id: if changes.any_vlan { None } else { changes.vlan_id.or(current.vlan_id) },
+    }
+}
+
+// Différentiel entre les règles en base et le jeu demandé par ApplyRuleset
+#[derive(Default)]
+struct RulesetDiff {
+    created: Vec<Rule>,                  // ID 0 jusqu'à l'insertion en base
+    updated: Vec<(Option<Rule>, Rule)>,  // (avant, après) ; avant absent si la ligne était illisible
+    deleted: Vec<(i32, Option<Rule>)>,
+    unchanged: Vec<Rule>,
+}
+
+impl RulesetDiff {
+    fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.updated.is_empty() && self.deleted.is_empty()
+    }
+}
+
+// Une entrée avec ID remplace cette règle ; une entrée sans ID reprend une règle identique
+// encore libre, sinon elle est créée. Les règles existantes non reprises sont supprimées.
+fn diff_ruleset(current: Vec<(i32, Option<Rule>)>, desired: Vec<(Option<i32>, Rule)>) -> Result<RulesetDiff, String> {
+    let mut remaining: BTreeMap<i32, Option<Rule>> = current.into_iter().collect();
+    let mut diff = RulesetDiff::default();
+    let mut anonymous = Vec::new();
+    // Entrées avec ID d'abord : une entrée sans ID ne doit pas leur prendre leur règle
+    for (id, rule) in desired {
+        let Some(id) = id else {
+            anonymous.push(rule);
+            continue;
+        };
+        let before = remaining.remove(&id).ok_or_else(|| format!("Règle ID {} inexistante ou citée deux fois.", id))?;
+        if before.as_ref() == Some(&rule) {
+            diff.unchanged.push(rule);
+        } else {
+            diff.updated.push((before, rule));
+        }
+    }
+    for rule in anonymous {
+        let same = remaining.iter()
+            .find(|(id, current)| current.as_ref() == Some(&Rule { id: **id, ..rule.clone() }))
+            .map(|(id, _)| *id);
+        match same {
+            Some(id) => {
+                remaining.remove(&id);
+                diff.unchanged.push(Rule { id, ..rule });
+            }
+            None => diff.created.push(rule),
+        }
+    }
+    diff.deleted = remaining.into_iter().collect();
+    Ok(diff)
+}
+
+fn ruleset_response(diff: &RulesetDiff, generation: u32, connections_killed: usize, message: String) -> ApplyRulesetResponse {
+    ApplyRulesetResponse {
+        created: diff.created.iter()
+            .map(|rule| RulesetChange { id: rule.id, before: None, after: Some(rule_to_data(rule)) })
+            .collect(),
+        updated: diff.updated.iter()
+            .map(|(before, after)| RulesetChange { id: after.id, before: before.as_ref().map(rule_to_data), after: Some(rule_to_data(after)) })
+            .collect(),
+        deleted: diff.deleted.iter()
+            .map(|(id, before)| RulesetChange { id: *id, before: before.as_ref().map(rule_to_data), after: None })
+            .collect(),
+        unchanged: diff.unchanged.len() as u32,
+        generation,
+        connections_killed: connections_killed as u32,
+        message,
+    }
+}
+
+// Colonnes ajoutées au fil des versions : une base existante est mise à niveau au démarrage
+async fn ensure_schema(db_client: &tokio_postgres::Client) -> Result<(), anyhow::Error> {
+    db_client
+        .batch_execute(&format!(
+            "ALTER TABLE rules ADD COLUMN IF NOT EXISTS source_port_end INTEGER;
+             ALTER TABLE rules ADD COLUMN IF NOT EXISTS dest_port_end INTEGER;
+             ALTER TABLE rules ADD COLUMN IF NOT EXISTS priority INTEGER NOT NULL DEFAULT {};
+             DO $$ BEGIN
+                 -- Réécriture de la table sous verrou exclusif : seulement pour une base d'avant BIGINT
+                 IF (SELECT data_type FROM information_schema.columns
+                     WHERE table_schema = current_schema() AND table_name = 'rules' AND column_name = 'usage_count') = 'integer' THEN
+                     ALTER TABLE rules ALTER COLUMN usage_count TYPE BIGINT;
+                 END IF;
+             END $$;
+             ALTER TABLE rules ADD COLUMN IF NOT EXISTS byte_count BIGINT NOT NULL DEFAULT 0;
+             ALTER TABLE rules ADD COLUMN IF NOT EXISTS last_hit TIMESTAMPTZ;
+             ALTER TABLE rules ADD COLUMN IF NOT EXISTS icmp_type INTEGER;
+             ALTER TABLE rules ADD COLUMN IF NOT EXISTS icmp_code INTEGER;
+             ALTER TABLE rules ADD COLUMN IF NOT EXISTS vlan_id INTEGER;
+             CREATE TABLE IF NOT EXISTS conntrack_timeouts (
+                 protocol TEXT NOT NULL,
+                 state TEXT NOT NULL DEFAULT '',
+                 port INTEGER NOT NULL DEFAULT 0,
+                 timeout_s INTEGER NOT NULL,
+                 PRIMARY KEY (protocol, state, port)
+             );
+             CREATE TABLE IF NOT EXISTS default_policies (
+                 traffic_class TEXT PRIMARY KEY,
+                 policy TEXT NOT NULL
+             );",
+            DEFAULT_PRIORITY
+        ))
+        .await
+        .context("Erreur lors de la mise à niveau du schéma de la table rules")?;
+    Ok(())
+}
+
+// Timeouts de suivi : valeurs par défaut, surchargées par la table conntrack_timeouts
+async fn load_ctt_timeouts(db_client: &tokio_postgres::Client) -> Result<CttTimeouts, anyhow::Error> {
+    let rows = db_client
+        .query("SELECT protocol, state, port, timeout_s FROM conntrack_timeouts", &[])
+        .await
+        .context("Erreur lors de la lecture de conntrack_timeouts")?;
+
+    let mut timeouts = CttTimeouts::default();
+    for row in rows {
+        let protocol: String = row.get("protocol");
+        let state: String = row.get("state");
+        let port: i32 = row.get("port");
+        let timeout_s: i32 = row.get("timeout_s");
+        let applied = TimeoutTarget::parse(&protocol, &state, port.max(0) as u32)
+            .and_then(|target| timeouts.set(target, (timeout_s > 0).then_some(timeout_s as u64 * 1_000_000_000)));
+        if let Err(e) = applied {
+            warn!("Timeout CTT ignoré ({} {} port {}): {}", protocol, state, port, e);
+        }
+    }
+    Ok(timeouts)
+}
+
+fn ctt_timeouts_to_proto(timeouts: &CttTimeouts) -> Vec<ConntrackTimeout> {
+    timeouts.entries().into_iter().map(|(target, ns)| {
+        let (protocol, state, port) = target.names();
+        ConntrackTimeout {
+            protocol: protocol.to_string(),
+            state: state.to_string(),
+            port: port as u32,
+            timeout_s: (ns / 1_000_000_000) as u32,
+        }
+    }).collect()
+}
+
+// Politiques par défaut : intégrées, surchargées par la table default_policies
+async fn load_default_policies(db_client: &tokio_postgres::Client, policies: &mut DefaultPolicies) -> Result<(), anyhow::Error> {
+    let rows = db_client
+        .query("SELECT traffic_class, policy FROM default_policies", &[])
+        .await
+        .context("Erreur lors de la lecture de default_policies")?;
+
+    for row in rows {
+        let class_name: String = row.get("traffic_class");
+        let policy_name: String = row.get("policy");
+        match policy::parse_class(&class_name).and_then(|class| Ok((class, policy::parse_policy(&policy_name)?))) {
+            Ok((class, policy)) => policies.set(class, policy).context("DEFAULT_POLICY map update error")?,
+            Err(e) => warn!("Politique par défaut ignorée ({} {}): {}", class_name, policy_name, e),
+        }
+    }
+    Ok(())
+}
+
+fn default_policies_to_proto(policies: &DefaultPolicies) -> Vec<DefaultPolicy> {
+    policies.entries().into_iter().map(|(traffic_class, policy)| DefaultPolicy {
+        traffic_class: traffic_class.to_string(),
+        policy: policy.to_string(),
+    }).collect()
+}
+
+// Pagination de ListConnections
+const CONNECTIONS_DEFAULT_LIMIT: usize = 100;
+const CONNECTIONS_MAX_LIMIT: usize = 1000;
+
+fn conn_filter_from_proto(req: &ConnectionFilter) -> Result<ConnFilter, String> {
+    let prefix = match req.ip.trim() {
+        "" | "*" | "any" => None,
+        ip => Some(IpPrefix::parse(ip)?),
+    };
+    let port = req.port
+        .map(|port| u16::try_from(port).map_err(|_| format!("Port invalide : {}", port)))
+        .transpose()?;
+    let protocol = match req.protocol.trim() {
+        "" => None,
+        protocol => Some(conntrack::parse_protocol(protocol)?),
+    };
+    let state = match req.state.trim() {
+        "" => None,
+        state => Some(conntrack::parse_state(state)?),
+    };
+    let vlan_id = req.vlan_id
+        .map(|vlan_id| u16::try_from(vlan_id).ok().filter(|v| *v <= 4094).ok_or_else(|| format!("VLAN invalide : {}", vlan_id)))
+        .transpose()?;
+    Ok(ConnFilter { prefix, port, protocol, state, vlan_id })
+}
+
+fn flow_from_tuple(tuple: &ConnectionTuple) -> Result<Flow, String> {
+    let parse_ip = |ip: &str| ip.trim().parse::<IpAddr>().map_err(|_| format!("Adresse IP invalide : '{}'", ip));
+    let parse_port = |port: u32| u16::try_from(port).map_err(|_| format!("Port invalide : {}", port));
+    let source = parse_ip(&tuple.source_ip)?;
+    let dest = parse_ip(&tuple.dest_ip)?;
+    if source.is_ipv4() != dest.is_ipv4() {
+        return Err("Adresses source et destination de familles différentes.".to_string());
+    }
+    let vlan_id = match tuple.vlan_id {
+        None => 0,
+        Some(vlan_id) => u16::try_from(vlan_id).ok().filter(|v| *v <= 4094).ok_or_else(|| format!("VLAN invalide : {}", vlan_id))?,
+    };
+    Ok(Flow {
+        source,
+        dest,
+        source_port: parse_port(tuple.source_port)?,
+        dest_port: parse_port(tuple.dest_port)?,
+        protocol: conntrack::parse_protocol(&tuple.protocol)?,
+        vlan_id,
+    })
+}
+
+fn conn_entry_to_proto(entry: &ConnEntry) -> ConnectionInfo {
+    const NS_PER_S: u64 = 1_000_000_000;
+    ConnectionInfo {
+        source_ip: entry.flow.source.to_string(),
+        dest_ip: entry.flow.dest.to_string(),
+        source_port: entry.flow.source_port as u32,
+        dest_port: entry.flow.dest_port as u32,
+        protocol: events::protocol_name(entry.flow.protocol),
+        vlan_id: (entry.flow.vlan_id != 0).then_some(entry.flow.vlan_id as u32),
+        state: entry.state.to_string(),
+        age_s: entry.age_ns / NS_PER_S,
+        idle_s: entry.idle_ns / NS_PER_S,
+        expires_in_s: entry.expires_in_ns / NS_PER_S,
+    }
+}
+
+// Événements en attente d'envoi par client WatchEvents ; au-delà, les plus anciens sont sautés
+const WATCH_BUFFER: usize = 256;
+
+fn watch_filter_from_proto(req: &WatchEventsRequest) -> Result<EventFilter, String> {
+    let prefix = match req.ip.trim() {
+        "" | "*" | "any" => None,
+        ip => Some(IpPrefix::parse(ip)?),
+    };
+    let port = req.port
+        .map(|port| u16::try_from(port).map_err(|_| format!("Port invalide : {}", port)))
+        .transpose()?;
+    let verdict = match req.verdict.trim() {
+        "" => None,
+        verdict => Some(Verdict::parse(verdict)?),
+    };
+    Ok(EventFilter { prefix, port, verdict, rule_id: req.rule_id })
+}
+
+fn event_to_proto(event: &PacketEvent, skipped: u64) -> EventInfo {
+    // Horodatage noyau (monotone) ramené à l'heure murale
+    let age = Duration::from_nanos(kernel_monotonic_ns().saturating_sub(event.timestamp_ns));
+    let unix_time_ms = SystemTime::now()
+        .checked_sub(age)
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+    EventInfo {
+        unix_time_ms,
+        source_ip: event.source.to_string(),
+        dest_ip: event.dest.to_string(),
+        protocol: events::protocol_name(event.protocol),
+        source_port: event.source_port as u32,
+        dest_port: event.dest_port as u32,
+        vlan_id: event.vlan_id.map(u32::from),
+        verdict: event.verdict.to_string(),
+        rule_id: event.rule_id,
+        reason: event.reason.to_string(),
+        skipped,
+    }
+}
+
+// Relaie les événements du bus vers un client WatchEvents jusqu'à sa déconnexion
+async fn forward_events(
+    mut events: broadcast::Receiver<PacketEvent>,
+    filter: EventFilter,
+    tx: mpsc::Sender<Result<EventInfo, Status>>,
+) {
+    let mut skipped = 0;
+    loop {
+        let received = tokio::select! {
+            received = events.recv() => received,
+            // Client parti alors qu'aucun événement n'arrive
+            _ = tx.closed() => break,
+        };
+        match received {
+            Ok(event) => {
+                if !filter.matches(&event) {
+                    continue;
+                }
+                if tx.send(Ok(event_to_proto(&event, skipped))).await.is_err() {
+                    break;
+                }
+                skipped = 0;
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => skipped += n,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+fn log_level_to_proto(verbosity: &EventVerbosity) -> LogLevel {
+    LogLevel {
+        level: events::level_name(verbosity.level()).to_string(),
+        sample_rate: verbosity.sample_rate(),
+    }
+}
+
+
+impl MyFirewallService {
+    // Retire des tables de suivi les flux retenus par `predicate` ; renvoie le nombre d'entrées retirées
+    async fn purge_connections(&self, predicate: impl Fn(&Flow) -> bool) -> usize {
+        let removed_v4 = conntrack::remove_matching(&mut *self.ctt_map.lock().await, |flow, _| predicate(flow));
+        let removed_v6 = conntrack::remove_matching(&mut *self.ctt_v6_map.lock().await, |flow, _| predicate(flow));
+        removed_v4 + removed_v6
+    }
+}
 
 #[tonic::async_trait]
 impl FirewallService for MyFirewallService {
     async fn get_status( /* ... */ &self, request: Request<Empty>) -> Result<Response<FirewallStatus>, Status> {
         info!("gRPC: Appel de GetStatus reçu");
+        let fragments = match self.fragment_counters.lock().await.totals() {
+            Ok(totals) => Some(FragmentStats {
+                first: totals[FRAG_STAT_FIRST as usize],
+                followed: totals[FRAG_STAT_FOLLOWED as usize],
+                orphan: totals[FRAG_STAT_ORPHAN as usize],
+                tiny: totals[FRAG_STAT_TINY as usize],
+                overlapping: totals[FRAG_STAT_OVERLAP as usize],
+            }),
+            Err(e) => {
+                warn!("Lecture de FRAGMENT_STATS impossible : {}", e);
+                None
+            }
+        };
         let status = FirewallStatus {
             status: "UP".to_string(),
+            conntrack_timeouts: ctt_timeouts_to_proto(&*self.ctt_timeouts.lock().await),
+            default_policies: default_policies_to_proto(&*self.default_policies.lock().await),
+            fragments,
+            log_level: Some(log_level_to_proto(&*self.event_verbosity.lock().await)),
+            egress_tracking: self.egress_tracking,
         };
         Ok(Response::new(status))
     }
@@ -109,7 +592,40 @@ impl FirewallService for MyFirewallService {
     async fn list_rules( /* ... */ &self, request: Request<Empty>) -> Result<Response<RuleListResponse>, Status> {
         info!("gRPC: Appel de ListRules reçu");
         match fetch_and_format_rules_from_db(&self.db_client).await {
-            Ok(rules) => Ok(Response::new(RuleListResponse { rules })),
+            Ok(mut rules) => {
+                // Afficher la règle telle que le noyau l'applique réellement, dans l'ordre
+                // d'évaluation (les règles non chargées à la fin)
+                let blocklists = self.bpf_blocklist_map.lock().await;
+                rules.sort_by_key(|info| (blocklists.rank(info.id).unwrap_or(u32::MAX), info.id));
+                // Ajouter les hits noyau pas encore reportés en base
+                match self.rule_counters.lock().await.pending() {
+                    Ok(pending) => {
+                        for info in rules.iter_mut() {
+                            if let Some(delta) = pending.get(&(info.id as u32)) {
+                                info.usage_count += delta.packets as i64;
+                                info.byte_count += delta.bytes;
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Lecture de RULE_STATS impossible : {}", e),
+                }
+                for info in rules.iter_mut() {
+                    if let Some(rule) = blocklists.get(info.id) {
+                        info.source_ip = rule.source.to_string();
+                        info.dest_ip = rule.dest.to_string();
+                        info.source_port = rule.source_port.to_string();
+                        info.dest_port = rule.dest_port.to_string();
+                        info.action = rule.action_name().to_string();
+                        info.protocol = rule.protocol_name().to_string();
+                        info.priority = rule.priority;
+                        info.icmp_type = rule.icmp_type.map(i32::from);
+                        info.icmp_code = rule.icmp_code.map(i32::from);
+                        info.vlan_id = rule.vlan_id.map(i32::from);
+                        info.enforced = true;
+                    }
+                }
+                Ok(Response::new(RuleListResponse { rules }))
+            }
             Err(e) => {
                 error!("Erreur lors de la récupération des règles pour gRPC: {}", e);
                 Err(Status::internal(format!("Échec de la récupération des règles: {}", e)))
@@ -125,26 +641,35 @@ impl FirewallService for MyFirewallService {
         info!("gRPC: Appel de CreateRule reçu pour : {:?}", req_data.rule);
 
         let rule_to_create = req_data.rule.ok_or_else(|| Status::invalid_argument("Données de règle manquantes"))?;
-
-        // Validations (simples)
-        if rule_to_create.source_ip.is_empty() || rule_to_create.dest_ip.is_empty() {
-            return Err(Status::invalid_argument("IPs source/dest requises."));
-        }
-        let action_str = rule_to_create.action.to_lowercase();
-        if action_str != "allow" && action_str != "deny" {
-            return Err(Status::invalid_argument("Action doit être 'allow' ou 'deny'."));
-        }
-        let source_port_db: Option<i32> = rule_to_create.source_port.parse().ok();
-        let dest_port_db: Option<i32> = rule_to_create.dest_port.parse().ok();
+        let purge = req_data.purge_connections.unwrap_or(true);
+
+        // IP ou préfixe CIDR, IPv4 ou IPv6 : on valide avant d'écrire quoi que ce soit en base
+        let mut rule_bpf = rule_from_data(0, &rule_to_create).map_err(Status::invalid_argument)?;
+        let action_str = rule_bpf.action_name();
+        let priority = rule_bpf.priority;
+        let icmp_type_db = rule_bpf.icmp_type.map(i32::from);
+        let icmp_code_db = rule_bpf.icmp_code.map(i32::from);
+        let vlan_id_db = rule_bpf.vlan_id.map(i32::from);
+        let source_ip_db = rule_bpf.source.to_string();
+        let dest_ip_db = rule_bpf.dest.to_string();
+        // Début NULL = wildcard, fin NULL = port unique
+        let (source_port_db, source_port_end_db) = rule_bpf.source_port.to_db();
+        let (dest_port_db, dest_port_end_db) = rule_bpf.dest_port.to_db();
+
+        // Le verrou sérialise les modifications du jeu de règles : la ligne insérée ne reste
+        // en base que si le noyau accepte la règle
+        let mut blocklist_map_guard = self.bpf_blocklist_map.lock().await;
 
         // Insertion DB
         let created_rule_id: i32 = match self.db_client.query_one(
-            "INSERT INTO rules (source_ip, dest_ip, source_port, dest_port, action, protocol) \
-             VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            "INSERT INTO rules (source_ip, dest_ip, source_port, source_port_end, dest_port, dest_port_end, action, protocol, priority, icmp_type, icmp_code, vlan_id) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING id",
             &[
-                &rule_to_create.source_ip, &rule_to_create.dest_ip,
-                &source_port_db, &dest_port_db,
-                &action_str, &rule_to_create.protocol.to_uppercase(),
+                &source_ip_db, &dest_ip_db,
+                &source_port_db, &source_port_end_db,
+                &dest_port_db, &dest_port_end_db,
+                &action_str, &rule_bpf.protocol_name(), &priority,
+                &icmp_type_db, &icmp_code_db, &vlan_id_db,
             ],
         ).await {
             Ok(row) => row.get(0),
@@ -155,43 +680,32 @@ impl FirewallService for MyFirewallService {
         };
         info!("Règle insérée dans DB ID: {}", created_rule_id);
 
-        // Insertion dans la map eBPF `BLOCKLIST`
-        const ACTION_DENY_U32: u32 = 1;
-        const ACTION_ALLOW_U32: u32 = 2;
-        match (rule_to_create.source_ip.parse::<std::net::Ipv4Addr>(), rule_to_create.dest_ip.parse::<std::net::Ipv4Addr>()) {
-            (Ok(ip_src_obj), Ok(ip_dst_obj)) => {
-                let port_for_bpf = if rule_to_create.dest_port == "*" || rule_to_create.dest_port.is_empty() {
-                     0 // Wildcard port
-                } else {
-                    rule_to_create.dest_port.parse().unwrap_or(0) // Port spécifique, ou 0 si invalide
-                };
-
-                let key_bpf = IpPort {
-                    addr: u32::from(ip_src_obj).to_be(),
-                    addr_dest: u32::from(ip_dst_obj).to_be(),
-                    port: port_for_bpf.to_be(), // port en network byte order
-                    _pad: 0,
-                };
-                let action_value_bpf = if action_str == "deny" { ACTION_DENY_U32 } else { ACTION_ALLOW_U32 };
-
-                let mut blocklist_map_guard = self.bpf_blocklist_map.lock().await;
-                match blocklist_map_guard.insert(key_bpf, action_value_bpf, 0) {
-                    Ok(_) => info!("Règle ID {} insérée/mise à jour dans la map BPF BLOCKLIST.", created_rule_id),
-                    Err(e) => {
-                        error!("Erreur d'insertion dans BPF BLOCKLIST pour règle ID {}: {}", created_rule_id, e);
-                        // Peut-être annuler l'insertion DB ou marquer la règle comme inactive?
-                        // Pour l'instant, on continue mais on logue l'erreur.
-                    }
-                }
-            }
-            _ => {
-                warn!("IPs invalides pour l'insertion BPF, règle ID {}: {} -> {}", created_rule_id, rule_to_create.source_ip, rule_to_create.dest_ip);
+        // Compilation dans les tries eBPF `BLOCKLIST` / `BLOCKLIST_V6`
+        rule_bpf.id = created_rule_id;
+
+        // Règle refusée par le noyau (trop de règles qui se recouvrent, map pleine) : la ligne est retirée
+        if let Err(e) = blocklist_map_guard.insert(rule_bpf.clone()) {
+            error!("Erreur d'insertion dans BPF BLOCKLIST pour règle ID {}: {}", created_rule_id, e);
+            if let Err(db_error) = self.db_client.execute("DELETE FROM rules WHERE id = $1", &[&created_rule_id]).await {
+                error!("DB Delete error (règle ID {} non appliquée restée en base): {}", created_rule_id, db_error);
             }
+            return Err(Status::failed_precondition(format!("Règle non applicable dans le noyau : {}", e)));
+        }
+        info!("Règle ID {} insérée/mise à jour dans la map BPF BLOCKLIST.", created_rule_id);
+
+        // Un refus ne s'applique qu'aux nouveaux flux : couper aussi ceux déjà suivis
+        let mut message = format!("Règle créée ID {}.", created_rule_id);
+        let mut connections_killed = 0;
+        if purge && rule_bpf.action == ACTION_DENY {
+            connections_killed = self.purge_connections(|flow| conntrack::cut_by_deny(&blocklist_map_guard, &rule_bpf, flow)).await;
+            info!("🔪 Règle ID {} : {} entrée(s) de suivi retirée(s).", created_rule_id, connections_killed);
+            message = format!("Règle créée ID {} ({} connexion(s) coupée(s)).", created_rule_id, connections_killed);
         }
 
         Ok(Response::new(CreateRuleResponse {
             created_rule_id,
-            message: format!("Règle créée ID {}.", created_rule_id),
+            message,
+            connections_killed: connections_killed as u32,
         }))
     }
 
@@ -199,76 +713,558 @@ impl FirewallService for MyFirewallService {
         &self,
         request: Request<DeleteRuleRequest>,
     ) -> Result<Response<DeleteRuleResponse>, tonic::Status> {
-        let rule_id_to_delete = request.into_inner().rule
+        let req = request.into_inner();
+        let rule_id_to_delete = req.rule
             .ok_or_else(|| Status::invalid_argument("Données de suppression manquantes"))?
             .id;
+        let purge = req.purge_connections.unwrap_or(true);
         info!("gRPC: Appel de DeleteRule pour ID: {}", rule_id_to_delete);
 
-        // 1. Récupérer les infos de la règle depuis la DB pour la clé BPF
-        let (source_ip_str, dest_ip_str, dest_port_opt_db) : (String, String, Option<i32>) =
-            match self.db_client.query_opt(
-                "SELECT source_ip, dest_ip, dest_port FROM rules WHERE id = $1",
-                &[&rule_id_to_delete]
-            ).await {
-                Ok(Some(row)) => (row.get(0), row.get(1), row.get(2)),
-                Ok(None) => return Err(Status::not_found(format!("Règle ID {} non trouvée.", rule_id_to_delete))),
-                Err(e) => {
-                    error!("DB Select error for delete: {}", e);
-                    return Err(Status::internal(format!("DB error: {}", e)));
-                }
-            };
-
-        // 2. Suppression de la map eBPF BLOCKLIST
-        match (source_ip_str.parse::<std::net::Ipv4Addr>(), dest_ip_str.parse::<std::net::Ipv4Addr>()) {
-            (Ok(ip_src_obj), Ok(ip_dst_obj)) => {
-                 let port_for_bpf = dest_port_opt_db.map_or(0, |p| p as u16); // 0 pour wildcard si NULL
-
-                let key_bpf = IpPort {
-                    addr: u32::from(ip_src_obj).to_be(),
-                    addr_dest: u32::from(ip_dst_obj).to_be(),
-                    port: port_for_bpf.to_be(),
-                    _pad: 0,
-                };
-                let mut blocklist_map_guard = self.bpf_blocklist_map.lock().await;
-                match blocklist_map_guard.remove(&key_bpf) {
-                    Ok(_) => info!("Règle ID {} (clé BPF {:?}) supprimée de BLOCKLIST.", rule_id_to_delete, key_bpf),
-                    Err(e) => warn!("Erreur ou clé non trouvée lors de la suppression BPF BLOCKLIST pour ID {}: {} (clé {:?})", rule_id_to_delete, e, key_bpf),
-                }
+        // 1. Retrait du jeu compilé dans les tries eBPF BLOCKLIST ; en cas d'échec, rien n'a changé
+        let mut blocklist_map_guard = self.bpf_blocklist_map.lock().await;
+        let removed_rule = match blocklist_map_guard.remove(rule_id_to_delete) {
+            Ok(Some(rule)) => {
+                info!("Règle ID {} supprimée de BLOCKLIST.", rule_id_to_delete);
+                Some(rule)
             }
-            _ => {
-                warn!("IPs invalides pour la suppression BPF, règle ID {}", rule_id_to_delete);
+            Ok(None) => {
+                warn!("Règle ID {} absente de BLOCKLIST (non chargée dans le noyau).", rule_id_to_delete);
+                None
             }
-        }
-        // NOTE: On ne nettoie PAS la CONN_TRACK_TABLE ici pour la simplicité.
-        // Les connexions existantes autorisées par cette règle continueront jusqu'à leur timeout.
-        // Pour un comportement plus strict, il faudrait itérer CONN_TRACK_TABLE et supprimer les entrées correspondantes.
+            Err(e) => {
+                error!("Erreur lors de la mise à jour BPF BLOCKLIST pour ID {}: {}", rule_id_to_delete, e);
+                return Err(Status::internal(format!("Retrait de la règle du noyau impossible : {}", e)));
+            }
+        };
 
-        // 3. Suppression de la base de données PostgreSQL
-        match self.db_client.execute("DELETE FROM rules WHERE id = $1", &[&rule_id_to_delete]).await {
-            Ok(0) => return Err(Status::not_found(format!("Règle ID {} non trouvée pour suppression DB.", rule_id_to_delete))),
+        // 2. Suppression de la base de données PostgreSQL ; en cas d'échec, le noyau reprend la règle
+        let result = self.db_client.execute("DELETE FROM rules WHERE id = $1", &[&rule_id_to_delete]).await;
+        match result {
+            Ok(0) if removed_rule.is_none() => return Err(Status::not_found(format!("Règle ID {} non trouvée.", rule_id_to_delete))),
             Ok(_) => info!("Règle ID {} supprimée de la DB.", rule_id_to_delete),
             Err(e) => {
                 error!("DB Delete error: {}", e);
+                if let Some(rule) = removed_rule {
+                    if let Err(restore_error) = blocklist_map_guard.insert(rule) {
+                        error!("Restauration BPF BLOCKLIST impossible pour règle ID {}: {}", rule_id_to_delete, restore_error);
+                    }
+                }
                 return Err(Status::internal(format!("DB error: {}", e)));
             }
         }
+        self.rule_counters.lock().await.forget(rule_id_to_delete as u32);
+
+        // 3. Les flux suivis ne repassent pas par les règles : couper ceux que la règle admettait seule
+        let mut message = format!("Règle ID {} supprimée.", rule_id_to_delete);
+        let mut connections_killed = 0;
+        if let Some(rule) = removed_rule.filter(|rule| purge && rule.action == ACTION_ALLOW) {
+            connections_killed = self.purge_connections(|flow| conntrack::revoked_by_removal(&blocklist_map_guard, &rule, flow)).await;
+            info!("🔪 Règle ID {} : {} entrée(s) de suivi retirée(s).", rule_id_to_delete, connections_killed);
+            message = format!("Règle ID {} supprimée ({} connexion(s) coupée(s)).", rule_id_to_delete, connections_killed);
+        }
 
         Ok(Response::new(DeleteRuleResponse {
-            delete_rule_id,
-            message: format!("Règle ID {} supprimée.", rule_id_to_delete),
+            delete_rule_id: rule_id_to_delete,
+            message,
+            connections_killed: connections_killed as u32,
         }))
     }
+
+    async fn update_rule(
+        &self,
+        request: Request<UpdateRuleRequest>,
+    ) -> Result<Response<UpdateRuleResponse>, tonic::Status> {
+        let req = request.into_inner();
+        info!("gRPC: Appel de UpdateRule reçu : {:?}", req);
+        let rule_id = req.id;
+        let changes = req.changes.ok_or_else(|| Status::invalid_argument("Modifications manquantes"))?;
+        let purge = req.purge_connections.unwrap_or(true);
+
+        // Le verrou sérialise les modifications du jeu de règles pendant toute la mise à jour
+        let mut blocklist_map_guard = self.bpf_blocklist_map.lock().await;
+
+        // 1. Règle actuelle (la base fait foi, y compris pour une règle non chargée dans le noyau)
+        let row = match self.db_client.query_opt(
+            "SELECT id, source_ip, dest_ip, source_port, source_port_end, dest_port, dest_port_end, action, protocol, priority, \
+             icmp_type, icmp_code, vlan_id FROM rules WHERE id = $1",
+            &[&rule_id],
+        ).await {
+            Ok(Some(row)) => row,
+            Ok(None) => return Err(Status::not_found(format!("Règle ID {} non trouvée.", rule_id))),
+            Err(e) => {
+                error!("DB Select error: {}", e);
+                return Err(Status::internal(format!("DB error: {}", e)));
+            }
+        };
+        let current = rule_from_row(&row).map_err(|e| Status::internal(format!("Règle ID {} illisible en base : {}", rule_id, e)))?;
+        let updated = rule_from_data(rule_id, &merge_rule_update(&current, changes)).map_err(Status::invalid_argument)?;
+
+        // 2. Remplacement dans les tries eBPF : les nouvelles entrées sont écrites avant le retrait
+        // des anciennes, la règle reste appliquée sans interruption. En cas d'échec, rien n'a changé.
+        let previous = blocklist_map_guard.get(rule_id).cloned();
+        if let Err(e) = blocklist_map_guard.insert(updated.clone()) {
+            error!("Erreur de mise à jour BPF BLOCKLIST pour règle ID {}: {}", rule_id, e);
+            return Err(Status::failed_precondition(format!("Règle non applicable dans le noyau : {}", e)));
+        }
+
+        // 3. Ligne en base ; en cas d'échec, le noyau reprend la version précédente
+        let (source_port_db, source_port_end_db) = updated.source_port.to_db();
+        let (dest_port_db, dest_port_end_db) = updated.dest_port.to_db();
+        let result = self.db_client.execute(
+            "UPDATE rules SET source_ip = $1, dest_ip = $2, source_port = $3, source_port_end = $4, dest_port = $5, dest_port_end = $6, \
+             action = $7, protocol = $8, priority = $9, icmp_type = $10, icmp_code = $11, vlan_id = $12 WHERE id = $13",
+            &[
+                &updated.source.to_string(), &updated.dest.to_string(),
+                &source_port_db, &source_port_end_db,
+                &dest_port_db, &dest_port_end_db,
+                &updated.action_name(), &updated.protocol_name(), &updated.priority,
+                &updated.icmp_type.map(i32::from), &updated.icmp_code.map(i32::from), &updated.vlan_id.map(i32::from),
+                &rule_id,
+            ],
+        ).await;
+        if let Err(e) = result {
+            error!("DB Update error: {}", e);
+            let reverted = match previous {
+                Some(previous) => blocklist_map_guard.insert(previous),
+                None => blocklist_map_guard.remove(rule_id).map(|_| ()),
+            };
+            if let Err(revert_error) = reverted {
+                error!("Restauration BPF BLOCKLIST impossible pour règle ID {}: {}", rule_id, revert_error);
+            }
+            return Err(Status::internal(format!("DB error: {}", e)));
+        }
+        info!("Règle ID {} mise à jour (DB et BLOCKLIST).", rule_id);
+
+        // 4. Flux suivis que l'ancienne version admettait seule, ou que la nouvelle refuse
+        let mut message = format!("Règle ID {} mise à jour.", rule_id);
+        let mut connections_killed = 0;
+        if purge {
+            connections_killed = self.purge_connections(|flow| {
+                matches!(&previous, Some(previous) if conntrack::revoked_by_removal(&blocklist_map_guard, previous, flow))
+                    || conntrack::cut_by_deny(&blocklist_map_guard, &updated, flow)
+            }).await;
+            info!("🔪 Règle ID {} : {} entrée(s) de suivi retirée(s).", rule_id, connections_killed);
+            message = format!("Règle ID {} mise à jour ({} connexion(s) coupée(s)).", rule_id, connections_killed);
+        }
+
+        Ok(Response::new(UpdateRuleResponse {
+            id: rule_id,
+            message,
+            connections_killed: connections_killed as u32,
+        }))
+    }
+
+    async fn apply_ruleset(
+        &self,
+        request: Request<ApplyRulesetRequest>,
+    ) -> Result<Response<ApplyRulesetResponse>, tonic::Status> {
+        let req = request.into_inner();
+        info!("gRPC: Appel de ApplyRuleset reçu ({} règle(s), dry_run: {})", req.rules.len(), req.dry_run);
+        let purge = req.purge_connections.unwrap_or(true);
+
+        // 1. Tout le jeu est validé avant d'ouvrir la transaction
+        let desired = req.rules.iter().enumerate()
+            .map(|(index, entry)| {
+                let data = entry.rule.as_ref().ok_or_else(|| format!("Règle n°{} : données manquantes.", index + 1))?;
+                rule_from_data(entry.id.unwrap_or(0), data)
+                    .map(|rule| (entry.id, rule))
+                    .map_err(|e| format!("Règle n°{} : {}", index + 1, e))
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(Status::invalid_argument)?;
+
+        // Le verrou sérialise les modifications du jeu de règles jusqu'à la bascule
+        let mut blocklist_map_guard = self.bpf_blocklist_map.lock().await;
+        let mut ruleset_db = self.ruleset_db.lock().await;
+        let db_error = |e: tokio_postgres::Error| {
+            error!("DB ApplyRuleset error: {}", e);
+            Status::internal(format!("DB error: {}", e))
+        };
+        let transaction = ruleset_db.transaction().await.map_err(db_error)?;
+
+        // 2. Règles en base, verrouillées jusqu'à la fin de la transaction
+        let rows = transaction.query(
+            "SELECT id, source_ip, dest_ip, source_port, source_port_end, dest_port, dest_port_end, action, protocol, priority, \
+             icmp_type, icmp_code, vlan_id FROM rules FOR UPDATE",
+            &[],
+        ).await.map_err(db_error)?;
+        let current = rows.iter()
+            .map(|row| {
+                let id: i32 = row.get("id");
+                let rule = rule_from_row(row).map_err(|e| warn!("Règle ID {} illisible en base : {}", id, e)).ok();
+                (id, rule)
+            })
+            .collect();
+        let mut diff = diff_ruleset(current, desired).map_err(Status::invalid_argument)?;
+
+        // 3. Simulation ou jeu identique : la transaction est abandonnée sans écriture
+        if req.dry_run || diff.is_empty() {
+            let message = match req.dry_run {
+                true => format!("Simulation : {} création(s), {} modification(s), {} suppression(s), {} inchangée(s).",
+                    diff.created.len(), diff.updated.len(), diff.deleted.len(), diff.unchanged.len()),
+                false => "Aucune modification.".to_string(),
+            };
+            return Ok(Response::new(ruleset_response(&diff, blocklist_map_guard.generation(), 0, message)));
+        }
+
+        // 4. Écritures en base
+        for (id, _) in &diff.deleted {
+            transaction.execute("DELETE FROM rules WHERE id = $1", &[id]).await.map_err(db_error)?;
+        }
+        for (_, rule) in &diff.updated {
+            let (source_port_db, source_port_end_db) = rule.source_port.to_db();
+            let (dest_port_db, dest_port_end_db) = rule.dest_port.to_db();
+            transaction.execute(
+                "UPDATE rules SET source_ip = $1, dest_ip = $2, source_port = $3, source_port_end = $4, dest_port = $5, dest_port_end = $6, \
+                 action = $7, protocol = $8, priority = $9, icmp_type = $10, icmp_code = $11, vlan_id = $12 WHERE id = $13",
+                &[
+                    &rule.source.to_string(), &rule.dest.to_string(),
+                    &source_port_db, &source_port_end_db,
+                    &dest_port_db, &dest_port_end_db,
+                    &rule.action_name(), &rule.protocol_name(), &rule.priority,
+                    &rule.icmp_type.map(i32::from), &rule.icmp_code.map(i32::from), &rule.vlan_id.map(i32::from),
+                    &rule.id,
+                ],
+            ).await.map_err(db_error)?;
+        }
+        for rule in &mut diff.created {
+            let (source_port_db, source_port_end_db) = rule.source_port.to_db();
+            let (dest_port_db, dest_port_end_db) = rule.dest_port.to_db();
+            let row = transaction.query_one(
+                "INSERT INTO rules (source_ip, dest_ip, source_port, source_port_end, dest_port, dest_port_end, action, protocol, priority, icmp_type, icmp_code, vlan_id) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING id",
+                &[
+                    &rule.source.to_string(), &rule.dest.to_string(),
+                    &source_port_db, &source_port_end_db,
+                    &dest_port_db, &dest_port_end_db,
+                    &rule.action_name(), &rule.protocol_name(), &rule.priority,
+                    &rule.icmp_type.map(i32::from), &rule.icmp_code.map(i32::from), &rule.vlan_id.map(i32::from),
+                ],
+            ).await.map_err(db_error)?;
+            rule.id = row.get(0);
+        }
+
+        // 5. Jeu complet écrit dans les tries inactifs ; en cas d'échec la transaction est annulée
+        let final_rules = diff.unchanged.iter()
+            .chain(diff.updated.iter().map(|(_, rule)| rule))
+            .chain(diff.created.iter())
+            .cloned()
+            .collect();
+        let staged = match blocklist_map_guard.stage(final_rules) {
+            Ok(staged) => staged,
+            Err(e) => {
+                error!("Erreur de préparation du jeu de règles BPF : {}", e);
+                return Err(Status::failed_precondition(format!("Jeu de règles non applicable dans le noyau : {}", e)));
+            }
+        };
+
+        // 6. Bascule du noyau sur le nouveau jeu, puis validation en base ; si la validation
+        // échoue, le noyau revient au jeu précédent (ses tries n'ont pas été touchés)
+        let previous_rules = match blocklist_map_guard.swap(staged) {
+            Ok(previous_rules) => previous_rules,
+            Err(e) => {
+                error!("Bascule RULES_GENERATION impossible : {}", e);
+                return Err(Status::internal(format!("Bascule du jeu de règles impossible : {}", e)));
+            }
+        };
+        if let Err(e) = transaction.commit().await {
+            if let Err(revert_error) = blocklist_map_guard.swap(previous_rules) {
+                error!("Retour au jeu de règles précédent impossible : {}", revert_error);
+            }
+            return Err(db_error(e));
+        }
+        drop(ruleset_db);
+        let generation = blocklist_map_guard.generation();
+        info!("🔁 Jeu de règles appliqué (génération {}) : {} créée(s), {} modifiée(s), {} supprimée(s).",
+            generation, diff.created.len(), diff.updated.len(), diff.deleted.len());
+        {
+            let mut rule_counters = self.rule_counters.lock().await;
+            for (id, _) in &diff.deleted {
+                rule_counters.forget(*id as u32);
+            }
+        }
+
+        // 7. Flux suivis qu'une ancienne version admettait seule, ou qu'une nouvelle règle refuse
+        let mut connections_killed = 0;
+        if purge {
+            let previous: Vec<&Rule> = diff.updated.iter().filter_map(|(before, _)| before.as_ref())
+                .chain(diff.deleted.iter().filter_map(|(_, before)| before.as_ref()))
+                .collect();
+            let introduced: Vec<&Rule> = diff.updated.iter().map(|(_, after)| after).chain(diff.created.iter()).collect();
+            connections_killed = self.purge_connections(|flow| {
+                previous.iter().any(|rule| conntrack::revoked_by_removal(&blocklist_map_guard, rule, flow))
+                    || introduced.iter().any(|rule| conntrack::cut_by_deny(&blocklist_map_guard, rule, flow))
+            }).await;
+            info!("🔪 Jeu de règles : {} entrée(s) de suivi retirée(s).", connections_killed);
+        }
+
+        let message = format!("Jeu de règles appliqué (génération {}) : {} création(s), {} modification(s), {} suppression(s), {} connexion(s) coupée(s).",
+            generation, diff.created.len(), diff.updated.len(), diff.deleted.len(), connections_killed);
+        Ok(Response::new(ruleset_response(&diff, generation, connections_killed, message)))
+    }
+
+    async fn set_conntrack_timeout(
+        &self,
+        request: Request<ConntrackTimeout>,
+    ) -> Result<Response<SetConntrackTimeoutResponse>, tonic::Status> {
+        let req = request.into_inner();
+        info!("gRPC: Appel de SetConntrackTimeout reçu : {:?}", req);
+
+        let target = TimeoutTarget::parse(&req.protocol, &req.state, req.port).map_err(Status::invalid_argument)?;
+        let timeout_s = i32::try_from(req.timeout_s).map_err(|_| Status::invalid_argument("Timeout trop grand."))?;
+        let timeout_ns = (timeout_s > 0).then_some(timeout_s as u64 * 1_000_000_000);
+        let (protocol, state, port) = target.names();
+        let port = port as i32;
+
+        // Valider (et appliquer) avant d'écrire en base ; le verrou évite deux écritures croisées
+        let mut timeouts = self.ctt_timeouts.lock().await;
+        let mut updated = timeouts.clone();
+        updated.set(target, timeout_ns).map_err(Status::invalid_argument)?;
+
+        let result = match timeout_ns {
+            Some(_) => self.db_client.execute(
+                "INSERT INTO conntrack_timeouts (protocol, state, port, timeout_s) VALUES ($1, $2, $3, $4) \
+                 ON CONFLICT (protocol, state, port) DO UPDATE SET timeout_s = EXCLUDED.timeout_s",
+                &[&protocol, &state, &port, &timeout_s],
+            ).await,
+            None => self.db_client.execute(
+                "DELETE FROM conntrack_timeouts WHERE protocol = $1 AND state = $2 AND port = $3",
+                &[&protocol, &state, &port],
+            ).await,
+        };
+        if let Err(e) = result {
+            error!("DB conntrack_timeouts error: {}", e);
+            return Err(Status::internal(format!("DB error: {}", e)));
+        }
+        *timeouts = updated;
+
+        let what = if port != 0 { format!("{} port {}", protocol, port) } else { format!("{} {}", protocol, state).trim_end().to_string() };
+        let message = match timeout_ns {
+            Some(_) => format!("Timeout {} fixé à {}s.", what, timeout_s),
+            None => format!("Surcharge {} retirée.", what),
+        };
+        info!("⏱️ {}", message);
+        Ok(Response::new(SetConntrackTimeoutResponse { message }))
+    }
+
+    async fn set_default_policy(
+        &self,
+        request: Request<DefaultPolicy>,
+    ) -> Result<Response<SetDefaultPolicyResponse>, tonic::Status> {
+        let req = request.into_inner();
+        info!("gRPC: Appel de SetDefaultPolicy reçu : {:?}", req);
+
+        let class = policy::parse_class(&req.traffic_class).map_err(Status::invalid_argument)?;
+        let policy = policy::parse_policy(&req.policy).map_err(Status::invalid_argument)?;
+        let (class_name, policy_name) = (policy::class_name(class), policy::policy_name(policy));
+
+        // Persister d'abord : une politique appliquée mais non enregistrée serait perdue au redémarrage
+        let mut policies = self.default_policies.lock().await;
+        if let Err(e) = self.db_client.execute(
+            "INSERT INTO default_policies (traffic_class, policy) VALUES ($1, $2) \
+             ON CONFLICT (traffic_class) DO UPDATE SET policy = EXCLUDED.policy",
+            &[&class_name, &policy_name],
+        ).await {
+            error!("DB default_policies error: {}", e);
+            return Err(Status::internal(format!("DB error: {}", e)));
+        }
+        if let Err(e) = policies.set(class, policy) {
+            error!("DEFAULT_POLICY map update error: {}", e);
+            return Err(Status::internal(format!("BPF map error: {}", e)));
+        }
+
+        let message = format!("Politique par défaut {} : {}.", class_name, policy_name);
+        info!("🚦 {}", message);
+        Ok(Response::new(SetDefaultPolicyResponse { message }))
+    }
+
+    async fn set_log_level(
+        &self,
+        request: Request<LogLevel>,
+    ) -> Result<Response<SetLogLevelResponse>, tonic::Status> {
+        let req = request.into_inner();
+        info!("gRPC: Appel de SetLogLevel reçu : {:?}", req);
+
+        let level = events::parse_level(&req.level).map_err(Status::invalid_argument)?;
+        let mut verbosity = self.event_verbosity.lock().await;
+        if let Err(e) = verbosity.set(level, req.sample_rate) {
+            error!("LOG_CONFIG map update error: {}", e);
+            return Err(Status::internal(format!("BPF map error: {}", e)));
+        }
+
+        let message = match verbosity.sample_rate() {
+            rate if rate > 1 => format!("Verbosité des événements : {} (1/{}).", events::level_name(level), rate),
+            _ => format!("Verbosité des événements : {}.", events::level_name(level)),
+        };
+        info!("📦 {}", message);
+        Ok(Response::new(SetLogLevelResponse { message }))
+    }
+
+    async fn get_stats(&self, _request: Request<Empty>) -> Result<Response<DatapathStats>, Status> {
+        // Appelée en boucle par `xdp-drop-cli stats --interval` : pas de journalisation
+        let totals = self.datapath_counters.lock().await.totals().map_err(|e| {
+            error!("DATAPATH_STATS map read error: {}", e);
+            Status::internal(format!("BPF map error: {}", e))
+        })?;
+        Ok(Response::new(DatapathStats {
+            timestamp_ns: kernel_monotonic_ns(),
+            passed: totals[DATAPATH_STAT_PASSED as usize],
+            dropped_rule: totals[DATAPATH_STAT_DROPPED_RULE as usize],
+            dropped_policy: totals[DATAPATH_STAT_DROPPED_POLICY as usize],
+            dropped_conntrack: totals[DATAPATH_STAT_DROPPED_CONNTRACK as usize],
+            dropped_fragment: totals[DATAPATH_STAT_DROPPED_FRAGMENT as usize],
+            dropped_invalid: totals[DATAPATH_STAT_DROPPED_INVALID as usize],
+            aborted: totals[DATAPATH_STAT_ABORTED as usize],
+            conntrack_insert_failed: totals[DATAPATH_STAT_CONNTRACK_INSERT_FAILED as usize],
+            events_lost: totals[DATAPATH_STAT_EVENTS_LOST as usize],
+        }))
+    }
+
+    type WatchEventsStream = ReceiverStream<Result<EventInfo, Status>>;
+
+    async fn watch_events(
+        &self,
+        request: Request<WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, tonic::Status> {
+        let req = request.into_inner();
+        info!("gRPC: Appel de WatchEvents reçu : {:?}", req);
+
+        let filter = watch_filter_from_proto(&req).map_err(Status::invalid_argument)?;
+        let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+        let events = self.event_bus.subscribe();
+        tokio::spawn(async move {
+            forward_events(events, filter, tx).await;
+            info!("gRPC: Fin de WatchEvents");
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn list_connections(
+        &self,
+        request: Request<ListConnectionsRequest>,
+    ) -> Result<Response<ListConnectionsResponse>, tonic::Status> {
+        let req = request.into_inner();
+        info!("gRPC: Appel de ListConnections reçu : {:?}", req);
+
+        let filter = conn_filter_from_proto(&req.filter.unwrap_or_default()).map_err(Status::invalid_argument)?;
+        let limit = match req.limit as usize {
+            0 => CONNECTIONS_DEFAULT_LIMIT,
+            limit => limit.min(CONNECTIONS_MAX_LIMIT),
+        };
+
+        let timeouts = self.ctt_timeouts.lock().await.clone();
+        let now_ns = kernel_monotonic_ns();
+        let mut entries = conntrack::list(&*self.ctt_map.lock().await, &filter, &timeouts, now_ns);
+        entries.extend(conntrack::list(&*self.ctt_v6_map.lock().await, &filter, &timeouts, now_ns));
+        // Ordre stable d'un appel à l'autre pour la pagination
+        entries.sort_by_key(|entry| entry.flow);
+
+        let total = entries.len() as u32;
+        let connections = entries.iter()
+            .skip(req.offset as usize)
+            .take(limit)
+            .map(conn_entry_to_proto)
+            .collect();
+        Ok(Response::new(ListConnectionsResponse { connections, total }))
+    }
+
+    async fn delete_connection(
+        &self,
+        request: Request<ConnectionTuple>,
+    ) -> Result<Response<DeleteConnectionResponse>, tonic::Status> {
+        let req = request.into_inner();
+        info!("gRPC: Appel de DeleteConnection reçu : {:?}", req);
+
+        let flow = flow_from_tuple(&req).map_err(Status::invalid_argument)?;
+        let removed = match flow.source {
+            IpAddr::V4(_) => conntrack::delete(&mut *self.ctt_map.lock().await, &flow),
+            IpAddr::V6(_) => conntrack::delete(&mut *self.ctt_v6_map.lock().await, &flow),
+        };
+        let removed = removed.map_err(|e| {
+            error!("CONN_TRACK_TABLE delete error: {}", e);
+            Status::internal(format!("BPF map error: {}", e))
+        })?;
+        let Some(removed) = removed else {
+            return Err(Status::not_found("Aucune entrée de suivi pour ce flux."));
+        };
+
+        let message = format!(
+            "Entrée de suivi {} {}:{} -> {}:{} retirée.",
+            events::protocol_name(removed.protocol), removed.source, removed.source_port, removed.dest, removed.dest_port
+        );
+        info!("🔪 {}", message);
+        Ok(Response::new(DeleteConnectionResponse { message }))
+    }
+
+    async fn flush_connections(
+        &self,
+        request: Request<ConnectionFilter>,
+    ) -> Result<Response<FlushConnectionsResponse>, tonic::Status> {
+        let req = request.into_inner();
+        info!("gRPC: Appel de FlushConnections reçu : {:?}", req);
+
+        let filter = conn_filter_from_proto(&req).map_err(Status::invalid_argument)?;
+        let removed_v4 = conntrack::remove_matching(&mut *self.ctt_map.lock().await, |flow, value| filter.matches(flow, value));
+        let removed_v6 = conntrack::remove_matching(&mut *self.ctt_v6_map.lock().await, |flow, value| filter.matches(flow, value));
+        let removed = removed_v4 + removed_v6;
+
+        let message = format!("{} entrée(s) de suivi retirée(s).", removed);
+        info!("🔪 {}", message);
+        Ok(Response::new(FlushConnectionsResponse { removed: removed as u32, message }))
+    }
+}
+
+
+// Report des compteurs de hits noyau (RULE_STATS) dans la table rules
+async fn flush_rule_counters(
+    db_client: &tokio_postgres::Client,
+    rule_counters: &tokio::sync::Mutex<RuleCounters>,
+) {
+    let pending = match rule_counters.lock().await.pending() {
+        Ok(pending) => pending,
+        Err(e) => {
+            warn!("📊 Lecture de RULE_STATS impossible : {}", e);
+            return;
+        }
+    };
+
+    for (rule_id, delta) in pending {
+        let result = db_client.execute(
+            "UPDATE rules SET usage_count = usage_count + $1, byte_count = byte_count + $2, last_hit = now() WHERE id = $3",
+            &[&(delta.packets as i64), &(delta.bytes as i64), &(rule_id as i32)],
+        ).await;
+        match result {
+            // Reporté (ou règle supprimée entre-temps) : ne plus le compter
+            Ok(_) => rule_counters.lock().await.mark_flushed(rule_id, delta),
+            // Non marqué : le delta sera retenté au prochain passage
+            Err(e) => warn!("📊 Report des compteurs de la règle ID {} impossible : {}", rule_id, e),
+        }
+    }
+}
+
+// Tâche de report périodique des compteurs
+async fn run_counters_flush_task(
+    db_client: Arc<tokio_postgres::Client>,
+    rule_counters: Arc<tokio::sync::Mutex<RuleCounters>>,
+) {
+    const FLUSH_INTERVAL_S: u64 = 10;
+
+    info!("📊 Tâche de report des compteurs démarrée (intervalle: {}s).", FLUSH_INTERVAL_S);
+    let mut interval_timer = interval(Duration::from_secs(FLUSH_INTERVAL_S));
+
+    loop {
+        interval_timer.tick().await;
+        flush_rule_counters(&db_client, &rule_counters).await;
+    }
 }
 
 
 // Tâche de nettoyage de la table de suivi des connexions
 async fn run_ctt_cleanup_task(
     ctt_map: Arc<tokio::sync::Mutex<AyaHashMap<MapData, ConnectionKey, ConnectionValue>>>,
+    ctt_v6_map: Arc<tokio::sync::Mutex<AyaHashMap<MapData, ConnectionKeyV6, ConnectionValue>>>,
+    ctt_timeouts: Arc<tokio::sync::Mutex<CttTimeouts>>,
 ) {
-    // Définir les timeouts (en nanosecondes)
-    const TCP_ESTABLISHED_TIMEOUT_NS: u64 = 300 * 1_000_000_000; // 5 minutes
-    const TCP_TRANSIENT_TIMEOUT_NS: u64 = 60 * 1_000_000_000; // 1 minute (SYN_SENT, SYN_RECEIVED)
-    const UDP_TIMEOUT_NS: u64 = 30 * 1_000_000_000; // 30 secondes
     const CLEANUP_INTERVAL_S: u64 = 10; // Exécuter le nettoyage toutes les 10 secondes
 
     info!("🧹 Tâche de nettoyage CTT démarrée (intervalle: {}s).", CLEANUP_INTERVAL_S);
@@ -276,141 +1272,35 @@ async fn run_ctt_cleanup_task(
 
     loop {
         interval_timer.tick().await;
-        info!("🧹 Exécution du nettoyage de la table de suivi des connexions...");
-
-        let mut ctt_map_guard = ctt_map.lock().await;
-        let mut keys_to_remove = Vec::new();
-        let mut inspected_count = 0;
-        let mut removed_count = 0;
-
-        // Il faut obtenir l'heure actuelle d'une manière compatible avec bpf_ktime_get_ns.
-        // Malheureusement, il n'y a pas d'appel direct bpf_ktime_get_ns depuis userspace.
-        // Une astuce est de lire une valeur de timestamp d'une map spéciale mise à jour par eBPF,
-        // ou plus simplement, on suppose que l'horloge du système userspace est raisonnablement
-        // synchronisée avec celle du kernel (ce qui est généralement le cas).
-        // Pour une vraie précision, il faudrait une map eBPF qui stocke juste le ktime actuel.
-        // Ici, on va utiliser `SystemTime` et le convertir, en sachant qu'il peut y avoir un léger décalage.
-        // La solution la plus robuste est de lire le ktime à partir d'un programme eBPF via une map.
-        // Pour simplifier, on va utiliser une approximation avec l'heure système.
-        // Le timestamp `bpf_ktime_get_ns` est un u64 représentant des nanosecondes monotones depuis le démarrage.
-        // On va devoir faire attention si on compare directement.
-        // Pour cet exemple, nous allons supposer que nous pouvons obtenir un timestamp comparable.
-        // Une approche plus simple pour un POC est de se baser sur les durées et non les timestamps absolus.
-        // Mais les timestamps sont déjà dans la map...
-        //
-        // Solution pragmatique: on ne peut pas obtenir bpf_ktime_get_ns() directement.
-        // On va donc retirer les entrées si last_seen_ns est "trop vieux" par rapport à la dernière fois qu'on a regardé.
-        // Mais c'est incorrect. On doit comparer à "maintenant".
-        //
-        // Alternative: Itérer les clés, pour chaque clé, lire la valeur. C'est ce que fait `iter()`.
-        // Le `last_seen_ns` est celui écrit par eBPF.
-        // On a besoin d'un "maintenant" du point de vue de `bpf_ktime_get_ns`.
-        // C'est le point délicat.
-        //
-        // **Simplification pour cet exemple :** On va juste logguer les entrées.
-        // Pour une VRAIE suppression, il faudrait un moyen fiable d'obtenir le ktime_ns actuel
-        // ou que le programme eBPF lui-même marque les entrées comme périmées,
-        // et que userspace les supprime.
-        // Ou, alternativement, la map `LruHashMap` d'Aya gère l'expiration, mais elle est moins flexible pour les états.
-        //
-        // Pour une VRAIE implémentation: utiliser une map eBPF de type PERF_EVENT_ARRAY
-        // pour que eBPF notifie userspace quand une entrée *devrait* être créée avec son timestamp,
-        // et userspace maintient sa propre CTT avec des `Instant` Rust, et envoie des commandes
-        // à eBPF pour supprimer des clés. C'est BEAUCOUP plus complexe.
-        //
-        // **Solution de contournement pour la démo :** On ne peut pas avoir bpf_ktime_get_ns() en user space.
-        // Donc, on ne peut pas comparer directement `last_seen_ns` avec un "maintenant" équivalent.
-        // Ce que font certains outils (comme conntrack-tools) est de lire les entrées et de calculer
-        // une "durée de vie restante" basée sur le timeout connu pour cet état et le `last_seen_ns`.
-        // Mais pour cela, il faut quand même un point de référence.
-        //
-        // **Option la plus simple (mais moins précise) pour la démo :**
-        // Lors du nettoyage, lire toutes les entrées.
-        // Si une entrée n'a pas été mise à jour depuis X cycles de nettoyage, la considérer comme vieille.
-        // Ceci est très approximatif.
-        //
-        // **La meilleure approche avec les maps eBPF actuelles serait:**
-        // En eBPF: si une entrée est trop vieille LORS D'UN ACCES, la supprimer.
-        // Mais on veut un nettoyage proactif.
-        //
-        // Ok, compromis pour la démo : on va supposer que la différence de temps entre le dernier
-        // `bpf_ktime_get_ns()` stocké et un `bpf_ktime_get_ns()` qu'on *pourrait* lire maintenant
-        // est équivalente à la durée mesurée par `Instant::now()`. C'est une approximation.
-        // Il est plus sûr de lire toutes les entrées et de les supprimer si elles semblent vieilles.
-        // Pour ce faire, il faut itérer sur les clés, puis faire un `get` pour chaque clé.
-
-        // On itère sur les clés d'abord, puis on récupère les valeurs pour éviter les problèmes de borrowing avec le MutexGuard
-        let current_keys: Vec<ConnectionKey> = ctt_map_guard.keys().collect::<Result<_, _>>().unwrap_or_default();
-
-        for key in current_keys {
-            inspected_count += 1;
-            if let Ok(Some(value)) = ctt_map_guard.get(&key, 0) { // Le flag 0 est standard
-                let conn_value: ConnectionValue = value; // Aya dérive Pod, donc c'est une copie.
-
-                // On ne peut PAS appeler bpf_ktime_get_ns() ici.
-                // On va devoir utiliser une heuristique ou accepter l'imprécision.
-                // Le plus simple: si une règle est supprimée en userspace, elle le sera aussi en eBPF.
-                // Les timeouts ici sont pour les connexions qui se terminent "naturellement" ou sont inactives.
-
-                // *****************************************************************************
-                // DÉBUT SECTION CRITIQUE POUR LE TIMESTAMP
-                // Pour une vraie implémentation, il faudrait un mécanisme pour que
-                // eBPF fournisse un timestamp "actuel" à userspace, ou que userspace
-                // ait une connaissance des timeouts et supprime si last_seen_ns est "vieux".
-                // Ici, on va juste imprimer, car on n'a pas de `bpf_ktime_get_ns()` fiable en userspace.
-                // log::trace!("CTT Entry: {:?} -> {:?} (last_seen_ns: {})", key, conn_value.state, conn_value.last_seen_ns);
-                //
-                // Alternative (pas implémentée ici pour garder le code plus simple pour le moment):
-                // 1. Créez une map eBPF séparée (par exemple, `CURRENT_KTIME_MAP`) de type `Array` avec 1 seule entrée.
-                // 2. Dans votre programme eBPF, au début de `try_xdp_firewall`, écrivez `bpf_ktime_get_ns()` dans cette map.
-                // 3. En userspace, lisez cette map pour obtenir un `current_ktime_ns` quasi-actuel du kernel.
-                // 4. Comparez `conn_value.last_seen_ns` avec ce `current_ktime_ns`.
-                // *****************************************************************************
-
-                // Pour la démo, on va juste simuler une expiration si on ne voit pas de mise à jour
-                // sur un très grand nombre de cycles (pas idéal).
-                // OU, on se fie au fait que le kernel va réutiliser les entrées de map si elle est pleine (pas idéal non plus).
-
-                // Simplification extrême pour la démo : on ne supprime RIEN ici pour éviter des faux positifs
-                // dus à la problématique du timestamp. On va juste logguer.
-                // Dans un vrai système, cette partie est cruciale et doit être correcte.
-
-                // Exemple de logique si on avait current_ktime_ns:
-                /*
-                let current_ktime_ns = get_current_kernel_time_somehow().await; // Fonction hypothétique
-                let age_ns = current_ktime_ns.saturating_sub(conn_value.last_seen_ns);
-                let timeout_ns = match conn_value.state {
-                    ConnStateVariant::Tcp(TcpState::Established) => TCP_ESTABLISHED_TIMEOUT_NS,
-                    ConnStateVariant::Tcp(_) => TCP_TRANSIENT_TIMEOUT_NS,
-                    ConnStateVariant::Udp(_) => UDP_TIMEOUT_NS,
-                };
-                if age_ns > timeout_ns {
-                    keys_to_remove.push(key);
-                }
-                */
-            }
-        }
-        // Pour la démo, on va logguer le nombre d'entrées, mais pas supprimer activement
-        // à cause du problème de timestamp.
-        let map_size = ctt_map_guard.iter().count();
-        info!("🧹 CTT: {} entrées inspectées. {} entrées actuellement dans la map. (Nettoyage actif désactivé pour la démo à cause du timestamp).", inspected_count, map_size);
+        // Copie des timeouts en vigueur : un changement via gRPC s'applique au passage suivant
+        let timeouts = ctt_timeouts.lock().await.clone();
 
+        // Même horloge que bpf_ktime_get_ns() côté noyau
+        let now_ns = kernel_monotonic_ns();
+        let (inspected_v4, removed_v4) = conntrack::expire(&mut *ctt_map.lock().await, &timeouts, now_ns);
+        let (inspected_v6, removed_v6) = conntrack::expire(&mut *ctt_v6_map.lock().await, &timeouts, now_ns);
 
-        // Si on avait des clés à supprimer:
-        // for key_to_remove in keys_to_remove {
-        //     if let Err(e) = ctt_map_guard.remove(&key_to_remove) {
-        //         warn!("🧹 Erreur lors de la suppression de la clé CTT {:?}: {}", key_to_remove, e);
-        //     } else {
-        //         removed_count += 1;
-        //     }
-        // }
-        // if removed_count > 0 {
-        //     info!("🧹 CTT: {} entrées supprimées.", removed_count);
-        // }
+        info!("🧹 CTT: {} entrées IPv4 et {} entrées IPv6 inspectées, {} et {} expirées supprimées.",
+            inspected_v4, inspected_v6, removed_v4, removed_v6);
     }
 }
 
 
+// Charge tc_egress et l'attache en sortie de l'interface
+fn attach_tc_egress(bpf: &mut Bpf, iface: &str) -> Result<(), anyhow::Error> {
+    // La qdisc clsact peut déjà exister (redémarrage du daemon) : l'erreur est ignorée.
+    if let Err(e) = tc::qdisc_add_clsact(iface) {
+        warn!("clsact qdisc on {}: {}", iface, e);
+    }
+    let egress: &mut SchedClassifier = bpf.program_mut("tc_egress")
+        .ok_or_else(|| anyhow::anyhow!("eBPF program 'tc_egress' not found"))?
+        .try_into().context("Program conversion to SchedClassifier error")?;
+    egress.load().context("TC egress program load error")?;
+    egress.attach(iface, TcAttachType::Egress)
+        .context(format!("TC egress attach error to {}", iface))?;
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let opt = Opt::parse();
@@ -432,80 +1322,165 @@ async fn main() -> Result<(), anyhow::Error> {
         .context(format!("XDP attach error to {}", opt.iface))?;
     info!("eBPF program loaded and attached to {}.", opt.iface);
 
-    // Map pour les règles statiques
-    let blocklist_bpf_map: AyaHashMap<_, IpPort, u32> =
-        AyaHashMap::try_from(bpf.map_mut("BLOCKLIST").context("BLOCKLIST map not found")?)?;
-    let blocklist_map_arc = Arc::new(tokio::sync::Mutex::new(blocklist_bpf_map));
+    // Suivi des flux sortants : partage CONN_TRACK_TABLE(_V6) avec xdp_firewall pour que
+    // les réponses aux connexions ouvertes par l'hôte soient acceptées.
+    // Facultatif (pas de clsact sur certaines interfaces) : sans lui, ces réponses exigent une règle.
+    let egress_tracking = match attach_tc_egress(&mut bpf, &opt.iface) {
+        Ok(()) => {
+            info!("TC egress tracker attached to {}.", opt.iface);
+            true
+        }
+        Err(e) => {
+            warn!("⚠️ TC egress tracker not attached to {}: {:#}. Running without egress tracking: \
+                   replies to connections opened by this host need an allow rule.", opt.iface, e);
+            false
+        }
+    };
+
+    // Tries LPM pour les règles statiques (IPv4 et IPv6), en deux jeux basculés par RULES_GENERATION
+    let blocklists = Blocklists::new(
+        RuleBank::new(
+            LpmTrie::try_from(bpf.take_map("SRC_PREFIXES").context("SRC_PREFIXES map not found")?)?,
+            LpmTrie::try_from(bpf.take_map("SRC_PREFIXES_V6").context("SRC_PREFIXES_V6 map not found")?)?,
+            LpmTrie::try_from(bpf.take_map("BLOCKLIST").context("BLOCKLIST map not found")?)?,
+            LpmTrie::try_from(bpf.take_map("BLOCKLIST_V6").context("BLOCKLIST_V6 map not found")?)?,
+            AyaHashMap::try_from(bpf.take_map("RULE_SET_LINKS").context("RULE_SET_LINKS map not found")?)?,
+        ),
+        RuleBank::new(
+            LpmTrie::try_from(bpf.take_map("SRC_PREFIXES_B").context("SRC_PREFIXES_B map not found")?)?,
+            LpmTrie::try_from(bpf.take_map("SRC_PREFIXES_V6_B").context("SRC_PREFIXES_V6_B map not found")?)?,
+            LpmTrie::try_from(bpf.take_map("BLOCKLIST_B").context("BLOCKLIST_B map not found")?)?,
+            LpmTrie::try_from(bpf.take_map("BLOCKLIST_V6_B").context("BLOCKLIST_V6_B map not found")?)?,
+            AyaHashMap::try_from(bpf.take_map("RULE_SET_LINKS_B").context("RULE_SET_LINKS_B map not found")?)?,
+        ),
+        Array::try_from(bpf.take_map("RULES_GENERATION").context("RULES_GENERATION map not found")?)?,
+    );
+    let blocklist_map_arc = Arc::new(tokio::sync::Mutex::new(blocklists));
+
+    // Compteurs de hits par règle
+    let rule_stats_map: PerCpuHashMap<_, u32, RuleStats> =
+        PerCpuHashMap::try_from(bpf.take_map("RULE_STATS").context("RULE_STATS map not found")?)?;
+    let rule_counters_arc = Arc::new(tokio::sync::Mutex::new(RuleCounters::new(rule_stats_map)));
+
+    // Compteurs de fragments IPv4 / IPv6
+    let fragment_stats_map: PerCpuArray<_, u64> =
+        PerCpuArray::try_from(bpf.take_map("FRAGMENT_STATS").context("FRAGMENT_STATS map not found")?)?;
+    let fragment_counters_arc = Arc::new(tokio::sync::Mutex::new(FragmentCounters::new(fragment_stats_map)));
+
+    // Compteurs de verdicts du datapath
+    let datapath_stats_map: PerCpuArray<_, u64> =
+        PerCpuArray::try_from(bpf.take_map("DATAPATH_STATS").context("DATAPATH_STATS map not found")?)?;
+    let datapath_counters_arc = Arc::new(tokio::sync::Mutex::new(DatapathCounters::new(datapath_stats_map)));
 
 
     // NOUVELLE MAP: Table de suivi des connexions
     let ctt_bpf_map: AyaHashMap<_, ConnectionKey, ConnectionValue> =
-        AyaHashMap::try_from(bpf.map_mut("CONN_TRACK_TABLE").context("CONN_TRACK_TABLE map not found")?)?;
+        AyaHashMap::try_from(bpf.take_map("CONN_TRACK_TABLE").context("CONN_TRACK_TABLE map not found")?)?;
     let ctt_map_arc = Arc::new(tokio::sync::Mutex::new(ctt_bpf_map));
+    let ctt_v6_bpf_map: AyaHashMap<_, ConnectionKeyV6, ConnectionValue> =
+        AyaHashMap::try_from(bpf.take_map("CONN_TRACK_TABLE_V6").context("CONN_TRACK_TABLE_V6 map not found")?)?;
+    let ctt_v6_map_arc = Arc::new(tokio::sync::Mutex::new(ctt_v6_bpf_map));
 
 
-    let (pg_client_raw, connection) = tokio_postgres::connect(
-        "host=localhost user=postgres password=postgres dbname=firewall",
-        tokio_postgres::NoTls,
-    ).await.context("PostgreSQL connection error")?;
+    let (pg_client_raw, connection) = tokio_postgres::connect(DB_CONFIG, tokio_postgres::NoTls)
+        .await.context("PostgreSQL connection error")?;
     info!("Connecté à PostgreSQL.");
     let pg_client = Arc::new(pg_client_raw);
     tokio::spawn(async move {
         if let Err(e) = connection.await { eprintln!("PostgreSQL background connection error: {e}"); }
     });
+    // Seconde connexion pour ApplyRuleset : les requêtes partagées ne doivent pas tomber dans sa transaction
+    let (ruleset_db_raw, ruleset_connection) = tokio_postgres::connect(DB_CONFIG, tokio_postgres::NoTls)
+        .await.context("PostgreSQL connection error (ruleset)")?;
+    let ruleset_db = Arc::new(tokio::sync::Mutex::new(ruleset_db_raw));
+    tokio::spawn(async move {
+        if let Err(e) = ruleset_connection.await { eprintln!("PostgreSQL background connection error (ruleset): {e}"); }
+    });
+
+    ensure_schema(&pg_client).await?;
 
     info!("📋 Chargement des règles initiales (BLOCKLIST) depuis la DB...");
-    let initial_rules_from_db = pg_client.query( /* ... */ "SELECT id, source_ip, dest_ip, source_port, dest_port, action, protocol, usage_count FROM rules", &[]).await
+    let initial_rules_from_db = pg_client.query( /* ... */ "SELECT id, source_ip, dest_ip, source_port, source_port_end, dest_port, dest_port_end, action, protocol, priority, icmp_type, icmp_code, vlan_id, usage_count FROM rules", &[]).await
         .context("Initial rule loading error")?;
 
-    const ACTION_DENY: u32 = 1;
-    const ACTION_ALLOW: u32 = 2; // Rappel: pour initier des connexions
-
-    { // Bloc pour le MutexGuard de blocklist_map_arc
-        let mut blocklist_map_guard = blocklist_map_arc.lock().await;
-        for row in initial_rules_from_db {
-            let id: i32 = row.get("id");
-            let source_ip: String = row.get("source_ip");
-            let dest_ip: String = row.get("dest_ip");
-            let _source_port: Option<i32> = row.get("source_port"); // Non utilisé dans la clé BLOCKLIST actuelle
-            let dest_port_opt: Option<i32> = row.get("dest_port");
-            let action: String = row.get("action");
-            let _protocol: Option<String> = row.get("protocol"); // Non utilisé dans la clé BLOCKLIST actuelle
-
-            let ip_addr = source_ip.parse::<std::net::Ipv4Addr>().context(format!("Invalid source IP for rule {id}"))?;
-            let ip_dest_addr = dest_ip.parse::<std::net::Ipv4Addr>().context(format!("Invalid dest IP for rule {id}"))?;
-            let port_val = dest_port_opt.unwrap_or(0) as u16; // 0 pour wildcard
-
-            let key = IpPort {
-                addr: u32::from(ip_addr).to_be(),
-                addr_dest: u32::from(ip_dest_addr).to_be(),
-                port: port_val.to_be(), // Port en network byte order
-                _pad: 0,
-            };
-
-            let action_value = match action.to_lowercase().as_str() {
-                "deny" => ACTION_DENY,
-                "allow" => ACTION_ALLOW,
-                _ => { warn!("Unknown action '{}' for rule #{id}, ignored.", action); continue; }
-            };
-            blocklist_map_guard.insert(key, action_value, 0).context(format!("BPF insert error for rule #{id}"))?;
-            info!("🛡️ BLOCKLIST Rule #{id}: {} -> {}:{} | Action: {}", source_ip, dest_ip, port_val, action);
+    let mut initial_rules = Vec::new();
+    for row in initial_rules_from_db {
+        let id: i32 = row.get("id");
+        let action: String = row.get("action");
+        match rule_from_row(&row) {
+            Ok(rule) => {
+                info!("🛡️ BLOCKLIST Rule #{id}: {}:{} -> {}:{} | Proto: {} | Action: {} | Prio: {}",
+                    rule.source, rule.source_port, rule.dest, rule.dest_port, rule.protocol_name(), action, rule.priority);
+                initial_rules.push(rule);
+            }
+            Err(e) => warn!("Rule #{id} ignored: {}", e),
         }
     }
+    let rejected_rules = blocklist_map_arc.lock().await.load(initial_rules).context("BPF BLOCKLIST load error")?;
+    for (rule, e) in rejected_rules {
+        // Restée en base, la règle apparaît non appliquée dans ListRules
+        warn!("Rule #{} not loaded in kernel: {}", rule.id, e);
+    }
 
 
+    let ctt_timeouts = load_ctt_timeouts(&pg_client).await?;
+    for (target, ns) in ctt_timeouts.entries() {
+        let (protocol, state, port) = target.names();
+        info!("⏱️ Timeout CTT {} {} port {}: {}s", protocol, state, port, ns / 1_000_000_000);
+    }
+    let ctt_timeouts_arc = Arc::new(tokio::sync::Mutex::new(ctt_timeouts));
+
+    let mut default_policies = DefaultPolicies::new(
+        Array::try_from(bpf.take_map("DEFAULT_POLICY").context("DEFAULT_POLICY map not found")?)?,
+    );
+    load_default_policies(&pg_client, &mut default_policies).await?;
+    for (class, policy) in default_policies.entries() {
+        info!("🚦 Politique par défaut {}: {}", class, policy);
+    }
+    let default_policies_arc = Arc::new(tokio::sync::Mutex::new(default_policies));
+
     // Démarrer la tâche de nettoyage CTT
-    let ctt_cleanup_task_handle = tokio::spawn(run_ctt_cleanup_task(Arc::clone(&ctt_map_arc)));
+    let ctt_cleanup_task_handle = tokio::spawn(run_ctt_cleanup_task(Arc::clone(&ctt_map_arc), Arc::clone(&ctt_v6_map_arc), Arc::clone(&ctt_timeouts_arc)));
+    let counters_flush_task_handle = tokio::spawn(run_counters_flush_task(Arc::clone(&pg_client), Arc::clone(&rule_counters_arc)));
+
+    // Événements paquet : lecture du ring buffer EVENTS, diffusés aux abonnés (journal)
+    let events_ring = RingBuf::try_from(bpf.take_map("EVENTS").context("EVENTS map not found")?)?;
+    let event_verbosity = EventVerbosity::new(
+        Array::try_from(bpf.take_map("LOG_CONFIG").context("LOG_CONFIG map not found")?)?,
+    ).context("LOG_CONFIG map init error")?;
+    let event_verbosity_arc = Arc::new(tokio::sync::Mutex::new(event_verbosity));
+    let event_bus = EventBus::new();
+    let event_log_task_handle = tokio::spawn(run_event_log_task(event_bus.subscribe()));
+    let event_consumer_task_handle = tokio::spawn({
+        let event_bus = event_bus.clone();
+        async move {
+            if let Err(e) = run_event_consumer(events_ring, event_bus).await {
+                error!("📭 Lecture des événements interrompue : {:#}", e);
+            }
+        }
+    });
 
 
     let grpc_addr = "[::1]:50051".parse().context("Invalid gRPC address")?;
     let firewall_service = MyFirewallService {
         db_client: Arc::clone(&pg_client),
+        ruleset_db,
         bpf_blocklist_map: Arc::clone(&blocklist_map_arc), // Passer le handle de la map
-        // bpf_ctt_map: Arc::clone(&ctt_map_arc), // Si gRPC doit interagir avec CTT
+        rule_counters: Arc::clone(&rule_counters_arc),
+        ctt_timeouts: Arc::clone(&ctt_timeouts_arc),
+        default_policies: Arc::clone(&default_policies_arc),
+        fragment_counters: Arc::clone(&fragment_counters_arc),
+        datapath_counters: Arc::clone(&datapath_counters_arc),
+        event_verbosity: Arc::clone(&event_verbosity_arc),
+        event_bus: event_bus.clone(),
+        ctt_map: Arc::clone(&ctt_map_arc),
+        ctt_v6_map: Arc::clone(&ctt_v6_map_arc),
+        egress_tracking,
     };
     info!("Service Firewall gRPC en cours de création...");
+    let grpc_metrics = GrpcMetrics::default();
     let grpc_server_future = Server::builder()
+        .layer(GrpcMetricsLayer::new(grpc_metrics.clone()))
         .add_service(FirewallServiceServer::new(firewall_service))
         .serve(grpc_addr);
 
@@ -514,13 +1489,109 @@ async fn main() -> Result<(), anyhow::Error> {
         if let Err(e) = grpc_server_future.await { eprintln!("Erreur serveur gRPC : {e}"); }
     });
 
+    // Endpoint Prometheus, seulement si une adresse d'écoute est fournie
+    let metrics_task_handle = opt.metrics_addr.map(|metrics_addr| {
+        let sources = Arc::new(MetricsSources {
+            db_client: Arc::clone(&pg_client),
+            blocklists: Arc::clone(&blocklist_map_arc),
+            rule_counters: Arc::clone(&rule_counters_arc),
+            datapath_counters: Arc::clone(&datapath_counters_arc),
+            fragment_counters: Arc::clone(&fragment_counters_arc),
+            ctt_v4: Arc::clone(&ctt_map_arc),
+            ctt_v6: Arc::clone(&ctt_v6_map_arc),
+            grpc: grpc_metrics,
+        });
+        tokio::spawn(async move {
+            if let Err(e) = run_metrics_server(metrics_addr, sources).await {
+                error!("📈 Endpoint Prometheus arrêté : {:#}", e);
+            }
+        })
+    });
+
     info!("🔥 Le firewall stateful est en marche !");
     info!("⏳ Appuyez sur Ctrl-C pour arrêter...");
     signal::ctrl_c().await.context("Ctrl-C signal error")?;
     info!("🛑 Arrêt du firewall...");
 
     ctt_cleanup_task_handle.abort(); // Arrêter la tâche de nettoyage proprement
+    counters_flush_task_handle.abort();
+    event_consumer_task_handle.abort();
+    event_log_task_handle.abort();
+    if let Some(handle) = metrics_task_handle {
+        handle.abort();
+    }
+    // Dernier report pour ne pas perdre les hits depuis le dernier passage
+    flush_rule_counters(&pg_client, &rule_counters_arc).await;
     // Attendre un peu si nécessaire : tokio::time::sleep(Duration::from_millis(100)).await;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(id: i32, source: &str, dest: &str, dest_port: &str, action: &str) -> Rule {
+        Rule::parse(id, source, dest, PortRange::ANY, PortRange::parse(dest_port).unwrap(), "tcp", action).unwrap()
+    }
+
+    fn ids(rules: &[Rule]) -> Vec<i32> {
+        rules.iter().map(|rule| rule.id).collect()
+    }
+
+    #[test]
+    fn diff_ruleset_classifies_changes() {
+        let current = vec![
+            (1, Some(rule(1, "10.0.0.0/8", "*", "22", "allow"))),
+            (2, Some(rule(2, "*", "*", "23", "deny"))),
+            (3, Some(rule(3, "*", "*", "80", "allow"))),
+            (4, None), // Ligne illisible
+            (5, Some(rule(5, "*", "*", "443", "allow"))),
+        ];
+        let desired = vec![
+            (Some(1), rule(1, "10.0.0.0/8", "*", "22", "allow")),
+            (Some(2), rule(2, "*", "*", "23", "allow")),
+            (Some(4), rule(4, "*", "*", "25", "deny")),
+            // Sans ID, identique à la règle 3 : reprise
+            (None, rule(0, "*", "*", "80", "allow")),
+            (None, rule(0, "*", "*", "8080", "allow")),
+        ];
+        let diff = diff_ruleset(current, desired).unwrap();
+        assert_eq!(ids(&diff.unchanged), [1, 3]);
+        assert_eq!(diff.updated.iter().map(|(before, after)| (before.as_ref().map(|r| r.id), after.id)).collect::<Vec<_>>(), [(Some(2), 2), (None, 4)]);
+        assert_eq!(diff.created.len(), 1);
+        assert_eq!(diff.created[0].id, 0);
+        assert_eq!(diff.created[0].dest_port, PortRange::parse("8080").unwrap());
+        assert_eq!(diff.deleted.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [5]);
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn diff_ruleset_keeps_identical_ruleset() {
+        let current = vec![(7, Some(rule(7, "*", "*", "22", "deny"))), (8, Some(rule(8, "*", "*", "22", "deny")))];
+        // Deux entrées sans ID identiques reprennent chacune une règle
+        let desired = vec![(None, rule(0, "*", "*", "22", "deny")), (None, rule(0, "*", "*", "22", "deny"))];
+        let diff = diff_ruleset(current, desired).unwrap();
+        assert!(diff.is_empty());
+        assert_eq!(ids(&diff.unchanged), [7, 8]);
+    }
+
+    #[test]
+    fn diff_ruleset_id_takes_precedence_over_anonymous_match() {
+        let current = vec![(1, Some(rule(1, "*", "*", "22", "deny")))];
+        // L'entrée sans ID ne doit pas prendre la règle citée par ID plus loin
+        let desired = vec![(None, rule(0, "*", "*", "22", "deny")), (Some(1), rule(1, "*", "*", "22", "allow"))];
+        let diff = diff_ruleset(current, desired).unwrap();
+        assert_eq!(diff.updated.len(), 1);
+        assert_eq!(diff.created.len(), 1);
+        assert!(diff.deleted.is_empty());
+    }
+
+    #[test]
+    fn diff_ruleset_rejects_unknown_or_repeated_ids() {
+        let current = vec![(1, Some(rule(1, "*", "*", "22", "deny")))];
+        assert!(diff_ruleset(current.clone(), vec![(Some(9), rule(9, "*", "*", "22", "deny"))]).is_err());
+        let repeated = vec![(Some(1), rule(1, "*", "*", "22", "deny")), (Some(1), rule(1, "*", "*", "23", "deny"))];
+        assert!(diff_ruleset(current, repeated).is_err());
+    }
+}
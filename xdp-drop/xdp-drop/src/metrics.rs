@@ -0,0 +1,312 @@
+// Endpoint Prometheus (/metrics).
+//
+// Optionnel : servi seulement si le daemon est lancé avec --metrics-addr. Les valeurs sont lues
+// à chaque scrape (maps noyau, jeu de règles chargé, base) ; seuls les appels gRPC sont comptés
+// par le daemon, via `GrpcMetricsLayer` posée devant le service. Les compteurs noyau repartent
+// de zéro au chargement du programme XDP, ce que Prometheus traite comme un reset.
+
+use anyhow::Context;
+use aya::maps::{HashMap as AyaHashMap, MapData};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::{Display, Write};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use xdp_drop_common::{
+    ConnectionKey, ConnectionKeyV6, ConnectionValue, BLOCKLIST_MAX_ENTRIES, CONN_TRACK_MAX_ENTRIES,
+    DATAPATH_STAT_ABORTED, DATAPATH_STAT_CONNTRACK_INSERT_FAILED, DATAPATH_STAT_DROPPED_CONNTRACK,
+    DATAPATH_STAT_DROPPED_FRAGMENT, DATAPATH_STAT_DROPPED_INVALID, DATAPATH_STAT_DROPPED_POLICY,
+    DATAPATH_STAT_DROPPED_RULE, DATAPATH_STAT_EVENTS_LOST, DATAPATH_STAT_PASSED, FRAG_STAT_FIRST,
+    FRAG_STAT_FOLLOWED, FRAG_STAT_ORPHAN, FRAG_STAT_OVERLAP, FRAG_STAT_TINY,
+};
+
+use crate::counters::{DatapathCounters, FragmentCounters, RuleCounters};
+use crate::rules::Blocklists;
+
+// Bornes (en secondes) de l'histogramme de durée des appels gRPC
+const GRPC_LATENCY_BUCKETS_S: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+// Au-delà, la base est considérée indisponible
+const DB_HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+struct GrpcMethodStats {
+    calls: BTreeMap<String, u64>, // Par code de statut gRPC
+    buckets: [u64; GRPC_LATENCY_BUCKETS_S.len()], // Non cumulés ; au-delà de la dernière borne : count seulement
+    count: u64,
+    sum_s: f64,
+}
+
+// Appels gRPC par méthode, alimentés par GrpcMetricsLayer
+#[derive(Clone, Default)]
+pub struct GrpcMetrics {
+    methods: Arc<std::sync::Mutex<BTreeMap<String, GrpcMethodStats>>>,
+}
+
+impl GrpcMetrics {
+    fn observe(&self, method: String, code: tonic::Code, elapsed: Duration) {
+        let elapsed_s = elapsed.as_secs_f64();
+        let mut methods = self.methods.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = methods.entry(method).or_default();
+        *stats.calls.entry(format!("{:?}", code)).or_default() += 1;
+        if let Some(bucket) = GRPC_LATENCY_BUCKETS_S.iter().position(|le| elapsed_s <= *le) {
+            stats.buckets[bucket] += 1;
+        }
+        stats.count += 1;
+        stats.sum_s += elapsed_s;
+    }
+
+    fn render(&self, out: &mut Exposition) {
+        let methods = self.methods.lock().unwrap_or_else(|e| e.into_inner());
+        out.header("xdp_drop_grpc_requests_total", "counter", "Appels gRPC par méthode et code de statut");
+        for (method, stats) in methods.iter() {
+            for (code, calls) in &stats.calls {
+                out.sample("xdp_drop_grpc_requests_total", &[("method", method), ("code", code)], calls);
+            }
+        }
+        out.header("xdp_drop_grpc_request_duration_seconds", "histogram", "Durée des appels gRPC");
+        for (method, stats) in methods.iter() {
+            let mut cumulative = 0;
+            for (le, in_bucket) in GRPC_LATENCY_BUCKETS_S.iter().zip(stats.buckets) {
+                cumulative += in_bucket;
+                out.sample("xdp_drop_grpc_request_duration_seconds_bucket", &[("method", method), ("le", &le.to_string())], cumulative);
+            }
+            out.sample("xdp_drop_grpc_request_duration_seconds_bucket", &[("method", method), ("le", "+Inf")], stats.count);
+            out.sample("xdp_drop_grpc_request_duration_seconds_sum", &[("method", method)], stats.sum_s);
+            out.sample("xdp_drop_grpc_request_duration_seconds_count", &[("method", method)], stats.count);
+        }
+    }
+}
+
+// Couche tower comptant chaque appel gRPC (méthode, code de statut, durée)
+#[derive(Clone)]
+pub struct GrpcMetricsLayer {
+    metrics: GrpcMetrics,
+}
+
+impl GrpcMetricsLayer {
+    pub fn new(metrics: GrpcMetrics) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> tower::Layer<S> for GrpcMetricsLayer {
+    type Service = GrpcMetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcMetricsService { inner, metrics: self.metrics.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcMetricsService<S> {
+    inner: S,
+    metrics: GrpcMetrics,
+}
+
+impl<S, ReqBody, ResBody> tower::Service<http::Request<ReqBody>> for GrpcMetricsService<S>
+where
+    S: tower::Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        // "/firewall.FirewallService/GetStatus" -> "GetStatus"
+        let method = request.uri().path().rsplit('/').next().unwrap_or_default().to_string();
+        let metrics = self.metrics.clone();
+        let started = Instant::now();
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await;
+            // Un appel en erreur porte son statut dans les en-têtes ; un succès, dans les trailers
+            let code = match &response {
+                Ok(response) => response.headers().get("grpc-status")
+                    .map_or(tonic::Code::Ok, |status| tonic::Code::from_bytes(status.as_bytes())),
+                Err(_) => tonic::Code::Unknown,
+            };
+            metrics.observe(method, code, started.elapsed());
+            response
+        })
+    }
+}
+
+// Tout ce qu'un scrape lit, partagé avec le service gRPC
+pub struct MetricsSources {
+    pub db_client: Arc<tokio_postgres::Client>,
+    pub blocklists: Arc<Mutex<Blocklists>>,
+    pub rule_counters: Arc<Mutex<RuleCounters>>,
+    pub datapath_counters: Arc<Mutex<DatapathCounters>>,
+    pub fragment_counters: Arc<Mutex<FragmentCounters>>,
+    pub ctt_v4: Arc<Mutex<AyaHashMap<MapData, ConnectionKey, ConnectionValue>>>,
+    pub ctt_v6: Arc<Mutex<AyaHashMap<MapData, ConnectionKeyV6, ConnectionValue>>>,
+    pub grpc: GrpcMetrics,
+}
+
+impl MetricsSources {
+    async fn render(&self) -> String {
+        let mut out = Exposition::default();
+
+        match self.datapath_counters.lock().await.totals() {
+            Ok(totals) => {
+                out.header("xdp_drop_packets_passed_total", "counter", "Paquets acceptés par le programme XDP");
+                out.sample("xdp_drop_packets_passed_total", &[], totals[DATAPATH_STAT_PASSED as usize]);
+                out.header("xdp_drop_packets_dropped_total", "counter", "Paquets rejetés par le programme XDP, par raison");
+                for (reason, stat) in [
+                    ("rule", DATAPATH_STAT_DROPPED_RULE),
+                    ("policy", DATAPATH_STAT_DROPPED_POLICY),
+                    ("conntrack", DATAPATH_STAT_DROPPED_CONNTRACK),
+                    ("fragment", DATAPATH_STAT_DROPPED_FRAGMENT),
+                    ("invalid", DATAPATH_STAT_DROPPED_INVALID),
+                ] {
+                    out.sample("xdp_drop_packets_dropped_total", &[("reason", reason)], totals[stat as usize]);
+                }
+                out.header("xdp_drop_packets_aborted_total", "counter", "Paquets tronqués ou en échec d'accès à une map (XDP_ABORTED)");
+                out.sample("xdp_drop_packets_aborted_total", &[], totals[DATAPATH_STAT_ABORTED as usize]);
+                out.header("xdp_drop_conntrack_insert_failures_total", "counter", "Insertions refusées par la table de suivi (pleine)");
+                out.sample("xdp_drop_conntrack_insert_failures_total", &[], totals[DATAPATH_STAT_CONNTRACK_INSERT_FAILED as usize]);
+                out.header("xdp_drop_events_lost_total", "counter", "Événements paquet perdus (limite de débit ou ring buffer plein)");
+                out.sample("xdp_drop_events_lost_total", &[], totals[DATAPATH_STAT_EVENTS_LOST as usize]);
+            }
+            Err(e) => warn!("📈 Lecture de DATAPATH_STATS impossible : {}", e),
+        }
+
+        match self.fragment_counters.lock().await.totals() {
+            Ok(totals) => {
+                out.header("xdp_drop_fragments_total", "counter", "Fragments IPv4 / IPv6 vus par le programme XDP");
+                for (kind, stat) in [
+                    ("first", FRAG_STAT_FIRST),
+                    ("followed", FRAG_STAT_FOLLOWED),
+                    ("orphan", FRAG_STAT_ORPHAN),
+                    ("tiny", FRAG_STAT_TINY),
+                    ("overlapping", FRAG_STAT_OVERLAP),
+                ] {
+                    out.sample("xdp_drop_fragments_total", &[("kind", kind)], totals[stat as usize]);
+                }
+            }
+            Err(e) => warn!("📈 Lecture de FRAGMENT_STATS impossible : {}", e),
+        }
+
+        let ctt_v4 = count_entries(self.ctt_v4.lock().await.keys());
+        let ctt_v6 = count_entries(self.ctt_v6.lock().await.keys());
+        out.header("xdp_drop_conntrack_entries", "gauge", "Entrées de la table de suivi");
+        out.sample("xdp_drop_conntrack_entries", &[("family", "ipv4")], ctt_v4);
+        out.sample("xdp_drop_conntrack_entries", &[("family", "ipv6")], ctt_v6);
+        out.header("xdp_drop_conntrack_capacity", "gauge", "Capacité de la table de suivi");
+        out.sample("xdp_drop_conntrack_capacity", &[("family", "ipv4")], CONN_TRACK_MAX_ENTRIES);
+        out.sample("xdp_drop_conntrack_capacity", &[("family", "ipv6")], CONN_TRACK_MAX_ENTRIES);
+
+        {
+            let blocklists = self.blocklists.lock().await;
+            let (entries_v4, entries_v6) = blocklists.kernel_entries();
+            out.header("xdp_drop_rules_loaded", "gauge", "Règles chargées dans le noyau");
+            out.sample("xdp_drop_rules_loaded", &[], blocklists.rule_count());
+            out.header("xdp_drop_blocklist_entries", "gauge", "Entrées du trie BLOCKLIST");
+            out.sample("xdp_drop_blocklist_entries", &[("family", "ipv4")], entries_v4);
+            out.sample("xdp_drop_blocklist_entries", &[("family", "ipv6")], entries_v6);
+            out.header("xdp_drop_rules_generation", "gauge", "Génération du jeu de règles actif (ApplyRuleset)");
+            out.sample("xdp_drop_rules_generation", &[], blocklists.generation());
+            out.header("xdp_drop_blocklist_capacity", "gauge", "Capacité du trie BLOCKLIST");
+            out.sample("xdp_drop_blocklist_capacity", &[("family", "ipv4")], BLOCKLIST_MAX_ENTRIES);
+            out.sample("xdp_drop_blocklist_capacity", &[("family", "ipv6")], BLOCKLIST_MAX_ENTRIES);
+        }
+
+        match self.rule_counters.lock().await.totals() {
+            Ok(totals) => {
+                let totals: BTreeMap<_, _> = totals.into_iter().collect();
+                out.header("xdp_drop_rule_packets_total", "counter", "Paquets décidés par la règle");
+                for (rule_id, stats) in &totals {
+                    out.sample("xdp_drop_rule_packets_total", &[("rule_id", &rule_id.to_string())], stats.packets);
+                }
+                out.header("xdp_drop_rule_bytes_total", "counter", "Octets décidés par la règle");
+                for (rule_id, stats) in &totals {
+                    out.sample("xdp_drop_rule_bytes_total", &[("rule_id", &rule_id.to_string())], stats.bytes);
+                }
+            }
+            Err(e) => warn!("📈 Lecture de RULE_STATS impossible : {}", e),
+        }
+
+        self.grpc.render(&mut out);
+
+        let db_up = matches!(
+            tokio::time::timeout(DB_HEALTH_TIMEOUT, self.db_client.simple_query("SELECT 1")).await,
+            Ok(Ok(_))
+        );
+        out.header("xdp_drop_db_up", "gauge", "1 si la base PostgreSQL répond");
+        out.sample("xdp_drop_db_up", &[], db_up as u8);
+
+        out.0
+    }
+}
+
+// Entrées lisibles d'une table ; le parcours s'arrête à la première erreur
+fn count_entries<K>(keys: impl Iterator<Item = Result<K, aya::maps::MapError>>) -> usize {
+    keys.take_while(Result::is_ok).count()
+}
+
+// Format texte d'exposition Prometheus
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+async fn handle_request(request: Request<Body>, sources: Arc<MetricsSources>) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::from("Not found, see /metrics\n"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    let mut response = Response::new(Body::from(sources.render().await));
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"));
+    Ok(response)
+}
+
+pub async fn run_metrics_server(addr: SocketAddr, sources: Arc<MetricsSources>) -> anyhow::Result<()> {
+    let make_service = make_service_fn(move |_connection| {
+        let sources = Arc::clone(&sources);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle_request(request, Arc::clone(&sources))))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .with_context(|| format!("Metrics address {} bind error", addr))?
+        .serve(make_service);
+    info!("📈 Endpoint Prometheus démarré sur http://{}/metrics", addr);
+    server.await.context("Metrics server error")
+}
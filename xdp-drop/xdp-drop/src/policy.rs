@@ -0,0 +1,80 @@
+// Politique par défaut par classe de trafic (map DEFAULT_POLICY).
+//
+// Le programme XDP applique la politique de la classe aux paquets qu'aucune règle ni
+// entrée de suivi n'a décidés (ARP, IPv6, ICMP, autres protocoles IP, TCP/UDP sans
+// règle, fragments IPv4 / IPv6 orphelins). Les politiques sont modifiables au runtime
+// via gRPC et persistées dans la table default_policies ; une classe sans ligne garde la
+// politique intégrée.
+
+use aya::maps::{Array, MapData, MapError};
+use xdp_drop_common::{
+    builtin_policy, POLICY_DROP, POLICY_LOG, POLICY_PASS, TRAFFIC_CLASS_ARP, TRAFFIC_CLASS_COUNT,
+    TRAFFIC_CLASS_FRAGMENT, TRAFFIC_CLASS_ICMP, TRAFFIC_CLASS_IPV6, TRAFFIC_CLASS_OTHER_IP, TRAFFIC_CLASS_UNMATCHED,
+};
+
+const TRAFFIC_CLASS_NAMES: [(u32, &str); TRAFFIC_CLASS_COUNT as usize] = [
+    (TRAFFIC_CLASS_ARP, "arp"),
+    (TRAFFIC_CLASS_IPV6, "ipv6"),
+    (TRAFFIC_CLASS_ICMP, "icmp"),
+    (TRAFFIC_CLASS_OTHER_IP, "other_ip"),
+    (TRAFFIC_CLASS_UNMATCHED, "unmatched"),
+    (TRAFFIC_CLASS_FRAGMENT, "fragment"),
+];
+
+const POLICY_NAMES: [(u32, &str); 3] = [(POLICY_DROP, "drop"), (POLICY_PASS, "pass"), (POLICY_LOG, "log")];
+
+pub fn parse_class(name: &str) -> Result<u32, String> {
+    let name = name.trim().to_lowercase();
+    TRAFFIC_CLASS_NAMES.iter()
+        .find(|(_, n)| *n == name)
+        .map(|(class, _)| *class)
+        .ok_or_else(|| format!(
+            "Classe de trafic inconnue : '{}' ({})",
+            name, TRAFFIC_CLASS_NAMES.map(|(_, n)| n).join(", ")
+        ))
+}
+
+pub fn parse_policy(name: &str) -> Result<u32, String> {
+    let name = name.trim().to_lowercase();
+    POLICY_NAMES.iter()
+        .find(|(_, n)| *n == name)
+        .map(|(policy, _)| *policy)
+        .ok_or_else(|| format!("Politique inconnue : '{}' (drop, pass ou log)", name))
+}
+
+pub fn class_name(class: u32) -> &'static str {
+    TRAFFIC_CLASS_NAMES.iter().find(|(c, _)| *c == class).map_or("?", |(_, n)| n)
+}
+
+pub fn policy_name(policy: u32) -> &'static str {
+    POLICY_NAMES.iter().find(|(p, _)| *p == policy).map_or("?", |(_, n)| n)
+}
+
+// Politiques en vigueur, tenues à jour avec la map noyau
+pub struct DefaultPolicies {
+    map: Array<MapData, u32>,
+    policies: [u32; TRAFFIC_CLASS_COUNT as usize],
+}
+
+impl DefaultPolicies {
+    pub fn new(map: Array<MapData, u32>) -> Self {
+        let mut policies = [0; TRAFFIC_CLASS_COUNT as usize];
+        for (class, policy) in policies.iter_mut().enumerate() {
+            *policy = builtin_policy(class as u32);
+        }
+        Self { map, policies }
+    }
+
+    pub fn set(&mut self, class: u32, policy: u32) -> Result<(), MapError> {
+        self.map.set(class, policy, 0)?;
+        self.policies[class as usize] = policy;
+        Ok(())
+    }
+
+    // (classe, politique) dans l'ordre des classes
+    pub fn entries(&self) -> Vec<(&'static str, &'static str)> {
+        TRAFFIC_CLASS_NAMES.iter()
+            .map(|(class, name)| (*name, policy_name(self.policies[*class as usize])))
+            .collect()
+    }
+}
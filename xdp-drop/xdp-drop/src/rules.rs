@@ -0,0 +1,1283 @@
+// Règles du firewall : parsing (IP/CIDR) et compilation vers les tries LPM eBPF.
+//
+// Le programme XDP fait deux recherches LPM :
+//   1. SRC_PREFIXES : adresse source -> classe du préfixe source le plus long connu ;
+//   2. BLOCKLIST    : (classe, protocole, adresse destination) -> ensemble de règles (`RuleSet`),
+//      préfixe destination le plus long, protocole exact et 0 ("tous"). Chaque règle porte un
+//      rang global (priorité, puis règle la plus précise) : parmi celles dont les plages de ports
+//      couvrent le paquet, le plus petit rang l'emporte. L'ensemble contient aussi les règles des
+//      préfixes destination moins spécifiques.
+// Une règle portant sur un préfixe source moins spécifique doit donc être recopiée dans
+// chaque classe qu'il contient : c'est le rôle de `Blocklists::sync`.
+// Au-delà de MAX_RULES_PER_KEY candidates, la suite d'un ensemble est chaînée dans RULE_SET_LINKS.
+// Les tries existent en deux jeux : un remplacement complet du jeu de règles est écrit dans
+// le jeu inactif, puis activé d'un coup par la map RULES_GENERATION (`Blocklists::stage` / `swap`).
+// "*" / "any" désigne toute adresse : préfixe /0, dans les deux familles si les deux IPs le sont.
+
+use anyhow::bail;
+use aya::maps::{
+    lpm_trie::{Key, LpmTrie},
+    Array, HashMap as AyaHashMap, MapData, MapError,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use xdp_drop_common::{
+    RuleEntry, RuleKey, RuleKeyV6, RuleSet, RuleSetLink, BLOCKLIST_MAX_ENTRIES, MAX_CANDIDATES_PER_KEY, MAX_RULES_PER_KEY,
+    RULE_KEY_PREFIX_BITS, RULE_SET_LINKS_MAX_ENTRIES, SRC_PREFIXES_MAX_ENTRIES,
+};
+
+use crate::conntrack::Flow;
+
+pub const ACTION_DENY: u32 = 1;
+pub const ACTION_ALLOW: u32 = 2; // Rappel: pour initier des connexions
+
+// Priorité d'une règle créée sans priorité explicite ; la plus petite valeur est évaluée en premier
+pub const DEFAULT_PRIORITY: i32 = 100;
+
+// Numéros de protocole IP utilisés dans les clés de règles (0 = tout protocole).
+// Une règle "ICMP" sans type est stockée avec PROTO_ICMP et compilée en PROTO_ICMPV6 pour IPv6 ;
+// avec un type elle ne vaut que pour IPv4 (les types diffèrent), "ICMPV6" que pour IPv6.
+pub const PROTO_ANY: u8 = 0;
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+pub const PROTO_ICMPV6: u8 = 58;
+
+// Préfixe CIDR normalisé (bits d'hôte à zéro)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    // Accepte "10.0.0.0/8", "2001:db8::/32" ou une adresse seule (/32 ou /128)
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (addr_str, len_str) = match s.split_once('/') {
+            Some((a, l)) => (a, Some(l)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr_str.parse().map_err(|_| format!("Adresse IP invalide : '{}'", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len_str {
+            Some(l) => l.parse::<u8>().ok().filter(|l| *l <= max_len)
+                .ok_or_else(|| format!("Longueur de préfixe invalide : '{}'", s))?,
+            None => max_len,
+        };
+        Ok(Self { addr: mask(addr, len), len })
+    }
+
+    // Préfixe /0 de la famille (toute adresse)
+    pub fn any(ipv4: bool) -> Self {
+        let addr = if ipv4 { IpAddr::V4(Ipv4Addr::UNSPECIFIED) } else { IpAddr::V6(Ipv6Addr::UNSPECIFIED) };
+        Self { addr, len: 0 }
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    // Vrai si `other` est inclus dans ce préfixe (même famille uniquement)
+    pub fn contains(&self, other: &IpPrefix) -> bool {
+        self.is_ipv4() == other.is_ipv4() && self.len <= other.len && mask(other.addr, self.len) == self.addr
+    }
+
+    pub fn contains_addr(&self, addr: IpAddr) -> bool {
+        let len = if addr.is_ipv4() { 32 } else { 128 };
+        self.contains(&IpPrefix { addr, len })
+    }
+
+    // Adresse telle que lue par le programme eBPF (network byte order)
+    fn v4_be(&self) -> u32 {
+        match self.addr {
+            IpAddr::V4(a) => u32::from(a).to_be(),
+            IpAddr::V6(_) => 0,
+        }
+    }
+
+    fn v6_be(&self) -> [u32; 4] {
+        match self.addr {
+            IpAddr::V6(a) => ipv6_to_be_words(&a),
+            IpAddr::V4(_) => [0; 4],
+        }
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let max_len = if self.is_ipv4() { 32 } else { 128 };
+        if self.len == max_len {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.len)
+        }
+    }
+}
+
+fn mask(addr: IpAddr, len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(a) => {
+            let m = if len == 0 { 0 } else { u32::MAX << (32 - len as u32) };
+            IpAddr::V4(Ipv4Addr::from(u32::from(a) & m))
+        }
+        IpAddr::V6(a) => {
+            let m = if len == 0 { 0 } else { u128::MAX << (128 - len as u32) };
+            IpAddr::V6(Ipv6Addr::from(u128::from(a) & m))
+        }
+    }
+}
+
+// Adresse d'une règle : préfixe CIDR, ou toute adresse ("*" / "any") des deux familles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddrMatch {
+    Any,
+    Prefix(IpPrefix),
+}
+
+impl AddrMatch {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "*" | "any" => Ok(AddrMatch::Any),
+            _ => IpPrefix::parse(s).map(AddrMatch::Prefix),
+        }
+    }
+
+    // Longueur de préfixe (0 pour toute adresse)
+    fn prefix_len(&self) -> u8 {
+        match self {
+            AddrMatch::Any => 0,
+            AddrMatch::Prefix(p) => p.prefix_len(),
+        }
+    }
+
+    // Préfixe effectif pour une famille, `None` si l'adresse est de l'autre famille
+    fn for_family(&self, ipv4: bool) -> Option<IpPrefix> {
+        match self {
+            AddrMatch::Any => Some(IpPrefix::any(ipv4)),
+            AddrMatch::Prefix(p) if p.is_ipv4() == ipv4 => Some(*p),
+            AddrMatch::Prefix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for AddrMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddrMatch::Any => write!(f, "*"),
+            AddrMatch::Prefix(p) => write!(f, "{}", p),
+        }
+    }
+}
+
+// Adresse IPv6 telle que lue par le programme eBPF (u6_addr32, network byte order)
+pub fn ipv6_to_be_words(addr: &Ipv6Addr) -> [u32; 4] {
+    let o = addr.octets();
+    core::array::from_fn(|i| u32::from_ne_bytes([o[4 * i], o[4 * i + 1], o[4 * i + 2], o[4 * i + 3]]))
+}
+
+// Inverse de `ipv6_to_be_words` (adresses lues dans les maps)
+pub fn ipv6_from_be_words(words: [u32; 4]) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    for (chunk, word) in octets.chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_ne_bytes());
+    }
+    Ipv6Addr::from(octets)
+}
+
+// Règle validée, telle que compilée vers le noyau
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub id: i32,
+    pub source: AddrMatch,
+    pub dest: AddrMatch,
+    pub protocol: u8, // PROTO_ANY = tout protocole
+    pub source_port: PortRange,
+    pub dest_port: PortRange,
+    pub action: u32,
+    pub priority: i32, // Plus petite valeur = évaluée en premier
+    pub icmp_type: Option<u8>, // ICMP/ICMPv6 uniquement ; None = tout type
+    pub icmp_code: Option<u8>, // Exige un type ; None = tout code
+    pub vlan_id: Option<u16>, // VLAN 802.1Q (1-4094) ; None = tout VLAN
+}
+
+impl Rule {
+    pub fn parse(
+        id: i32,
+        source_ip: &str,
+        dest_ip: &str,
+        source_port: PortRange,
+        dest_port: PortRange,
+        protocol: &str,
+        action: &str,
+    ) -> Result<Self, String> {
+        let source = AddrMatch::parse(source_ip)?;
+        let dest = AddrMatch::parse(dest_ip)?;
+        if let (AddrMatch::Prefix(s), AddrMatch::Prefix(d)) = (source, dest) {
+            if s.is_ipv4() != d.is_ipv4() {
+                return Err(format!("Familles d'adresses différentes : {} -> {}", source_ip, dest_ip));
+            }
+        }
+        let protocol = match protocol.trim().to_uppercase().as_str() {
+            "" | "*" | "ANY" => PROTO_ANY,
+            "TCP" => PROTO_TCP,
+            "UDP" => PROTO_UDP,
+            "ICMP" => PROTO_ICMP,
+            "ICMPV6" => PROTO_ICMPV6,
+            other => return Err(format!("Protocole inconnu : '{}' (TCP, UDP, ICMP, ICMPV6 ou ANY)", other)),
+        };
+        let icmp = protocol == PROTO_ICMP || protocol == PROTO_ICMPV6;
+        if icmp && !(source_port.is_any() && dest_port.is_any()) {
+            return Err("Les règles ICMP ne peuvent pas porter de ports (utiliser le type et le code)".to_string());
+        }
+        if protocol == PROTO_ICMPV6 && [source, dest].iter().any(|a| matches!(a, AddrMatch::Prefix(p) if p.is_ipv4())) {
+            return Err("Une règle ICMPV6 ne peut porter que sur des adresses IPv6".to_string());
+        }
+        let action = match action.to_lowercase().as_str() {
+            "deny" => ACTION_DENY,
+            "allow" => ACTION_ALLOW,
+            other => return Err(format!("Action inconnue : '{}'", other)),
+        };
+        Ok(Self {
+            id, source, dest, protocol, source_port, dest_port, action,
+            priority: DEFAULT_PRIORITY,
+            icmp_type: None,
+            icmp_code: None,
+            vlan_id: None,
+        })
+    }
+
+    // Type et code ICMP (gRPC, colonnes icmp_type / icmp_code) ; None = tout type / code
+    pub fn set_icmp(&mut self, icmp_type: Option<i32>, icmp_code: Option<i32>) -> Result<(), String> {
+        if icmp_type.is_none() && icmp_code.is_none() {
+            return Ok(());
+        }
+        if self.protocol != PROTO_ICMP && self.protocol != PROTO_ICMPV6 {
+            return Err("Type et code ICMP réservés aux règles ICMP / ICMPV6".to_string());
+        }
+        if icmp_type.is_none() {
+            return Err("Un code ICMP exige un type".to_string());
+        }
+        let byte = |v: i32, what: &str| u8::try_from(v).map_err(|_| format!("{} ICMP invalide : {}", what, v));
+        // Les types ICMPv4 et ICMPv6 diffèrent : une règle ICMP typée ne vaut que pour IPv4
+        if self.protocol == PROTO_ICMP && [self.source, self.dest].iter().any(|a| matches!(a, AddrMatch::Prefix(p) if !p.is_ipv4())) {
+            return Err("Type ICMP sur des adresses IPv6 : utiliser le protocole ICMPV6".to_string());
+        }
+        self.icmp_type = icmp_type.map(|t| byte(t, "Type")).transpose()?;
+        self.icmp_code = icmp_code.map(|c| byte(c, "Code")).transpose()?;
+        Ok(())
+    }
+
+    // VLAN ID (gRPC, colonne vlan_id) ; None = tout VLAN, trames non balisées comprises
+    pub fn set_vlan(&mut self, vlan_id: Option<i32>) -> Result<(), String> {
+        self.vlan_id = vlan_id
+            .map(|v| match u16::try_from(v) {
+                Ok(v @ 1..=4094) => Ok(v),
+                _ => Err(format!("VLAN ID invalide : {} (1-4094)", v)),
+            })
+            .transpose()?;
+        Ok(())
+    }
+
+    pub fn protocol_name(&self) -> &'static str {
+        match self.protocol {
+            PROTO_TCP => "TCP",
+            PROTO_UDP => "UDP",
+            PROTO_ICMP => "ICMP",
+            PROTO_ICMPV6 => "ICMPV6",
+            _ => "ANY",
+        }
+    }
+
+    pub fn action_name(&self) -> &'static str {
+        if self.action == ACTION_DENY { "deny" } else { "allow" }
+    }
+
+    // Ordre d'évaluation : priorité, règles propres à un VLAN, puis ports les plus précis (port dest, puis source),
+    // destination et source les plus spécifiques, DENY, et enfin ID pour un ordre stable
+    fn evaluation_key(&self) -> impl Ord {
+        let (source_range, dest_range) = self.kernel_ranges();
+        (
+            self.priority,
+            self.vlan_id.is_none(),
+            dest_range.width(),
+            source_range.width(),
+            std::cmp::Reverse(self.dest.prefix_len()),
+            std::cmp::Reverse(self.source.prefix_len()),
+            self.action != ACTION_DENY,
+            self.id,
+        )
+    }
+
+    // Préfixes (source, destination) de la règle pour une famille, si elle s'y applique
+    fn prefixes(&self, ipv4: bool) -> Option<(IpPrefix, IpPrefix)> {
+        let family_ok = match self.protocol {
+            PROTO_ICMPV6 => !ipv4,
+            PROTO_ICMP if self.icmp_type.is_some() => ipv4,
+            _ => true,
+        };
+        if !family_ok {
+            return None;
+        }
+        Some((self.source.for_family(ipv4)?, self.dest.for_family(ipv4)?))
+    }
+
+    // Plages (source, destination) écrites dans RuleEntry : les ports, ou le type et le code ICMP
+    fn kernel_ranges(&self) -> (PortRange, PortRange) {
+        let exact = |v: Option<u8>| v.map_or(PortRange::ANY, |v| PortRange { min: v as u16, max: v as u16 });
+        match self.protocol {
+            PROTO_ICMP | PROTO_ICMPV6 => (exact(self.icmp_type), exact(self.icmp_code)),
+            _ => (self.source_port, self.dest_port),
+        }
+    }
+
+    // Numéro de protocole écrit dans les clés noyau de la famille
+    fn kernel_protocol(&self, ipv4: bool) -> u8 {
+        if self.protocol == PROTO_ICMP && !ipv4 { PROTO_ICMPV6 } else { self.protocol }
+    }
+
+    // Vrai si le noyau retiendrait la règle pour un paquet de `flow` (dans le sens du paquet).
+    // Pour un écho ICMP, les ports portent l'identifiant : le type du paquet est donné à part (code 0).
+    // Un autre protocole (GRE, ESP...) n'a ni ports ni type : seules les règles ANY sans ports le retiennent.
+    pub fn matches_flow(&self, flow: &Flow, icmp_type: u8) -> bool {
+        let ipv4 = flow.source.is_ipv4();
+        let Some((source, dest)) = self.prefixes(ipv4) else { return false };
+        if !source.contains_addr(flow.source) || !dest.contains_addr(flow.dest) {
+            return false;
+        }
+        if self.protocol != PROTO_ANY && self.kernel_protocol(ipv4) != flow.protocol {
+            return false;
+        }
+        if self.vlan_id.is_some() && self.vlan_id != Some(flow.vlan_id) {
+            return false;
+        }
+        // Mêmes comparaisons que match_rule_set côté eBPF
+        let (source_range, dest_range) = self.kernel_ranges();
+        let has_ports = flow.protocol == PROTO_TCP || flow.protocol == PROTO_UDP;
+        let (source_port, dest_port) = match flow.protocol {
+            _ if has_ports => (flow.source_port, flow.dest_port),
+            PROTO_ICMP | PROTO_ICMPV6 => (icmp_type as u16, 0),
+            _ => (0, 0),
+        };
+        if !has_ports && self.protocol == PROTO_ANY && !(source_range.is_any() && dest_range.is_any()) {
+            return false;
+        }
+        source_range.contains(source_port) && dest_range.contains(dest_port)
+    }
+}
+
+// Plage de ports inclusive, en ordre hôte ; 0-65535 = wildcard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl PortRange {
+    pub const ANY: PortRange = PortRange { min: 0, max: u16::MAX };
+
+    // Champ port reçu en texte (gRPC) : "*", "any" ou vide = wildcard, "80" ou "49152-65535"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Port invalide : '{}'", s);
+        let t = s.trim().to_lowercase();
+        match t.as_str() {
+            "" | "*" | "any" => Ok(Self::ANY),
+            _ => match t.split_once('-') {
+                Some((a, b)) => {
+                    let min = a.trim().parse::<u16>().map_err(|_| invalid())?;
+                    let max = b.trim().parse::<u16>().map_err(|_| invalid())?;
+                    if min > max {
+                        return Err(format!("Plage de ports inversée : '{}'", s));
+                    }
+                    Ok(Self { min, max })
+                }
+                // Un port 0 seul reste un wildcard, comme avant les plages
+                None => match t.parse::<u16>().map_err(|_| invalid())? {
+                    0 => Ok(Self::ANY),
+                    p => Ok(Self { min: p, max: p }),
+                },
+            },
+        }
+    }
+
+    // Colonnes `*_port` / `*_port_end` de la table rules : NULL = wildcard, fin NULL = port unique
+    pub fn from_db(start: Option<i32>, end: Option<i32>, which: &str) -> Result<Self, String> {
+        let port = |p: i32| u16::try_from(p).map_err(|_| format!("Port {} invalide : {}", which, p));
+        match start {
+            None | Some(0) if end.is_none() => Ok(Self::ANY),
+            None => Err(format!("Fin de plage de port {} sans début", which)),
+            Some(start) => {
+                let min = port(start)?;
+                let max = end.map(port).transpose()?.unwrap_or(min);
+                if min > max {
+                    return Err(format!("Plage de ports {} inversée : {}-{}", which, min, max));
+                }
+                Ok(Self { min, max })
+            }
+        }
+    }
+
+    pub fn to_db(&self) -> (Option<i32>, Option<i32>) {
+        if self.is_any() {
+            (None, None)
+        } else if self.min == self.max {
+            (Some(self.min as i32), None)
+        } else {
+            (Some(self.min as i32), Some(self.max as i32))
+        }
+    }
+
+    pub fn is_any(&self) -> bool {
+        *self == Self::ANY
+    }
+
+    fn contains(&self, port: u16) -> bool {
+        (self.min..=self.max).contains(&port)
+    }
+
+    // Nombre de ports couverts, pour trier les règles de la plus précise à la plus large
+    fn width(&self) -> u32 {
+        (self.max - self.min) as u32 + 1
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_any() {
+            write!(f, "*")
+        } else if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+// Entrées installées dans un trie : (longueur de préfixe, clé) -> valeur
+type TrieEntries<K, V = u32> = HashMap<(u32, K), V>;
+
+// Écritures du daemon dans une map noyau. Derrière un trait pour que les tests puissent
+// simuler un refus du noyau au milieu d'une mise à jour.
+trait KernelMap<K, V>: Send + Sync {
+    fn write(&mut self, key: &K, value: &V) -> Result<(), MapError>;
+}
+
+// Map dont les entrées peuvent être retirées (tries LPM et tables de hachage)
+trait KernelTable<K, V>: KernelMap<K, V> {
+    fn delete(&mut self, key: &K) -> Result<(), MapError>;
+}
+
+// Trie LPM : clé (longueur de préfixe, données), comme dans TrieEntries
+impl<K: aya::Pod + Send + Sync, V: aya::Pod + Send + Sync> KernelMap<(u32, K), V> for LpmTrie<MapData, K, V> {
+    fn write(&mut self, &(prefix_len, data): &(u32, K), value: &V) -> Result<(), MapError> {
+        self.insert(&Key::new(prefix_len, data), *value, 0)
+    }
+}
+
+impl<K: aya::Pod + Send + Sync, V: aya::Pod + Send + Sync> KernelTable<(u32, K), V> for LpmTrie<MapData, K, V> {
+    fn delete(&mut self, &(prefix_len, data): &(u32, K)) -> Result<(), MapError> {
+        self.remove(&Key::new(prefix_len, data))
+    }
+}
+
+impl<K: aya::Pod + Send + Sync, V: aya::Pod + Send + Sync> KernelMap<K, V> for AyaHashMap<MapData, K, V> {
+    fn write(&mut self, key: &K, value: &V) -> Result<(), MapError> {
+        self.insert(key, value, 0)
+    }
+}
+
+impl<K: aya::Pod + Send + Sync, V: aya::Pod + Send + Sync> KernelTable<K, V> for AyaHashMap<MapData, K, V> {
+    fn delete(&mut self, key: &K) -> Result<(), MapError> {
+        self.remove(key)
+    }
+}
+
+impl<V: aya::Pod + Send + Sync> KernelMap<u32, V> for Array<MapData, V> {
+    fn write(&mut self, index: &u32, value: &V) -> Result<(), MapError> {
+        self.set(*index, value, 0)
+    }
+}
+
+// Tries d'un jeu de règles (préfixes source et règles, IPv4 et IPv6), maillons des ensembles
+// trop grands, et entrées qui y sont installées
+pub struct RuleBank {
+    src_v4: Box<dyn KernelTable<(u32, u32), u32>>,
+    src_v6: Box<dyn KernelTable<(u32, [u32; 4]), u32>>,
+    rules_v4: Box<dyn KernelTable<(u32, RuleKey), RuleSet>>,
+    rules_v6: Box<dyn KernelTable<(u32, RuleKeyV6), RuleSet>>,
+    links: Box<dyn KernelTable<RuleSetLink, RuleSet>>,
+    installed_src_v4: TrieEntries<u32>,
+    installed_src_v6: TrieEntries<[u32; 4]>,
+    installed_rules_v4: TrieEntries<RuleKey, RuleSet>,
+    installed_rules_v6: TrieEntries<RuleKeyV6, RuleSet>,
+    installed_links: HashMap<RuleSetLink, RuleSet>,
+}
+
+impl RuleBank {
+    pub fn new(
+        src_v4: LpmTrie<MapData, u32, u32>,
+        src_v6: LpmTrie<MapData, [u32; 4], u32>,
+        rules_v4: LpmTrie<MapData, RuleKey, RuleSet>,
+        rules_v6: LpmTrie<MapData, RuleKeyV6, RuleSet>,
+        links: AyaHashMap<MapData, RuleSetLink, RuleSet>,
+    ) -> Self {
+        Self::from_maps(Box::new(src_v4), Box::new(src_v6), Box::new(rules_v4), Box::new(rules_v6), Box::new(links))
+    }
+
+    fn from_maps(
+        src_v4: Box<dyn KernelTable<(u32, u32), u32>>,
+        src_v6: Box<dyn KernelTable<(u32, [u32; 4]), u32>>,
+        rules_v4: Box<dyn KernelTable<(u32, RuleKey), RuleSet>>,
+        rules_v6: Box<dyn KernelTable<(u32, RuleKeyV6), RuleSet>>,
+        links: Box<dyn KernelTable<RuleSetLink, RuleSet>>,
+    ) -> Self {
+        Self {
+            src_v4,
+            src_v6,
+            rules_v4,
+            rules_v6,
+            links,
+            installed_src_v4: HashMap::new(),
+            installed_src_v6: HashMap::new(),
+            installed_rules_v4: HashMap::new(),
+            installed_rules_v6: HashMap::new(),
+            installed_links: HashMap::new(),
+        }
+    }
+
+    // Applique le différentiel avec le jeu compilé. Les nouvelles entrées sont écrites avant
+    // celles qui les référencent (maillons, ensembles, classes), et les entrées obsolètes ne
+    // sont retirées qu'ensuite.
+    // Un échec en cours de route laisse les entrées déjà écrites : l'appelant réapplique alors
+    // le jeu précédent (voir `Blocklists::restore`).
+    fn apply(&mut self, compiled: &CompiledRules) -> Result<(), MapError> {
+        install(&mut *self.links, &mut self.installed_links, &compiled.links)?;
+        install(&mut *self.rules_v4, &mut self.installed_rules_v4, &compiled.rules_v4)?;
+        install(&mut *self.rules_v6, &mut self.installed_rules_v6, &compiled.rules_v6)?;
+        install(&mut *self.src_v4, &mut self.installed_src_v4, &compiled.src_v4)?;
+        install(&mut *self.src_v6, &mut self.installed_src_v6, &compiled.src_v6)?;
+        prune(&mut *self.src_v4, &mut self.installed_src_v4, &compiled.src_v4)?;
+        prune(&mut *self.src_v6, &mut self.installed_src_v6, &compiled.src_v6)?;
+        prune(&mut *self.rules_v4, &mut self.installed_rules_v4, &compiled.rules_v4)?;
+        prune(&mut *self.rules_v6, &mut self.installed_rules_v6, &compiled.rules_v6)?;
+        prune(&mut *self.links, &mut self.installed_links, &compiled.links)?;
+        Ok(())
+    }
+}
+
+// Clé d'un ensemble chaîné : (longueur de préfixe, clé) dans BLOCKLIST ou BLOCKLIST_V6
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ChainKey {
+    V4(u32, RuleKey),
+    V6(u32, RuleKeyV6),
+}
+
+// Identifiants attribués à la compilation : classes des préfixes source et chaînes des
+// ensembles trop grands. Stables d'une compilation à l'autre pour des mises à jour incrémentales.
+#[derive(Debug, Clone)]
+struct KeyIds {
+    classes: HashMap<IpPrefix, u32>,
+    next_class: u32,
+    chains: HashMap<ChainKey, u32>,
+    next_chain: u32,
+}
+
+impl Default for KeyIds {
+    fn default() -> Self {
+        Self { classes: HashMap::new(), next_class: 1, chains: HashMap::new(), next_chain: 1 }
+    }
+}
+
+// Entrées noyau d'un jeu de règles et rang d'évaluation de chaque règle
+struct CompiledRules {
+    src_v4: TrieEntries<u32>,
+    src_v6: TrieEntries<[u32; 4]>,
+    rules_v4: TrieEntries<RuleKey, RuleSet>,
+    rules_v6: TrieEntries<RuleKeyV6, RuleSet>,
+    links: HashMap<RuleSetLink, RuleSet>,
+    ranks: HashMap<i32, u32>,
+}
+
+// Jeu de règles complet écrit dans le jeu de tries inactif, en attente de bascule (`Blocklists::swap`).
+// Les identifiants attribués pour ce jeu ne sont adoptés qu'à la bascule.
+pub struct StagedRules {
+    rules: BTreeMap<i32, Rule>,
+    ranks: HashMap<i32, u32>,
+    ids: KeyIds,
+}
+
+// Tries LPM des règles, en double : le noyau lit le jeu désigné par RULES_GENERATION.
+// Les modifications unitaires (CreateRule, DeleteRule, UpdateRule) sont appliquées au jeu actif ;
+// un remplacement complet (ApplyRuleset) est préparé dans l'autre jeu puis activé d'un coup.
+pub struct Blocklists {
+    banks: [RuleBank; 2],
+    generation_map: Box<dyn KernelMap<u32, u32>>,
+    generation: u32, // Jeu actif : banks[generation % 2]
+    // Jeu de règles actif, par ID
+    rules: BTreeMap<i32, Rule>,
+    // Rang d'évaluation de chaque règle chargée (0 = évaluée en premier)
+    ranks: HashMap<i32, u32>,
+    // Identifiants de classe et de chaîne du jeu actif
+    ids: KeyIds,
+}
+
+impl Blocklists {
+    pub fn new(bank: RuleBank, standby: RuleBank, generation_map: Array<MapData, u32>) -> Self {
+        Self::from_maps(bank, standby, Box::new(generation_map))
+    }
+
+    fn from_maps(bank: RuleBank, standby: RuleBank, generation_map: Box<dyn KernelMap<u32, u32>>) -> Self {
+        Self {
+            banks: [bank, standby],
+            generation_map,
+            generation: 0,
+            rules: BTreeMap::new(),
+            ranks: HashMap::new(),
+            ids: KeyIds::default(),
+        }
+    }
+
+    // Remplace tout le jeu de règles (chargement initial, génération 0). Si le jeu complet est
+    // refusé, les règles sont reprises une à une dans l'ordre d'évaluation : celles que le noyau
+    // refuse sont écartées et renvoyées avec leur erreur.
+    pub fn load(&mut self, mut rules: Vec<Rule>) -> anyhow::Result<Vec<(Rule, anyhow::Error)>> {
+        self.generation_map.write(&0, &0)?;
+        self.generation = 0;
+        self.rules = rules.iter().map(|r| (r.id, r.clone())).collect();
+        if self.sync().is_ok() {
+            return Ok(Vec::new());
+        }
+
+        self.rules.clear();
+        rules.sort_by_key(|rule| rule.evaluation_key());
+        let mut rejected = Vec::new();
+        for rule in rules {
+            if let Err(e) = self.insert(rule.clone()) {
+                rejected.push((rule, e));
+            }
+        }
+        // Retire les entrées qu'un essai refusé aurait laissées
+        self.sync()?;
+        Ok(rejected)
+    }
+
+    // Règle telle qu'elle est appliquée par le noyau, si elle est chargée
+    pub fn get(&self, id: i32) -> Option<&Rule> {
+        self.rules.get(&id)
+    }
+
+    // Règles chargées, par ID
+    pub fn rules(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.values()
+    }
+
+    // Position de la règle dans l'ordre d'évaluation du noyau
+    pub fn rank(&self, id: i32) -> Option<u32> {
+        self.ranks.get(&id).copied()
+    }
+
+    // Règle qui décide un paquet de `flow` (voir `Rule::matches_flow`), dans l'ordre d'évaluation du noyau
+    pub fn decide(&self, flow: &Flow, icmp_type: u8) -> Option<&Rule> {
+        self.rules.values()
+            .filter(|rule| rule.matches_flow(flow, icmp_type))
+            .min_by_key(|rule| self.rank(rule.id).unwrap_or(u32::MAX))
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    // Entrées installées dans BLOCKLIST et BLOCKLIST_V6 (jeu actif)
+    pub fn kernel_entries(&self) -> (usize, usize) {
+        let bank = self.active_bank();
+        (bank.installed_rules_v4.len(), bank.installed_rules_v6.len())
+    }
+
+    // En cas d'échec, la règle n'est pas retenue et le noyau reprend le jeu précédent
+    pub fn insert(&mut self, rule: Rule) -> anyhow::Result<()> {
+        let id = rule.id;
+        let previous = self.rules.insert(id, rule);
+        if let Err(e) = self.sync() {
+            match previous {
+                Some(previous) => self.rules.insert(id, previous),
+                None => self.rules.remove(&id),
+            };
+            return Err(self.restore(e));
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: i32) -> anyhow::Result<Option<Rule>> {
+        let removed = self.rules.remove(&id);
+        if let Some(rule) = &removed {
+            if let Err(e) = self.sync() {
+                self.rules.insert(id, rule.clone());
+                return Err(self.restore(e));
+            }
+        }
+        Ok(removed)
+    }
+
+    // Écrit un jeu de règles complet dans le jeu de tries inactif. Le noyau n'en voit rien
+    // tant que `swap` n'est pas appelé ; un échec laisse le jeu actif intact.
+    pub fn stage(&mut self, rules: Vec<Rule>) -> anyhow::Result<StagedRules> {
+        let rules: BTreeMap<i32, Rule> = rules.into_iter().map(|r| (r.id, r)).collect();
+        let mut ids = self.ids.clone();
+        let compiled = compile(&rules, &mut ids)?;
+        let standby = (self.generation as usize + 1) % 2;
+        self.banks[standby].apply(&compiled)?;
+        Ok(StagedRules { rules, ranks: compiled.ranks, ids })
+    }
+
+    // Active le jeu préparé par `stage` (une seule écriture dans RULES_GENERATION). Renvoie le
+    // jeu remplacé : ses tries restent intacts, `swap` peut donc le réactiver tant qu'aucune
+    // modification ne s'est intercalée. Idem entre `stage` et `swap`.
+    pub fn swap(&mut self, staged: StagedRules) -> Result<StagedRules, MapError> {
+        let generation = self.generation.wrapping_add(1);
+        self.generation_map.write(&0, &generation)?;
+        self.generation = generation;
+        Ok(StagedRules {
+            rules: std::mem::replace(&mut self.rules, staged.rules),
+            ranks: std::mem::replace(&mut self.ranks, staged.ranks),
+            ids: std::mem::replace(&mut self.ids, staged.ids),
+        })
+    }
+
+    fn active_bank(&self) -> &RuleBank {
+        &self.banks[self.generation as usize % 2]
+    }
+
+    // Après l'échec de `sync` pour une modification annulée dans `self.rules` : réécrit le jeu
+    // précédent par-dessus les entrées déjà modifiées. Renvoie l'erreur initiale, complétée de
+    // celle du rétablissement s'il échoue aussi.
+    fn restore(&mut self, error: anyhow::Error) -> anyhow::Error {
+        match self.sync() {
+            Ok(()) => error,
+            Err(e) => anyhow::anyhow!("{:#} ; jeu précédent non rétabli dans le noyau : {:#}", error, e),
+        }
+    }
+
+    // Recompile le jeu de règles et applique le différentiel au jeu de tries actif.
+    fn sync(&mut self) -> anyhow::Result<()> {
+        let compiled = compile(&self.rules, &mut self.ids)?;
+        let active = self.generation as usize % 2;
+        self.banks[active].apply(&compiled)?;
+        self.ranks = compiled.ranks;
+        Ok(())
+    }
+}
+
+// Entrées noyau d'un jeu de règles.
+// Un jeu qui dépasse MAX_CANDIDATES_PER_KEY règles candidates pour une clé, ou la capacité
+// d'une map (SRC_PREFIXES, BLOCKLIST, RULE_SET_LINKS), est refusé avant toute écriture.
+fn compile(rules: &BTreeMap<i32, Rule>, ids: &mut KeyIds) -> anyhow::Result<CompiledRules> {
+    let mut ordered: Vec<&Rule> = rules.values().collect();
+    ordered.sort_by_key(|rule| rule.evaluation_key());
+    let ranks: HashMap<i32, u32> = ordered.iter().enumerate().map(|(rank, rule)| (rule.id, rank as u32)).collect();
+
+    // Une règle "* -> *" s'applique aux deux familles : une vue par famille
+    let compiled: Vec<(IpPrefix, IpPrefix, &Rule)> = rules.values()
+        .flat_map(|rule| [true, false].into_iter().filter_map(move |ipv4| {
+            rule.prefixes(ipv4).map(|(src, dst)| (src, dst, rule))
+        }))
+        .collect();
+
+    let KeyIds { classes, next_class, chains, next_chain } = ids;
+    let sources: HashSet<IpPrefix> = compiled.iter().map(|(src, _, _)| *src).collect();
+    classes.retain(|p, _| sources.contains(p));
+    for source in &sources {
+        if !classes.contains_key(source) {
+            classes.insert(*source, *next_class);
+            *next_class += 1;
+        }
+    }
+
+    let mut src_v4 = HashMap::new();
+    let mut src_v6 = HashMap::new();
+    let mut rules_v4 = HashMap::new();
+    let mut rules_v6 = HashMap::new();
+    let mut links = HashMap::new();
+    let mut chained = HashSet::new();
+
+    for (class_prefix, &class) in classes.iter() {
+        let ipv4 = class_prefix.is_ipv4();
+        if ipv4 {
+            src_v4.insert((class_prefix.prefix_len() as u32, class_prefix.v4_be()), class);
+        } else {
+            src_v6.insert((class_prefix.prefix_len() as u32, class_prefix.v6_be()), class);
+        }
+
+        // Règles applicables à la classe : préfixe source incluant celui de la classe
+        let applicable: Vec<&(IpPrefix, IpPrefix, &Rule)> = compiled.iter()
+            .filter(|(src, _, _)| src.contains(class_prefix))
+            .collect();
+        // Une clé par (protocole, préfixe destination) ; le trie ne retient que la plus
+        // longue, son ensemble reprend donc les règles des destinations qui l'incluent.
+        let keys: HashSet<(u8, IpPrefix)> = applicable.iter()
+            .map(|(_, dst, rule)| (rule.kernel_protocol(ipv4), *dst))
+            .collect();
+
+        for (protocol, key_dst) in keys {
+            let mut candidates: Vec<&(IpPrefix, IpPrefix, &Rule)> = applicable.iter()
+                .filter(|(_, dst, rule)| rule.kernel_protocol(ipv4) == protocol && dst.contains(&key_dst))
+                .copied()
+                .collect();
+            if candidates.len() > MAX_CANDIDATES_PER_KEY {
+                bail!(
+                    "Trop de règles se recouvrent sur {} -> {} : {} règles candidates, au plus {} pour un même \
+                     (préfixe source, protocole, préfixe destination)",
+                    class_prefix, key_dst, candidates.len(), MAX_CANDIDATES_PER_KEY
+                );
+            }
+            candidates.sort_by_key(|(_, _, rule)| ranks[&rule.id]);
+
+            // Ensemble de tête, puis un maillon par tranche de MAX_RULES_PER_KEY règles suivantes
+            let mut sets: Vec<RuleSet> = candidates.chunks(MAX_RULES_PER_KEY)
+                .map(|chunk| rule_set(chunk.iter().map(|(_, _, rule)| *rule), &ranks))
+                .collect();
+            let prefix_len = RULE_KEY_PREFIX_BITS + key_dst.prefix_len() as u32;
+            let chain_key = if ipv4 {
+                ChainKey::V4(prefix_len, RuleKey { src_class: class, protocol, _pad: [0; 3], addr_dest: key_dst.v4_be() })
+            } else {
+                ChainKey::V6(prefix_len, RuleKeyV6 { src_class: class, protocol, _pad: [0; 3], addr_dest: key_dst.v6_be() })
+            };
+            if sets.len() > 1 {
+                let chain = *chains.entry(chain_key).or_insert_with(|| {
+                    *next_chain += 1;
+                    *next_chain - 1
+                });
+                chained.insert(chain_key);
+                sets[0].links = sets.len() as u32 - 1;
+                sets[0].chain = chain;
+                for (link, set) in sets.drain(1..).enumerate() {
+                    links.insert(RuleSetLink { chain, link: link as u32 }, set);
+                }
+            }
+
+            match chain_key {
+                ChainKey::V4(prefix_len, key) => rules_v4.insert((prefix_len, key), sets[0]),
+                ChainKey::V6(prefix_len, key) => rules_v6.insert((prefix_len, key), sets[0]),
+            };
+        }
+    }
+    for (map, entries, capacity) in [
+        ("SRC_PREFIXES", src_v4.len(), SRC_PREFIXES_MAX_ENTRIES),
+        ("SRC_PREFIXES_V6", src_v6.len(), SRC_PREFIXES_MAX_ENTRIES),
+        ("BLOCKLIST", rules_v4.len(), BLOCKLIST_MAX_ENTRIES),
+        ("BLOCKLIST_V6", rules_v6.len(), BLOCKLIST_MAX_ENTRIES),
+        ("RULE_SET_LINKS", links.len(), RULE_SET_LINKS_MAX_ENTRIES),
+    ] {
+        if entries > capacity as usize {
+            bail!("Jeu de règles trop grand : {} entrées dans {}, au plus {}", entries, map, capacity);
+        }
+    }
+    chains.retain(|key, _| chained.contains(key));
+
+    Ok(CompiledRules { src_v4, src_v6, rules_v4, rules_v6, links, ranks })
+}
+
+// Ensemble noyau d'au plus MAX_RULES_PER_KEY règles, dans l'ordre donné
+fn rule_set<'a>(rules: impl Iterator<Item = &'a Rule>, ranks: &HashMap<i32, u32>) -> RuleSet {
+    let unused = RuleEntry { rule_id: 0, rank: 0, action: 0, src_port_min: 0, src_port_max: 0, dst_port_min: 0, dst_port_max: 0, vlan_id: 0, _pad: 0 };
+    let mut set = RuleSet { count: 0, links: 0, chain: 0, _pad: 0, entries: [unused; MAX_RULES_PER_KEY] };
+    for (slot, rule) in set.entries.iter_mut().zip(rules) {
+        let (source_range, dest_range) = rule.kernel_ranges();
+        *slot = RuleEntry {
+            rule_id: rule.id as u32,
+            rank: ranks[&rule.id],
+            action: rule.action,
+            src_port_min: source_range.min,
+            src_port_max: source_range.max,
+            dst_port_min: dest_range.min,
+            dst_port_max: dest_range.max,
+            vlan_id: rule.vlan_id.unwrap_or(0),
+            _pad: 0,
+        };
+        set.count += 1;
+    }
+    set
+}
+
+// Écrit les entrées nouvelles ou modifiées
+fn install<K: Copy + Eq + Hash, V: Copy + PartialEq>(
+    map: &mut dyn KernelTable<K, V>,
+    installed: &mut HashMap<K, V>,
+    desired: &HashMap<K, V>,
+) -> Result<(), MapError> {
+    for (key, value) in desired {
+        if installed.get(key) != Some(value) {
+            map.write(key, value)?;
+            installed.insert(*key, *value);
+        }
+    }
+    Ok(())
+}
+
+// Retire les entrées qui ne font plus partie du jeu compilé
+fn prune<K: Copy + Eq + Hash, V>(
+    map: &mut dyn KernelTable<K, V>,
+    installed: &mut HashMap<K, V>,
+    desired: &HashMap<K, V>,
+) -> Result<(), MapError> {
+    let stale: Vec<K> = installed.keys().filter(|k| !desired.contains_key(k)).copied().collect();
+    for key in stale {
+        map.delete(&key)?;
+        installed.remove(&key);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    fn prefix(s: &str) -> IpPrefix {
+        IpPrefix::parse(s).unwrap()
+    }
+
+    #[test]
+    fn ip_prefix_clears_host_bits() {
+        assert_eq!(prefix("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(prefix("192.168.1.77/32").to_string(), "192.168.1.77");
+        assert_eq!(prefix("2001:db8:1:2::5/32").to_string(), "2001:db8::/32");
+        assert_eq!(prefix("10.0.0.1/0"), IpPrefix::any(true));
+        assert_eq!(prefix(" 10.0.0.1 ").prefix_len(), 32);
+        assert_eq!(prefix("::1").prefix_len(), 128);
+        assert_eq!(prefix("10.1.2.3/8"), prefix("10.0.0.0/8"));
+    }
+
+    #[test]
+    fn ip_prefix_rejects_invalid_input() {
+        for s in ["", "10.0.0", "10.0.0.0/33", "2001:db8::/129", "10.0.0.0/-1", "10.0.0.0/", "10.0.0.0/8/8", "*"] {
+            assert!(IpPrefix::parse(s).is_err(), "{} accepté", s);
+        }
+    }
+
+    #[test]
+    fn ip_prefix_contains_same_family_only() {
+        assert!(prefix("10.0.0.0/8").contains(&prefix("10.1.0.0/16")));
+        assert!(!prefix("10.1.0.0/16").contains(&prefix("10.0.0.0/8")));
+        assert!(!IpPrefix::any(true).contains(&prefix("2001:db8::/32")));
+        assert!(IpPrefix::any(false).contains_addr("2001:db8::1".parse().unwrap()));
+        assert!(!IpPrefix::any(false).contains_addr("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn port_range_parse() {
+        assert_eq!(PortRange::parse("").unwrap(), PortRange::ANY);
+        assert_eq!(PortRange::parse(" Any ").unwrap(), PortRange::ANY);
+        assert_eq!(PortRange::parse("0").unwrap(), PortRange::ANY);
+        assert_eq!(PortRange::parse("80").unwrap(), PortRange { min: 80, max: 80 });
+        assert_eq!(PortRange::parse("1024 - 2048").unwrap(), PortRange { min: 1024, max: 2048 });
+        assert_eq!(PortRange::parse("0-65535").unwrap(), PortRange::ANY);
+        for s in ["65536", "-1", "80-", "-80", "http", "1-2-3"] {
+            assert!(PortRange::parse(s).is_err(), "{} accepté", s);
+        }
+        assert!(PortRange::parse("2048-1024").unwrap_err().contains("inversée"));
+    }
+
+    #[test]
+    fn port_range_from_db() {
+        assert_eq!(PortRange::from_db(None, None, "dest").unwrap(), PortRange::ANY);
+        assert_eq!(PortRange::from_db(Some(0), None, "dest").unwrap(), PortRange::ANY);
+        assert_eq!(PortRange::from_db(Some(443), None, "dest").unwrap(), PortRange { min: 443, max: 443 });
+        assert_eq!(PortRange::from_db(Some(1000), Some(2000), "dest").unwrap(), PortRange { min: 1000, max: 2000 });
+        assert!(PortRange::from_db(None, Some(80), "dest").is_err());
+        assert!(PortRange::from_db(Some(2000), Some(1000), "dest").is_err());
+        assert!(PortRange::from_db(Some(-1), None, "source").is_err());
+        assert!(PortRange::from_db(Some(80), Some(70000), "source").is_err());
+    }
+
+    #[test]
+    fn port_range_round_trip() {
+        for range in [PortRange::ANY, PortRange { min: 22, max: 22 }, PortRange { min: 1, max: 1023 }] {
+            let (start, end) = range.to_db();
+            assert_eq!(PortRange::from_db(start, end, "dest").unwrap(), range);
+            assert_eq!(PortRange::parse(&range.to_string()).unwrap(), range);
+        }
+    }
+
+    #[test]
+    fn rule_parse() {
+        let rule = Rule::parse(7, "10.0.0.0/8", "any", PortRange::ANY, PortRange::parse("22").unwrap(), "tcp", "DENY").unwrap();
+        assert_eq!(rule.source, AddrMatch::Prefix(prefix("10.0.0.0/8")));
+        assert_eq!(rule.dest, AddrMatch::Any);
+        assert_eq!(rule.protocol, PROTO_TCP);
+        assert_eq!(rule.action, ACTION_DENY);
+        assert_eq!(rule.priority, DEFAULT_PRIORITY);
+        assert_eq!(rule.protocol_name(), "TCP");
+
+        let rule = Rule::parse(8, "*", "2001:db8::/32", PortRange::ANY, PortRange::ANY, "", "allow").unwrap();
+        assert_eq!(rule.protocol, PROTO_ANY);
+        assert_eq!(rule.action, ACTION_ALLOW);
+    }
+
+    #[test]
+    fn rule_parse_rejects_invalid_combinations() {
+        let any = PortRange::ANY;
+        let port = PortRange::parse("80").unwrap();
+        // Familles différentes
+        assert!(Rule::parse(1, "10.0.0.1", "2001:db8::1", any, any, "tcp", "deny").is_err());
+        // Protocole ou action inconnus
+        assert!(Rule::parse(1, "10.0.0.1", "10.0.0.2", any, any, "sctp", "deny").is_err());
+        assert!(Rule::parse(1, "10.0.0.1", "10.0.0.2", any, any, "tcp", "reject").is_err());
+        // Ports sur une règle ICMP
+        assert!(Rule::parse(1, "10.0.0.1", "10.0.0.2", any, port, "icmp", "deny").is_err());
+        // ICMPV6 sur des adresses IPv4
+        assert!(Rule::parse(1, "10.0.0.1", "*", any, any, "icmpv6", "deny").is_err());
+        assert!(Rule::parse(1, "*", "*", any, any, "icmpv6", "deny").is_ok());
+        // Adresse invalide
+        assert!(Rule::parse(1, "10.0.0.256", "*", any, any, "tcp", "deny").is_err());
+    }
+
+    fn rule(id: i32, source: &str, dest: &str, protocol: &str, dest_port: &str, action: &str) -> Rule {
+        Rule::parse(id, source, dest, PortRange::ANY, PortRange::parse(dest_port).unwrap(), protocol, action).unwrap()
+    }
+
+    fn compile_all(rules: &[Rule], ids: &mut KeyIds) -> anyhow::Result<CompiledRules> {
+        compile(&rules.iter().map(|rule| (rule.id, rule.clone())).collect(), ids)
+    }
+
+    // Identifiants des règles de l'ensemble BLOCKLIST d'une clé IPv4, dans l'ordre noyau
+    fn v4_set(compiled: &CompiledRules, ids: &KeyIds, source: &str, protocol: u8, dest: &str) -> Option<(Vec<u32>, RuleSet)> {
+        let (source, dest) = (prefix(source), prefix(dest));
+        let key = RuleKey { src_class: ids.classes[&source], protocol, _pad: [0; 3], addr_dest: dest.v4_be() };
+        let set = *compiled.rules_v4.get(&(RULE_KEY_PREFIX_BITS + dest.prefix_len() as u32, key))?;
+        Some((set.entries[..set.count as usize].iter().map(|entry| entry.rule_id).collect(), set))
+    }
+
+    fn tcp_flow(source: &str, dest: &str, dest_port: u16) -> Flow {
+        Flow {
+            source: source.parse().unwrap(),
+            dest: dest.parse().unwrap(),
+            source_port: 40000,
+            dest_port,
+            protocol: PROTO_TCP,
+            vlan_id: 0,
+        }
+    }
+
+    #[test]
+    fn evaluation_order_breaks_priority_ties() {
+        let mut rules = [
+            rule(1, "10.0.0.0/8", "*", "tcp", "*", "deny"),
+            rule(2, "10.0.0.0/8", "*", "tcp", "22", "allow"),
+            rule(3, "10.0.0.0/8", "192.168.0.0/16", "tcp", "*", "allow"),
+            rule(4, "10.1.0.0/16", "*", "tcp", "*", "allow"),
+            rule(5, "10.0.0.0/8", "*", "tcp", "*", "allow"),
+            rule(6, "10.0.0.0/8", "*", "tcp", "*", "deny"),
+        ];
+        rules[5].priority = 10;
+        rules[4].set_vlan(Some(12)).unwrap();
+        rules.sort_by_key(|rule| rule.evaluation_key());
+        // Priorité, VLAN, ports, destination puis source, DENY, et enfin ID
+        assert_eq!(rules.iter().map(|rule| rule.id).collect::<Vec<_>>(), [6, 5, 2, 3, 4, 1]);
+    }
+
+    #[test]
+    fn compile_merges_less_specific_destinations() {
+        let rules = [
+            rule(1, "10.0.0.0/8", "192.168.0.0/16", "tcp", "*", "deny"),
+            rule(2, "10.0.0.0/8", "192.168.1.0/24", "tcp", "*", "allow"),
+            rule(3, "10.0.0.0/8", "*", "any", "*", "deny"),
+            rule(4, "10.1.0.0/16", "192.168.1.0/24", "tcp", "443", "allow"),
+        ];
+        let mut ids = KeyIds::default();
+        let compiled = compile_all(&rules, &mut ids).unwrap();
+        // La clé la plus longue reprend les règles des destinations qui l'incluent
+        assert_eq!(v4_set(&compiled, &ids, "10.0.0.0/8", PROTO_TCP, "192.168.1.0/24").unwrap().0, [2, 1]);
+        assert_eq!(v4_set(&compiled, &ids, "10.0.0.0/8", PROTO_TCP, "192.168.0.0/16").unwrap().0, [1]);
+        assert_eq!(v4_set(&compiled, &ids, "10.0.0.0/8", PROTO_ANY, "0.0.0.0/0").unwrap().0, [3]);
+        assert!(v4_set(&compiled, &ids, "10.0.0.0/8", PROTO_TCP, "0.0.0.0/0").is_none());
+        // La classe du préfixe source le plus long reprend les règles des sources qui l'incluent
+        assert_eq!(v4_set(&compiled, &ids, "10.1.0.0/16", PROTO_TCP, "192.168.1.0/24").unwrap().0, [4, 2, 1]);
+        // "* -> *" s'applique aussi à IPv6, mais pas les règles IPv4
+        assert_eq!(compiled.src_v6.len(), 0);
+        assert_eq!(compiled.rules_v6.len(), 0);
+    }
+
+    #[test]
+    fn compile_keeps_class_ids_stable() {
+        let mut rules = vec![
+            rule(1, "10.0.0.0/8", "*", "tcp", "*", "deny"),
+            rule(2, "172.16.0.0/12", "*", "tcp", "*", "deny"),
+        ];
+        let mut ids = KeyIds::default();
+        compile_all(&rules, &mut ids).unwrap();
+        let class = ids.classes[&prefix("172.16.0.0/12")];
+        rules.remove(0);
+        compile_all(&rules, &mut ids).unwrap();
+        assert_eq!(ids.classes[&prefix("172.16.0.0/12")], class);
+        assert!(!ids.classes.contains_key(&prefix("10.0.0.0/8")));
+    }
+
+    #[test]
+    fn compile_chains_sets_beyond_max_rules_per_key() {
+        let count = MAX_RULES_PER_KEY + 8;
+        let rules: Vec<Rule> = (1..=count as i32)
+            .map(|id| rule(id, "10.0.0.0/8", "192.168.1.1", "tcp", &id.to_string(), "deny"))
+            .collect();
+        let mut ids = KeyIds::default();
+        let compiled = compile_all(&rules, &mut ids).unwrap();
+        let (head, set) = v4_set(&compiled, &ids, "10.0.0.0/8", PROTO_TCP, "192.168.1.1").unwrap();
+        assert_eq!(head, (1..=MAX_RULES_PER_KEY as u32).collect::<Vec<_>>());
+        assert_eq!(set.links, 1);
+        let link = compiled.links[&RuleSetLink { chain: set.chain, link: 0 }];
+        assert_eq!(link.count, 8);
+        assert_eq!(link.entries[0].rank, MAX_RULES_PER_KEY as u32);
+
+        // Sous le seuil, la chaîne disparaît
+        let compiled = compile_all(&rules[..MAX_RULES_PER_KEY], &mut ids).unwrap();
+        assert!(compiled.links.is_empty());
+        assert!(ids.chains.is_empty());
+    }
+
+    #[test]
+    fn compile_rejects_too_many_candidates() {
+        let rules: Vec<Rule> = (0..=MAX_CANDIDATES_PER_KEY as i32)
+            .map(|id| rule(id + 1, "10.0.0.0/8", "*", "udp", &(id % 60000 + 1).to_string(), "deny"))
+            .collect();
+        let err = compile_all(&rules, &mut KeyIds::default()).err().unwrap();
+        assert!(err.to_string().contains(&MAX_CANDIDATES_PER_KEY.to_string()), "{}", err);
+        assert!(compile_all(&rules[1..], &mut KeyIds::default()).is_ok());
+    }
+
+    #[test]
+    fn matches_flow_follows_kernel_comparisons() {
+        let web = rule(1, "10.0.0.0/8", "192.168.1.0/24", "tcp", "80-443", "allow");
+        assert!(web.matches_flow(&tcp_flow("10.2.3.4", "192.168.1.10", 443), 0));
+        assert!(!web.matches_flow(&tcp_flow("10.2.3.4", "192.168.1.10", 8080), 0));
+        assert!(!web.matches_flow(&tcp_flow("11.2.3.4", "192.168.1.10", 80), 0));
+        assert!(!web.matches_flow(&Flow { protocol: PROTO_UDP, ..tcp_flow("10.2.3.4", "192.168.1.10", 80) }, 0));
+
+        // "* -> *" vaut pour les deux familles ; une règle ANY à ports ne retient que TCP / UDP
+        let any = rule(2, "*", "*", "any", "*", "deny");
+        assert!(any.matches_flow(&tcp_flow("2001:db8::1", "2001:db8::2", 22), 0));
+        let ported = rule(3, "*", "*", "any", "53", "deny");
+        let echo = Flow { protocol: PROTO_ICMP, source_port: 7, dest_port: 7, ..tcp_flow("10.0.0.1", "10.0.0.2", 0) };
+        assert!(!ported.matches_flow(&echo, 8));
+
+        // Type ICMP porté par `icmp_type`, pas par les ports (identifiant d'écho)
+        let mut ping = rule(4, "*", "*", "icmp", "*", "deny");
+        ping.set_icmp(Some(8), None).unwrap();
+        assert!(ping.matches_flow(&echo, 8));
+        assert!(!ping.matches_flow(&echo, 0));
+        // Une règle ICMP typée ne vaut que pour IPv4
+        let echo_v6 = Flow { protocol: PROTO_ICMPV6, ..tcp_flow("2001:db8::1", "2001:db8::2", 0) };
+        assert!(!ping.matches_flow(&echo_v6, 8));
+        assert!(rule(5, "*", "*", "icmp", "*", "deny").matches_flow(&echo_v6, 128));
+
+        // Protocole sans transport lu (GRE) : règles ANY sans ports uniquement
+        let gre = Flow { protocol: 47, source_port: 0, dest_port: 0, ..tcp_flow("10.0.0.1", "10.0.0.2", 0) };
+        assert!(any.matches_flow(&gre, 0));
+        assert!(!ported.matches_flow(&gre, 0));
+        assert!(!rule(7, "*", "*", "tcp", "*", "deny").matches_flow(&gre, 0));
+        assert!(!rule(8, "*", "*", "any", "0-1023", "deny").matches_flow(&gre, 0));
+
+        // VLAN
+        let mut tagged = rule(6, "*", "*", "tcp", "*", "deny");
+        tagged.set_vlan(Some(12)).unwrap();
+        assert!(!tagged.matches_flow(&tcp_flow("10.0.0.1", "10.0.0.2", 22), 0));
+        assert!(tagged.matches_flow(&Flow { vlan_id: 12, ..tcp_flow("10.0.0.1", "10.0.0.2", 22) }, 0));
+    }
+
+    // Map noyau simulée : refuse toute nouvelle entrée au-delà de `capacity`. Les clones
+    // partagent le contenu, le test peut donc l'inspecter une fois la map confiée à un RuleBank.
+    struct FakeMap<K, V> {
+        entries: Arc<Mutex<HashMap<K, V>>>,
+        writes: Arc<AtomicUsize>, // Écritures acceptées
+        capacity: usize,
+    }
+
+    impl<K, V> FakeMap<K, V> {
+        fn new(capacity: usize) -> Self {
+            Self { entries: Arc::new(Mutex::new(HashMap::new())), writes: Arc::new(AtomicUsize::new(0)), capacity }
+        }
+
+        fn shared(&self) -> Self {
+            Self { entries: Arc::clone(&self.entries), writes: Arc::clone(&self.writes), capacity: self.capacity }
+        }
+    }
+
+    impl<K: Copy + Eq + Hash + Send, V: Copy + Send> KernelMap<K, V> for FakeMap<K, V> {
+        fn write(&mut self, key: &K, value: &V) -> Result<(), MapError> {
+            let mut entries = self.entries.lock().unwrap();
+            if !entries.contains_key(key) && entries.len() >= self.capacity {
+                return Err(MapError::OutOfBounds { index: entries.len() as u32, max_entries: self.capacity as u32 });
+            }
+            entries.insert(*key, *value);
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    impl<K: Copy + Eq + Hash + Send, V: Copy + Send> KernelTable<K, V> for FakeMap<K, V> {
+        fn delete(&mut self, key: &K) -> Result<(), MapError> {
+            self.entries.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    fn fake_bank(src_v4: &FakeMap<(u32, u32), u32>, rules_v4: &FakeMap<(u32, RuleKey), RuleSet>) -> RuleBank {
+        RuleBank::from_maps(
+            Box::new(src_v4.shared()),
+            Box::new(FakeMap::new(64)),
+            Box::new(rules_v4.shared()),
+            Box::new(FakeMap::new(64)),
+            Box::new(FakeMap::new(64)),
+        )
+    }
+
+    #[test]
+    fn failed_insert_restores_kernel_entries() {
+        // SRC_PREFIXES ne peut recevoir qu'une classe
+        let src_v4 = FakeMap::new(1);
+        let rules_v4 = FakeMap::new(64);
+        let standby = fake_bank(&FakeMap::new(64), &FakeMap::new(64));
+        let mut blocklists = Blocklists::from_maps(fake_bank(&src_v4, &rules_v4), standby, Box::new(FakeMap::new(1)));
+        assert!(blocklists.load(vec![rule(1, "10.0.0.0/8", "192.168.0.0/16", "tcp", "*", "deny")]).unwrap().is_empty());
+        let src_before = src_v4.entries.lock().unwrap().clone();
+        let rules_before = rules_v4.entries.lock().unwrap().clone();
+        let writes_before = rules_v4.writes.load(Ordering::Relaxed);
+
+        // Nouvelle source prioritaire : ses ensembles sont écrits et la règle 1 change de rang
+        // avant que SRC_PREFIXES, plein, ne refuse la nouvelle classe
+        let mut first = rule(2, "172.16.0.0/12", "*", "tcp", "*", "allow");
+        first.priority = 1;
+        assert!(blocklists.insert(first).is_err());
+        assert!(rules_v4.writes.load(Ordering::Relaxed) >= writes_before + 2);
+        assert_eq!(*src_v4.entries.lock().unwrap(), src_before);
+        assert_eq!(*rules_v4.entries.lock().unwrap(), rules_before);
+        assert_eq!(blocklists.rule_count(), 1);
+        assert_eq!(blocklists.rank(1), Some(0));
+        assert!(blocklists.get(2).is_none());
+
+        // Le jeu rétabli accepte les modifications suivantes
+        blocklists.insert(rule(3, "10.0.0.0/8", "*", "udp", "53", "deny")).unwrap();
+        assert_eq!(rules_v4.entries.lock().unwrap().len(), 2);
+        blocklists.remove(1).unwrap();
+        assert_eq!(rules_v4.entries.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn compile_rejects_rulesets_beyond_map_capacity() {
+        let rules: Vec<Rule> = (0..=SRC_PREFIXES_MAX_ENTRIES as i32)
+            .map(|id| rule(id + 1, &Ipv4Addr::from(0x0a00_0000 + id as u32).to_string(), "*", "tcp", "*", "deny"))
+            .collect();
+        let err = compile_all(&rules, &mut KeyIds::default()).err().unwrap();
+        assert!(err.to_string().contains("SRC_PREFIXES"), "{}", err);
+        assert!(compile_all(&rules[1..], &mut KeyIds::default()).is_ok());
+    }
+}